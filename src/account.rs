@@ -0,0 +1,134 @@
+//! Unix user and group lookups
+
+use std::{
+  ffi::{CStr, CString, OsStr, OsString},
+  io,
+  mem::MaybeUninit,
+  os::unix::ffi::OsStrExt,
+  ptr,
+};
+
+use crate::error::{AdeployError, Result};
+
+/// Upper bound for the string buffer handed to `getpwnam_r` and friends
+const MAX_LOOKUP_BUFFER: usize = 1024 * 1024;
+
+/// A Unix account resolved from a name or uid
+pub struct Account {
+  pub uid: libc::uid_t,
+  /// Primary group, unless a numeric uid has no passwd entry
+  pub gid: Option<libc::gid_t>,
+  pub name: Option<String>,
+  pub home: Option<OsString>,
+}
+
+/// Resolve a user name or numeric uid given in the `setting` configuration value
+pub fn lookup_user(user: &str, setting: &str) -> Result<Account> {
+  let numeric = user.parse::<libc::uid_t>().ok();
+  let name = c_string(user, setting)?;
+  let found = lookup(
+    |entry, buffer, length, result| match numeric {
+      // SAFETY: every pointer refers to storage of the stated size owned by `lookup`
+      Some(uid) => unsafe { libc::getpwuid_r(uid, entry, buffer, length, result) },
+      // SAFETY: as above, and `name` is NUL-terminated
+      None => unsafe { libc::getpwnam_r(name.as_ptr(), entry, buffer, length, result) },
+    },
+    |entry: &libc::passwd| Account {
+      uid: entry.pw_uid,
+      gid: Some(entry.pw_gid),
+      // SAFETY: a filled-in entry points at NUL-terminated strings in the lookup buffer
+      name: Some(
+        unsafe { CStr::from_ptr(entry.pw_name) }
+          .to_string_lossy()
+          .into_owned(),
+      ),
+      // SAFETY: as above
+      home: Some(OsStr::from_bytes(unsafe { CStr::from_ptr(entry.pw_dir) }.to_bytes()).to_owned()),
+    },
+  )
+  .map_err(|e| {
+    Box::new(AdeployError::Config(format!(
+      "Failed to look up {} '{}': {}",
+      setting, user, e
+    )))
+  })?;
+
+  match (found, numeric) {
+    (Some(account), _) => Ok(account),
+    (None, Some(uid)) => Ok(Account {
+      uid,
+      gid: None,
+      name: None,
+      home: None,
+    }),
+    (None, None) => Err(Box::new(AdeployError::Config(format!(
+      "Unknown {} '{}'",
+      setting, user
+    )))),
+  }
+}
+
+/// Resolve a group name or numeric gid given in the `setting` configuration value
+pub fn lookup_group(group: &str, setting: &str) -> Result<libc::gid_t> {
+  if let Ok(gid) = group.parse::<libc::gid_t>() {
+    return Ok(gid);
+  }
+  let name = c_string(group, setting)?;
+  lookup(
+    // SAFETY: every pointer refers to storage of the stated size owned by `lookup`, and
+    // `name` is NUL-terminated
+    |entry, buffer, length, result| unsafe {
+      libc::getgrnam_r(name.as_ptr(), entry, buffer, length, result)
+    },
+    |entry: &libc::group| entry.gr_gid,
+  )
+  .map_err(|e| {
+    Box::new(AdeployError::Config(format!(
+      "Failed to look up {} '{}': {}",
+      setting, group, e
+    )))
+  })?
+  .ok_or_else(|| {
+    Box::new(AdeployError::Config(format!(
+      "Unknown {} '{}'",
+      setting, group
+    )))
+  })
+}
+
+fn c_string(value: &str, setting: &str) -> Result<CString> {
+  CString::new(value).map_err(|_| {
+    Box::new(AdeployError::Config(format!(
+      "{} '{}' contains a NUL byte",
+      setting, value
+    )))
+  })
+}
+
+/// Run a reentrant `get*_r` lookup, growing its string buffer until the entry fits, and
+/// `read` what is needed from the entry while the buffer it points into is alive
+fn lookup<T, R>(
+  mut call: impl FnMut(*mut T, *mut libc::c_char, libc::size_t, *mut *mut T) -> libc::c_int,
+  read: impl FnOnce(&T) -> R,
+) -> io::Result<Option<R>> {
+  let mut buffer = vec![0 as libc::c_char; 1024];
+  loop {
+    let mut entry = MaybeUninit::<T>::uninit();
+    let mut result = ptr::null_mut();
+    match call(
+      entry.as_mut_ptr(),
+      buffer.as_mut_ptr(),
+      buffer.len(),
+      &mut result,
+    ) {
+      0 if result.is_null() => return Ok(None),
+      // SAFETY: a non-null result means the entry was filled in
+      0 => return Ok(Some(read(unsafe { entry.assume_init_ref() }))),
+      libc::ERANGE if buffer.len() < MAX_LOOKUP_BUFFER => {
+        let grown = buffer.len() * 2;
+        buffer.resize(grown, 0);
+      }
+      code => return Err(io::Error::from_raw_os_error(code)),
+    }
+  }
+}
@@ -0,0 +1,488 @@
+//! Backup archives, deployment records and retention
+
+use std::{
+  fs,
+  io::{self, BufReader, BufWriter, Read, Write},
+  path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log2::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  config::ServerPackageConfig,
+  error::{AdeployError, Result},
+  staging::{self, RESERVED_PREFIX},
+};
+
+/// Prefix shared by every backup snapshot name
+pub const BACKUP_PREFIX: &str = "backup_";
+/// Extension of compressed backup archives; older backups are plain directories
+pub const BACKUP_EXTENSION: &str = ".tar.gz";
+const BACKUP_TIMESTAMP_FORMAT: &str = "%Y%m%d_%H%M%S";
+/// Archive entry holding the manifest, written ahead of the snapshotted files
+pub const MANIFEST_ENTRY: &str = ".adeploy-backup.json";
+/// File in `deploy_path` describing the deployment that is currently live
+pub const DEPLOYMENT_RECORD: &str = ".adeploy-deployment.json";
+
+/// The deployment a package's live files came from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeploymentRecord {
+  pub deploy_id: String,
+  pub version: String,
+  pub deployed_at: DateTime<Utc>,
+}
+
+impl DeploymentRecord {
+  /// Record kept in `deploy_path`; `None` when nothing was deployed since records were introduced
+  pub fn load(deploy_path: &Path) -> Option<Self> {
+    let path = deploy_path.join(DEPLOYMENT_RECORD);
+    let contents = fs::read_to_string(&path).ok()?;
+    match serde_json::from_str(&contents) {
+      Ok(record) => Some(record),
+      Err(e) => {
+        warn!(
+          "Ignoring unreadable deployment record {}: {}",
+          path.display(),
+          e
+        );
+        None
+      }
+    }
+  }
+
+  /// Replace the record in `deploy_path`
+  pub fn save(&self, deploy_path: &Path) -> Result<()> {
+    let path = deploy_path.join(DEPLOYMENT_RECORD);
+    let contents = serde_json::to_vec_pretty(self).map_err(|e| {
+      Box::new(AdeployError::FileSystem(format!(
+        "Failed to encode deployment record: {}",
+        e
+      )))
+    })?;
+    let mut file = tempfile::NamedTempFile::new_in(deploy_path)?;
+    file.write_all(&contents)?;
+    file.persist(&path).map_err(|e| {
+      Box::new(AdeployError::FileSystem(format!(
+        "Failed to write deployment record {}: {}",
+        path.display(),
+        e
+      )))
+    })?;
+    Ok(())
+  }
+}
+
+/// Describes what a backup archive holds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupManifest {
+  pub package: String,
+  pub created_at: DateTime<Utc>,
+  /// Directory that was snapshotted
+  pub source: PathBuf,
+  /// Deployment that was live when the backup was taken, if known
+  pub deployment: Option<DeploymentRecord>,
+}
+
+/// A backup snapshot found in a package's backup directory
+#[derive(Debug, Clone)]
+pub struct BackupEntry {
+  pub name: String,
+  pub path: PathBuf,
+  pub created: DateTime<Utc>,
+  pub size: u64,
+}
+
+/// Limits applied to a package's backups; the newest backup is always kept
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetentionPolicy {
+  pub keep_count: Option<usize>,
+  pub max_age: Option<Duration>,
+  pub max_total_size: Option<u64>,
+}
+
+impl RetentionPolicy {
+  pub fn from_config(config: &ServerPackageConfig) -> Self {
+    Self {
+      keep_count: config.backup_keep_count,
+      // An age too large for a Duration can never be exceeded, so it is no limit at all
+      max_age: config
+        .backup_max_age
+        .and_then(|secs| i64::try_from(secs).ok())
+        .and_then(Duration::try_seconds),
+      max_total_size: config.backup_max_total_size,
+    }
+  }
+
+  /// Whether any limit is configured
+  pub fn is_bounded(&self) -> bool {
+    self.keep_count.is_some() || self.max_age.is_some() || self.max_total_size.is_some()
+  }
+
+  /// Backups that fall outside the limits, given newest first
+  pub fn select_expired<'a>(
+    &self,
+    backups: &'a [BackupEntry],
+    now: DateTime<Utc>,
+  ) -> Vec<&'a BackupEntry> {
+    let mut total_size = 0u64;
+    backups
+      .iter()
+      .enumerate()
+      .filter(|(index, backup)| {
+        total_size = total_size.saturating_add(backup.size);
+        let over_count = self.keep_count.is_some_and(|keep| *index >= keep);
+        let too_old = self.max_age.is_some_and(|age| now - backup.created > age);
+        let over_size = self.max_total_size.is_some_and(|max| total_size > max);
+        *index > 0 && (over_count || too_old || over_size)
+      })
+      .map(|(_, backup)| backup)
+      .collect()
+  }
+}
+
+/// Directory holding a package's backups: `backup_path`, or a directory named after the
+/// package next to the executable
+pub fn backup_directory(config: &ServerPackageConfig, package_name: &str) -> Result<PathBuf> {
+  if let Some(path) = &config.backup_path {
+    return Ok(PathBuf::from(path));
+  }
+
+  let current_exe = std::env::current_exe().map_err(|e| {
+    Box::new(AdeployError::FileSystem(format!(
+      "Failed to get current executable path: {}",
+      e
+    )))
+  })?;
+  let current_dir = current_exe.parent().ok_or_else(|| {
+    Box::new(AdeployError::FileSystem(
+      "Failed to get parent directory of executable".to_string(),
+    ))
+  })?;
+
+  Ok(current_dir.join(package_name))
+}
+
+/// File name of the backup taken for a deployment started at `time`
+pub fn backup_name(time: DateTime<Utc>) -> String {
+  format!(
+    "{}{}{}",
+    BACKUP_PREFIX,
+    time.format(BACKUP_TIMESTAMP_FORMAT),
+    BACKUP_EXTENSION
+  )
+}
+
+/// Path for a new backup taken at `time`, numbered when that second already has a backup
+pub fn new_backup_path(dir: &Path, time: DateTime<Utc>) -> PathBuf {
+  let path = dir.join(backup_name(time));
+  if !path.exists() {
+    return path;
+  }
+  let timestamp = time.format(BACKUP_TIMESTAMP_FORMAT);
+  (2..)
+    .map(|n| {
+      dir.join(format!(
+        "{}{}-{}{}",
+        BACKUP_PREFIX, timestamp, n, BACKUP_EXTENSION
+      ))
+    })
+    .find(|path| !path.exists())
+    .unwrap_or(path)
+}
+
+/// The backup named `name`, with or without its extension, or the newest one
+pub fn find_backup(dir: &Path, name: Option<&str>) -> Result<BackupEntry> {
+  let backups = list_backups(dir)?;
+  let found = match name {
+    Some(name) => backups
+      .into_iter()
+      .find(|backup| backup.name == name || backup_stem(&backup.name) == name),
+    None => backups.into_iter().next(),
+  };
+
+  found.ok_or_else(|| {
+    Box::new(AdeployError::Deploy(match name {
+      Some(name) => format!("Backup '{}' not found in {}", name, dir.display()),
+      None => format!("No backups found in {}", dir.display()),
+    }))
+  })
+}
+
+/// Backups in `dir`, newest first; a missing directory has none
+pub fn list_backups(dir: &Path) -> Result<Vec<BackupEntry>> {
+  let entries = match fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(e) => {
+      return Err(Box::new(AdeployError::FileSystem(format!(
+        "Failed to read backup directory {}: {}",
+        dir.display(),
+        e
+      ))))
+    }
+  };
+
+  let mut backups = Vec::new();
+  for entry in entries {
+    let entry = entry?;
+    let name = entry.file_name().to_string_lossy().into_owned();
+    let Some(timestamp) = name.strip_prefix(BACKUP_PREFIX) else {
+      continue;
+    };
+    let timestamp = backup_stem(timestamp);
+    // Backups taken within the same second carry a `-<n>` suffix
+    let timestamp = timestamp
+      .split_once('-')
+      .map_or(timestamp, |(time, _)| time);
+
+    let path = entry.path();
+    // Fall back to the modification time for snapshots renamed by hand
+    let created = match NaiveDateTime::parse_from_str(timestamp, BACKUP_TIMESTAMP_FORMAT) {
+      Ok(created) => created.and_utc(),
+      Err(_) => DateTime::<Utc>::from(entry.metadata()?.modified()?),
+    };
+    backups.push(BackupEntry {
+      size: disk_usage(&path)?,
+      name,
+      path,
+      created,
+    });
+  }
+
+  backups.sort_by(|a, b| {
+    b.created
+      .cmp(&a.created)
+      .then_with(|| backup_stem(&b.name).cmp(backup_stem(&a.name)))
+  });
+  Ok(backups)
+}
+
+/// Delete the backups in `dir` that fall outside `policy`, returning what was removed
+pub fn prune_backups(
+  dir: &Path,
+  policy: &RetentionPolicy,
+  now: DateTime<Utc>,
+) -> Result<Vec<BackupEntry>> {
+  if !policy.is_bounded() {
+    return Ok(Vec::new());
+  }
+
+  let backups = list_backups(dir)?;
+  let expired = policy.select_expired(&backups, now);
+  let mut removed = Vec::with_capacity(expired.len());
+  for backup in expired {
+    let result = if backup.path.is_dir() {
+      fs::remove_dir_all(&backup.path)
+    } else {
+      fs::remove_file(&backup.path)
+    };
+    result.map_err(|e| {
+      Box::new(AdeployError::FileSystem(format!(
+        "Failed to remove backup {}: {}",
+        backup.path.display(),
+        e
+      )))
+    })?;
+    info!("Removed backup {} ({} bytes)", backup.name, backup.size);
+    removed.push(backup.clone());
+  }
+
+  Ok(removed)
+}
+
+/// Apply a package's configured retention policy now; with `dry_run`, only report what
+/// would be removed
+pub fn prune_package_backups(
+  config: &ServerPackageConfig,
+  package_name: &str,
+  dry_run: bool,
+) -> Result<Vec<BackupEntry>> {
+  let policy = RetentionPolicy::from_config(config);
+  let dir = backup_directory(config, package_name)?;
+  if !dry_run {
+    return prune_backups(&dir, &policy, Utc::now());
+  }
+
+  let backups = list_backups(&dir)?;
+  Ok(
+    policy
+      .select_expired(&backups, Utc::now())
+      .into_iter()
+      .cloned()
+      .collect(),
+  )
+}
+
+/// Archive `source` into a compressed backup at `destination`, keeping symlinks, modes,
+/// owners and modification times. Returns the archive size, or `None` when there is nothing
+/// to back up.
+pub fn create_backup(
+  source: &Path,
+  destination: &Path,
+  manifest: &BackupManifest,
+) -> Result<Option<u64>> {
+  if !source.exists() {
+    return Ok(None);
+  }
+  let archive_error = |e: io::Error| {
+    Box::new(AdeployError::FileSystem(format!(
+      "Failed to back up {} into {}: {}",
+      source.display(),
+      destination.display(),
+      e
+    )))
+  };
+
+  // Written beside the final name so an interrupted backup is never listed
+  let parent = destination.parent().unwrap_or(Path::new("."));
+  let file = tempfile::NamedTempFile::new_in(parent).map_err(archive_error)?;
+  let encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+  let mut builder = tar::Builder::new(encoder);
+  builder.follow_symlinks(false);
+  append_manifest(&mut builder, manifest).map_err(archive_error)?;
+  append_tree(&mut builder, source).map_err(archive_error)?;
+
+  let file = builder
+    .into_inner()
+    .and_then(GzEncoder::finish)
+    .and_then(|writer| writer.into_inner().map_err(|e| e.into_error()))
+    .map_err(archive_error)?;
+  file.as_file().sync_all().map_err(archive_error)?;
+  let size = file.as_file().metadata().map_err(archive_error)?.len();
+  file
+    .persist(destination)
+    .map_err(|e| archive_error(e.error))?;
+  Ok(Some(size))
+}
+
+/// Manifest of a backup archive; directory backups predate manifests and have none
+pub fn read_manifest(backup: &Path) -> Result<Option<BackupManifest>> {
+  if backup.is_dir() {
+    return Ok(None);
+  }
+  let manifest_error = |e: String| {
+    Box::new(AdeployError::FileSystem(format!(
+      "Failed to read manifest of {}: {}",
+      backup.display(),
+      e
+    )))
+  };
+
+  let file = fs::File::open(backup).map_err(|e| manifest_error(e.to_string()))?;
+  let mut archive = tar::Archive::new(GzDecoder::new(BufReader::new(file)));
+  let mut entries = archive
+    .entries()
+    .map_err(|e| manifest_error(e.to_string()))?;
+  let Some(entry) = entries.next() else {
+    return Ok(None);
+  };
+  let mut entry = entry.map_err(|e| manifest_error(e.to_string()))?;
+  if entry.path().map_err(|e| manifest_error(e.to_string()))? != Path::new(MANIFEST_ENTRY) {
+    return Ok(None);
+  }
+
+  let mut contents = String::new();
+  entry
+    .read_to_string(&mut contents)
+    .map_err(|e| manifest_error(e.to_string()))?;
+  serde_json::from_str(&contents)
+    .map(Some)
+    .map_err(|e| manifest_error(e.to_string()))
+}
+
+/// Unpack a backup into an empty `destination`, restoring modes and modification times, and
+/// owners when running as root
+pub fn restore_backup(backup: &Path, destination: &Path) -> Result<()> {
+  let restore_error = |e: io::Error| {
+    Box::new(AdeployError::FileSystem(format!(
+      "Failed to restore backup {}: {}",
+      backup.display(),
+      e
+    )))
+  };
+
+  if backup.is_dir() {
+    for entry in fs::read_dir(backup).map_err(restore_error)? {
+      let entry = entry.map_err(restore_error)?;
+      staging::copy_entry(&entry.path(), &destination.join(entry.file_name()))
+        .map_err(restore_error)?;
+    }
+    return Ok(());
+  }
+
+  let file = fs::File::open(backup).map_err(restore_error)?;
+  let mut archive = tar::Archive::new(GzDecoder::new(BufReader::new(file)));
+  archive.set_preserve_permissions(true);
+  archive.set_preserve_mtime(true);
+  archive.set_preserve_ownerships(running_as_root());
+  archive.unpack(destination).map_err(restore_error)?;
+  match fs::remove_file(destination.join(MANIFEST_ENTRY)) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(restore_error(e)),
+    _ => Ok(()),
+  }
+}
+
+fn append_manifest<W: Write>(
+  builder: &mut tar::Builder<W>,
+  manifest: &BackupManifest,
+) -> io::Result<()> {
+  let contents = serde_json::to_vec_pretty(manifest)?;
+  let mut header = tar::Header::new_gnu();
+  header.set_size(contents.len() as u64);
+  header.set_mode(0o644);
+  header.set_uid(0);
+  header.set_gid(0);
+  header.set_mtime(manifest.created_at.timestamp().max(0) as u64);
+  header.set_cksum();
+  builder.append_data(&mut header, MANIFEST_ENTRY, contents.as_slice())
+}
+
+/// Append everything below `source` except adeploy's own bookkeeping entries
+fn append_tree<W: Write>(builder: &mut tar::Builder<W>, source: &Path) -> io::Result<()> {
+  let mut entries = fs::read_dir(source)?.collect::<io::Result<Vec<_>>>()?;
+  entries.sort_by_key(|entry| entry.file_name());
+  for entry in entries {
+    let name = entry.file_name();
+    if name.to_string_lossy().starts_with(RESERVED_PREFIX) {
+      continue;
+    }
+    if entry.file_type()?.is_dir() {
+      builder.append_dir_all(&name, entry.path())?;
+    } else {
+      builder.append_path_with_name(entry.path(), &name)?;
+    }
+  }
+  Ok(())
+}
+
+#[cfg(unix)]
+fn running_as_root() -> bool {
+  // SAFETY: geteuid has no preconditions and cannot fail
+  unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn running_as_root() -> bool {
+  false
+}
+
+fn backup_stem(name: &str) -> &str {
+  name.strip_suffix(BACKUP_EXTENSION).unwrap_or(name)
+}
+
+/// Bytes used by a file, or by everything below a directory
+fn disk_usage(path: &Path) -> io::Result<u64> {
+  let metadata = fs::symlink_metadata(path)?;
+  if !metadata.is_dir() {
+    return Ok(metadata.len());
+  }
+
+  let mut total = 0;
+  for entry in fs::read_dir(path)? {
+    total += disk_usage(&entry?.path())?;
+  }
+  Ok(total)
+}
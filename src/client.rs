@@ -1,26 +1,39 @@
 use std::{
+  collections::HashMap,
   convert::{TryFrom, TryInto},
+  path::PathBuf,
   time::Duration,
 };
 
 use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
 use log2::*;
-use tonic::transport::{Channel, Endpoint};
+use tokio::{io::AsyncReadExt, sync::mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+  transport::{Channel, Endpoint},
+  Streaming,
+};
+use uuid::Uuid;
 
 use crate::{
   adeploy::{
-    deploy_log::Level as DeployLogLevel, deploy_service_client::DeployServiceClient, DeployLog,
-    DeployRequest,
+    deploy_chunk::Payload, deploy_event::Event, deploy_log::Level as DeployLogLevel,
+    deploy_service_client::DeployServiceClient, DeployChunk, DeployEvent, DeployHeader, DeployLog,
+    DeployResponse, DeployStage, RestoreRequest,
   },
-  auth::Auth,
+  auth::{Auth, AuthRejection, SignedEnvelope, SignedRestore, AUTH_REJECTION_METADATA},
   config::{
     get_remote_config, ClientConfig, ClientPackageConfig, ConfigProvider, ConfigType, RemoteConfig,
   },
   deploy::DeployManager,
   error::{AdeployError, Result},
+  signer::{AgentSigner, KeyFileSigner, Signer},
+  tls,
 };
 
 const DEFAULT_MAX_MESSAGE_SIZE: u64 = 100 * 1024 * 1024;
+const UPLOAD_CHUNK_SIZE: usize = 256 * 1024;
 
 /// Deploy specific packages using an explicit provider
 pub async fn deploy(
@@ -33,15 +46,14 @@ pub async fn deploy(
   let max_file_size = resolved_max_file_size(remote_config);
   let mut client = connect_deploy_client(host, remote_config).await?;
   let deploy_manager = DeployManager::new();
-  let auth_resources = prepare_auth_resources(provider)?;
+  let signer = prepare_auth_resources(provider, &config)?;
   let packages_to_deploy = select_packages(&config, package_names)?;
 
   for (package_name, package_config) in packages_to_deploy {
     deploy_single_package(
       &deploy_manager,
       &mut client,
-      &auth_resources.ssh_auth,
-      &auth_resources.public_key,
+      signer.as_ref(),
       &package_name,
       package_config,
       max_file_size,
@@ -52,9 +64,76 @@ pub async fn deploy(
   Ok(())
 }
 
-struct AuthResources {
-  ssh_auth: Auth,
-  public_key: String,
+/// Ask the server to restore a package from one of its backups, the newest when `backup` is
+/// not given
+pub async fn restore(
+  host: &str,
+  package_name: &str,
+  backup: Option<&str>,
+  provider: &dyn ConfigProvider,
+) -> Result<()> {
+  let config = load_client_configuration(provider)?;
+  let remote_config = resolve_remote_configuration(&config, host)?;
+  let mut client = connect_deploy_client(host, remote_config).await?;
+  let signer = prepare_auth_resources(provider, &config)?;
+
+  info!(
+    "Restoring {} from {}",
+    package_name,
+    backup.unwrap_or("the latest backup")
+  );
+  let backup = backup.unwrap_or_default();
+  let timestamp = Utc::now().timestamp();
+  let nonce = Uuid::new_v4().simple().to_string();
+  let envelope = SignedRestore {
+    package_name,
+    backup,
+    timestamp,
+    nonce: &nonce,
+  };
+  let public_key = signer.public_key();
+  let signature = signer
+    .sign(&envelope.canonical_bytes())
+    .map_err(|e| Box::new(AdeployError::Auth(format!("Failed to sign data: {}", e))))?;
+
+  let request = RestoreRequest {
+    package_name: package_name.to_string(),
+    backup: backup.to_string(),
+    signature: general_purpose::STANDARD.encode(&signature),
+    public_key: public_key.to_string(),
+    timestamp,
+    nonce,
+  };
+  let response = match client.restore(request).await {
+    Ok(resp) => resp,
+    Err(status) => {
+      if status.code() == tonic::Code::Unauthenticated {
+        explain_rejection(&status, public_key);
+      }
+      return Err(Box::new(AdeployError::Grpc(status)));
+    }
+  };
+
+  let restore_response = follow_deploy_progress(package_name, response.into_inner()).await?;
+  for log_line in &restore_response.logs {
+    log_deploy_server_entry(log_line);
+  }
+  if restore_response.success {
+    info!(
+      "Restore succeeded for {} (ID: {})",
+      package_name, restore_response.deploy_id
+    );
+    Ok(())
+  } else {
+    error!(
+      "Restore failed for {}: {}",
+      package_name, restore_response.message
+    );
+    Err(Box::new(AdeployError::Deploy(format!(
+      "Package {} restore failed: {}",
+      package_name, restore_response.message
+    ))))
+  }
 }
 
 fn load_client_configuration(provider: &dyn ConfigProvider) -> Result<ClientConfig> {
@@ -86,16 +165,28 @@ async fn connect_deploy_client(
   let actual_port = remote_config.port;
   info!("Connecting to {}:{} for deployment", host, actual_port);
 
-  let endpoint_uri = format!("http://{}:{}", host, actual_port);
-  let endpoint = Channel::from_shared(endpoint_uri)
+  let scheme = if remote_config.tls.is_some() {
+    "https"
+  } else {
+    "http"
+  };
+  let endpoint_uri = format!("{}://{}:{}", scheme, host, actual_port);
+  let mut endpoint = Channel::from_shared(endpoint_uri)
     .map_err(|e| Box::new(AdeployError::Network(format!("Invalid endpoint: {}", e))))?;
+  if let Some(tls_settings) = &remote_config.tls {
+    endpoint = endpoint
+      .tls_config(tls::client_tls_config(tls_settings)?)
+      .map_err(|e| Box::new(AdeployError::Config(format!("Invalid TLS config: {}", e))))?;
+  }
   let endpoint = configure_endpoint(endpoint, remote_config.timeout);
   let channel = endpoint
     .connect()
     .await
     .map_err(|e| Box::new(AdeployError::Network(format!("Failed to connect: {}", e))))?;
 
-  let message_limit = clamp_message_limit(resolved_max_file_size(remote_config));
+  // Archives are streamed in small chunks, so this only bounds individual gRPC messages
+  let message_limit =
+    clamp_message_limit(resolved_max_file_size(remote_config).unwrap_or(DEFAULT_MAX_MESSAGE_SIZE));
   Ok(
     DeployServiceClient::new(channel)
       .max_decoding_message_size(message_limit)
@@ -103,7 +194,20 @@ async fn connect_deploy_client(
   )
 }
 
-fn prepare_auth_resources(provider: &dyn ConfigProvider) -> Result<AuthResources> {
+fn prepare_auth_resources(
+  provider: &dyn ConfigProvider,
+  config: &ClientConfig,
+) -> Result<Box<dyn Signer>> {
+  if let Some(agent_settings) = &config.ssh_agent {
+    let signer = AgentSigner::connect(agent_settings)?;
+    let identity = signer.identity();
+    info!(
+      "Signing with ssh-agent identity {} ({})",
+      identity.fingerprint, identity.comment
+    );
+    return Ok(Box::new(signer));
+  }
+
   let key_paths = provider.get_key_paths()?;
   let private_key_path = key_paths.private_key;
   let public_key_path = key_paths.public_key;
@@ -123,10 +227,7 @@ fn prepare_auth_resources(provider: &dyn ConfigProvider) -> Result<AuthResources
     )))
   })?;
 
-  Ok(AuthResources {
-    ssh_auth,
-    public_key,
-  })
+  Ok(Box::new(KeyFileSigner::new(ssh_auth, public_key)))
 }
 
 fn select_packages(
@@ -156,48 +257,62 @@ fn select_packages(
 async fn deploy_single_package(
   deploy_manager: &DeployManager,
   client: &mut DeployServiceClient<Channel>,
-  ssh_auth: &Auth,
-  public_key: &str,
+  signer: &dyn Signer,
   package_name: &str,
   package_config: &ClientPackageConfig,
-  max_file_size: u64,
+  max_file_size: Option<u64>,
 ) -> Result<()> {
   info!("Deploying {}", package_name);
 
-  let (archive_data, file_hash) = deploy_manager
+  let archive = deploy_manager
     .package_files(package_name, package_config)
     .await?;
 
-  enforce_client_archive_size(&archive_data, max_file_size)?;
-
-  let signature = ssh_auth
-    .sign_data(&archive_data)
+  enforce_client_archive_size(archive.size, max_file_size)?;
+
+  let version = "1.0.0";
+  let metadata = HashMap::new();
+  let timestamp = Utc::now().timestamp();
+  let nonce = Uuid::new_v4().simple().to_string();
+
+  // The archive itself is streamed, so the signature covers its hash plus the request fields
+  let envelope = SignedEnvelope {
+    package_name,
+    version,
+    file_hash: &archive.hash,
+    metadata: &metadata,
+    timestamp,
+    nonce: &nonce,
+  };
+  let public_key = signer.public_key();
+  let signature = signer
+    .sign(&envelope.canonical_bytes())
     .map_err(|e| Box::new(AdeployError::Auth(format!("Failed to sign data: {}", e))))?;
 
-  let request = tonic::Request::new(DeployRequest {
+  let header = DeployHeader {
     package_name: package_name.to_string(),
-    version: "1.0.0".to_string(),
-    file_data: archive_data,
-    file_hash,
+    version: version.to_string(),
+    file_hash: archive.hash.clone(),
+    file_size: archive.size,
     signature: general_purpose::STANDARD.encode(&signature),
     public_key: public_key.to_string(),
-    metadata: std::collections::HashMap::new(),
-  });
+    metadata,
+    timestamp,
+    nonce,
+  };
 
-  let response = match client.deploy(request).await {
+  let chunks = stream_archive_chunks(header, archive.path.to_path_buf());
+  let response = match client.deploy_progress(chunks).await {
     Ok(resp) => resp,
     Err(status) => {
       if status.code() == tonic::Code::Unauthenticated {
-        error!(
-          "Deployment rejected (unauthenticated). Add this public key to the server's `allowed_keys`: {}",
-          public_key.trim()
-        );
+        explain_rejection(&status, public_key);
       }
       return Err(Box::new(AdeployError::Grpc(status)));
     }
   };
 
-  let deploy_response = response.into_inner();
+  let deploy_response = follow_deploy_progress(package_name, response.into_inner()).await?;
 
   if deploy_response.success {
     info!(
@@ -223,6 +338,134 @@ async fn deploy_single_package(
   }
 }
 
+/// Print live progress events until the server reports the final result
+async fn follow_deploy_progress(
+  package_name: &str,
+  mut events: Streaming<DeployEvent>,
+) -> Result<DeployResponse> {
+  while let Some(event) = events
+    .message()
+    .await
+    .map_err(|status| Box::new(AdeployError::Grpc(status)))?
+  {
+    match event.event {
+      Some(Event::Stage(stage)) => {
+        let stage = DeployStage::try_from(stage).unwrap_or(DeployStage::Unspecified);
+        info!("[{}] Stage: {}", package_name, stage_label(stage));
+      }
+      Some(Event::Log(log_line)) => log_deploy_server_entry(&log_line),
+      Some(Event::Result(response)) => return Ok(response),
+      None => {}
+    }
+  }
+
+  Err(Box::new(AdeployError::Network(
+    "Server closed the progress stream without reporting a result".to_string(),
+  )))
+}
+
+fn stage_label(stage: DeployStage) -> &'static str {
+  match stage {
+    DeployStage::Unspecified => "unknown",
+    DeployStage::Verify => "verify",
+    DeployStage::Backup => "backup",
+    DeployStage::BeforeScript => "before-script",
+    DeployStage::Extract => "extract",
+    DeployStage::AfterScript => "after-script",
+    DeployStage::Activate => "activate",
+    DeployStage::Rollback => "rollback",
+    DeployStage::HealthCheck => "health-check",
+    DeployStage::Restore => "restore",
+  }
+}
+
+/// Stream the header followed by the archive contents in fixed-size chunks
+fn stream_archive_chunks(
+  header: DeployHeader,
+  archive_path: PathBuf,
+) -> ReceiverStream<DeployChunk> {
+  let (tx, rx) = mpsc::channel(4);
+
+  tokio::spawn(async move {
+    let header = DeployChunk {
+      payload: Some(Payload::Header(header)),
+    };
+    if tx.send(header).await.is_err() {
+      return;
+    }
+
+    let mut file = match tokio::fs::File::open(&archive_path).await {
+      Ok(file) => file,
+      Err(e) => {
+        error!("Failed to open archive {}: {}", archive_path.display(), e);
+        return;
+      }
+    };
+
+    loop {
+      let mut buffer = vec![0u8; UPLOAD_CHUNK_SIZE];
+      let read = match file.read(&mut buffer).await {
+        Ok(0) => break,
+        Ok(read) => read,
+        Err(e) => {
+          error!("Failed to read archive {}: {}", archive_path.display(), e);
+          break;
+        }
+      };
+      buffer.truncate(read);
+
+      let chunk = DeployChunk {
+        payload: Some(Payload::Data(buffer)),
+      };
+      if tx.send(chunk).await.is_err() {
+        break;
+      }
+    }
+  });
+
+  ReceiverStream::new(rx)
+}
+
+fn explain_rejection(status: &tonic::Status, public_key: &str) {
+  let reason = status
+    .metadata()
+    .get(AUTH_REJECTION_METADATA)
+    .and_then(|value| value.to_str().ok())
+    .and_then(AuthRejection::parse);
+
+  match reason {
+    Some(AuthRejection::ClockSkew) => error!(
+      "Deployment rejected: request timestamp is outside the server's window. Check that client and server clocks are synchronized"
+    ),
+    Some(AuthRejection::ReplayedNonce) => {
+      error!("Deployment rejected: the server has already seen this request (replay)")
+    }
+    Some(AuthRejection::LegacySignature) => {
+      error!("Deployment rejected: the server requires signed request envelopes")
+    }
+    Some(AuthRejection::InvalidSignature) => {
+      error!("Deployment rejected: signature does not match the public key")
+    }
+    Some(AuthRejection::KeyNotYetValid) => error!(
+      "Deployment rejected: this key is not valid yet ({})",
+      status.message()
+    ),
+    Some(AuthRejection::KeyExpired) => error!(
+      "Deployment rejected: this key has expired ({}). Ask the server operator to renew it",
+      status.message()
+    ),
+    Some(AuthRejection::KeyRevoked) => {
+      error!("Deployment rejected: this key has been revoked on the server")
+    }
+    Some(AuthRejection::KeyNotAllowed) | None => error!(
+      "Deployment rejected (unauthenticated). Add this public key to the server's `allowed_keys` or authorized keys file: {}",
+      Auth::parse_public_key(public_key)
+        .map(|key| Auth::format_openssh_public_key(&key, None))
+        .unwrap_or_else(|_| public_key.trim().to_string())
+    ),
+  }
+}
+
 fn configure_endpoint(endpoint: Endpoint, timeout_secs: u64) -> Endpoint {
   if timeout_secs == 0 {
     endpoint
@@ -232,11 +475,9 @@ fn configure_endpoint(endpoint: Endpoint, timeout_secs: u64) -> Endpoint {
   }
 }
 
-fn resolved_max_file_size(config: &RemoteConfig) -> u64 {
-  config
-    .max_file_size
-    .filter(|value| *value > 0)
-    .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+/// Archive size limit set for a remote; unset or 0 leaves the limit to the server
+fn resolved_max_file_size(config: &RemoteConfig) -> Option<u64> {
+  config.max_file_size.filter(|value| *value > 0)
 }
 
 fn clamp_message_limit(limit: u64) -> usize {
@@ -246,15 +487,12 @@ fn clamp_message_limit(limit: u64) -> usize {
     .unwrap_or(usize::MAX)
 }
 
-fn enforce_client_archive_size(data: &[u8], limit: u64) -> Result<()> {
-  if limit > 0 {
-    let archive_size = data.len() as u64;
-    if archive_size > limit {
-      return Err(Box::new(AdeployError::Deploy(format!(
-        "Archive size {} exceeds configured max_file_size {}",
-        archive_size, limit
-      ))));
-    }
+fn enforce_client_archive_size(archive_size: u64, limit: Option<u64>) -> Result<()> {
+  if let Some(limit) = limit.filter(|limit| archive_size > *limit) {
+    return Err(Box::new(AdeployError::Deploy(format!(
+      "Archive size {} exceeds configured max_file_size {}",
+      archive_size, limit
+    ))));
   }
   Ok(())
 }
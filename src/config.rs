@@ -1,15 +1,18 @@
 use std::{
-  collections::HashMap,
+  collections::{HashMap, HashSet},
   env, fs,
   path::{Path, PathBuf},
 };
 
+use chrono::{DateTime, NaiveDate, Utc};
+use glob::Pattern;
 use log2::*;
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
 
 use crate::{
   auth::Auth,
   error::{AdeployError, Result},
+  passphrase,
 };
 
 pub enum ConfigType {
@@ -101,10 +104,11 @@ impl ConfigProvider for ConfigProviderImpl {
   }
 
   fn get_key_paths(&self) -> Result<KeyPairPaths> {
-    let exe_dir = executable_dir()?;
-    let key_dir = exe_dir.join(".key");
-    let private_key_path = key_dir.join("id_ed25519");
-    let public_key_path = key_dir.join("id_ed25519.pub");
+    let KeyPairPaths {
+      private_key: private_key_path,
+      public_key: public_key_path,
+    } = default_key_paths()?;
+    let key_dir = executable_dir()?.join(".key");
 
     if !key_dir.exists() {
       fs::create_dir_all(&key_dir).map_err(|e| {
@@ -116,11 +120,24 @@ impl ConfigProvider for ConfigProviderImpl {
     }
 
     if !private_key_path.exists() || !public_key_path.exists() {
-      info!("Generating Ed25519 key pair");
-      Auth::generate_key_pair(
-        &public_key_path.to_string_lossy(),
-        &private_key_path.to_string_lossy(),
-      )?;
+      let public_key_path = public_key_path.to_string_lossy();
+      let private_key_path = private_key_path.to_string_lossy();
+
+      // A passphrase supplied through the environment encrypts the new private key
+      match passphrase::from_environment()? {
+        Some(passphrase) => {
+          info!("Generating Ed25519 key pair with an encrypted private key");
+          Auth::generate_key_pair_with_passphrase(
+            &public_key_path,
+            &private_key_path,
+            Some(&passphrase),
+          )?;
+        }
+        None => {
+          info!("Generating Ed25519 key pair");
+          Auth::generate_key_pair(&public_key_path, &private_key_path)?;
+        }
+      }
       info!("Stored key pair in {:?}", key_dir);
     }
 
@@ -133,6 +150,20 @@ impl ConfigProvider for ConfigProviderImpl {
 pub struct ClientConfig {
   pub packages: HashMap<String, ClientPackageConfig>,
   pub remotes: HashMap<String, RemoteConfig>,
+  /// Sign with an ssh-agent identity instead of the key files next to the executable
+  #[serde(default)]
+  pub ssh_agent: Option<SshAgentSettings>,
+}
+
+/// ssh-agent signer settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SshAgentSettings {
+  /// Key fingerprint (`SHA256:...`) or comment; the first ed25519 identity when omitted
+  #[serde(default)]
+  pub identity: Option<String>,
+  /// Agent socket path; defaults to `SSH_AUTH_SOCK`
+  #[serde(default)]
+  pub socket: Option<String>,
 }
 
 /// Package configuration for client
@@ -148,6 +179,26 @@ pub struct RemoteConfig {
   pub timeout: u64,
   #[serde(default)]
   pub max_file_size: Option<u64>,
+  /// Connect over TLS when present
+  #[serde(default)]
+  pub tls: Option<RemoteTlsSettings>,
+}
+
+/// Client-side TLS settings for a remote
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteTlsSettings {
+  /// PEM CA bundle used to verify the server; system roots are used when omitted
+  #[serde(default)]
+  pub ca: Option<String>,
+  /// PEM client certificate presented for mutual TLS
+  #[serde(default)]
+  pub client_cert: Option<String>,
+  /// PEM private key matching `client_cert`
+  #[serde(default)]
+  pub client_key: Option<String>,
+  /// Override the name checked against the server certificate
+  #[serde(default)]
+  pub server_name: Option<String>,
 }
 
 /// Server deployment configuration structure based on DESIGN.md
@@ -163,9 +214,275 @@ pub struct ServerPackageConfig {
   pub deploy_path: String,
   pub before_deploy_script: Option<String>,
   pub after_deploy_script: Option<String>,
+  /// Time limit for `before_deploy_script`, in seconds or with an `s`, `m` or `h` suffix
+  #[serde(default, deserialize_with = "deserialize_duration_secs")]
+  pub before_deploy_timeout: Option<u64>,
+  /// Time limit for `after_deploy_script`
+  #[serde(default, deserialize_with = "deserialize_duration_secs")]
+  pub after_deploy_timeout: Option<u64>,
+  /// How long a timed-out script may take to exit after SIGTERM before it is killed
+  #[serde(default, deserialize_with = "deserialize_duration_secs")]
+  pub script_kill_grace: Option<u64>,
+  /// Ordered hook commands per lifecycle stage, run after the single scripts above
+  #[serde(default)]
+  pub hooks: HooksConfig,
   #[serde(default)]
   pub backup_enabled: bool,
   pub backup_path: Option<String>,
+  /// Newest backups kept after each backup
+  #[serde(default)]
+  pub backup_keep_count: Option<usize>,
+  /// Backups older than this many seconds are removed; accepts `12h`, `30d` or `2w`
+  #[serde(default, deserialize_with = "deserialize_duration_secs")]
+  pub backup_max_age: Option<u64>,
+  /// Oldest backups are removed while all of them together exceed this many bytes;
+  /// accepts `500M` or `5G`
+  #[serde(default, deserialize_with = "deserialize_byte_size")]
+  pub backup_max_total_size: Option<u64>,
+  /// Key names (or raw keys) allowed to deploy this package, overriding per-key grants
+  #[serde(default)]
+  pub allowed_keys: Option<Vec<String>>,
+  /// How deployed files are laid out under `deploy_path`
+  #[serde(default)]
+  pub layout: DeployLayout,
+  /// Number of releases kept under `releases/`, including the live one
+  #[serde(default = "default_keep_releases")]
+  pub keep_releases: usize,
+  /// Restore the pre-deploy backup (or keep the previous release) when a deployment fails
+  /// after files were replaced
+  #[serde(default)]
+  pub rollback_on_failure: bool,
+  /// Probe that must pass after the files are live for the deployment to succeed
+  #[serde(default)]
+  pub health_check: Option<HealthCheckConfig>,
+  /// How in-place extraction treats files already in `deploy_path`
+  #[serde(default)]
+  pub sync_mode: SyncMode,
+  /// Globs relative to `deploy_path` that deployments never overwrite or delete
+  #[serde(default)]
+  pub preserve: Vec<String>,
+  /// Owner given to extracted files, by user name or uid; needs root unless it is the
+  /// server's own user
+  #[serde(default)]
+  pub owner: Option<String>,
+  /// Group given to extracted files, by name or gid; defaults to the owner's primary group
+  #[serde(default)]
+  pub group: Option<String>,
+  /// Mode for extracted files, such as `"0644"`, replacing the one recorded in the archive
+  #[serde(default, deserialize_with = "deserialize_mode")]
+  pub file_mode: Option<u32>,
+  /// Mode for extracted directories, such as `"0755"`
+  #[serde(default, deserialize_with = "deserialize_mode")]
+  pub dir_mode: Option<u32>,
+  /// Modes for extracted paths matching a glob relative to the archive root, such as
+  /// `"bin/*" = "0755"`; the longest matching glob wins
+  #[serde(default, deserialize_with = "deserialize_mode_map")]
+  pub modes: HashMap<String, u32>,
+  /// Keep setuid, setgid and sticky bits recorded in the archive instead of stripping them
+  #[serde(default)]
+  pub keep_setuid: bool,
+  /// Directory hook scripts run in, relative to `deploy_path` (or to the release being deployed
+  /// with the releases layout) unless absolute; defaults to the directory holding the adeploy
+  /// executable
+  #[serde(default)]
+  pub working_directory: Option<String>,
+  /// Extra environment variables for hook scripts; adeploy's own `ADEPLOY_*` variables win
+  #[serde(default)]
+  pub env: HashMap<String, String>,
+  /// Inherited environment variables (names or globs such as `LC_*`) hook scripts may see;
+  /// unset passes the server's whole environment through
+  #[serde(default)]
+  pub env_allowlist: Option<Vec<String>>,
+  /// Unix user hook scripts run as, by name or uid; requires the server to run as root
+  #[serde(default)]
+  pub run_as_user: Option<String>,
+  /// Unix group hook scripts run as, by name or gid; defaults to the user's primary group
+  #[serde(default)]
+  pub run_as_group: Option<String>,
+  /// Resource limits for each hook script process on Unix
+  #[serde(default)]
+  pub hook_limits: HookLimits,
+}
+
+/// Resource limits applied to hook scripts and everything they start
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HookLimits {
+  /// CPU time in seconds, or with an `s`, `m` or `h` suffix (`RLIMIT_CPU`)
+  #[serde(default, deserialize_with = "deserialize_duration_secs")]
+  pub cpu_time: Option<u64>,
+  /// Address space in bytes, or with a `K`, `M` or `G` suffix (`RLIMIT_AS`)
+  #[serde(default, deserialize_with = "deserialize_byte_size")]
+  pub address_space: Option<u64>,
+  /// Open file descriptors (`RLIMIT_NOFILE`)
+  #[serde(default)]
+  pub open_files: Option<u64>,
+}
+
+/// Hook commands for each lifecycle stage, run in order via `sh -c`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HooksConfig {
+  /// Before anything is changed
+  #[serde(default, deserialize_with = "deserialize_pre_change_hooks")]
+  pub before_deploy: Vec<HookConfig>,
+  /// Before the live files are backed up, when `backup_enabled` is set
+  #[serde(default, deserialize_with = "deserialize_pre_change_hooks")]
+  pub before_backup: Vec<HookConfig>,
+  /// After the files are in place, when nothing was deployed before
+  #[serde(default)]
+  pub on_first_install: Vec<HookConfig>,
+  /// After the files are in place, when they replace an earlier deployment
+  #[serde(default)]
+  pub on_upgrade: Vec<HookConfig>,
+  /// After the files are in place, before a release is activated
+  #[serde(default)]
+  pub after_deploy: Vec<HookConfig>,
+  /// Once the deployment is live and its health check (if any) has passed
+  #[serde(default)]
+  pub after_health_check: Vec<HookConfig>,
+  /// After a deployment fails, once any rollback has finished
+  #[serde(default)]
+  pub on_failure: Vec<HookConfig>,
+  /// After a failed deployment has been rolled back
+  #[serde(default)]
+  pub on_rollback: Vec<HookConfig>,
+}
+
+/// A hook command; written either as a plain string or as a table with its own settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "HookEntry")]
+pub struct HookConfig {
+  pub command: String,
+  /// Time limit in seconds, after which the command's process group is terminated
+  pub timeout: Option<u64>,
+  /// What a failure does to the deployment; each stage has its own default
+  pub failure: Option<FailurePolicy>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HookEntry {
+  Command(String),
+  Detailed {
+    command: String,
+    #[serde(default, deserialize_with = "deserialize_duration_secs")]
+    timeout: Option<u64>,
+    #[serde(default)]
+    failure: Option<FailurePolicy>,
+  },
+}
+
+impl From<HookEntry> for HookConfig {
+  fn from(entry: HookEntry) -> Self {
+    match entry {
+      HookEntry::Command(command) => Self {
+        command,
+        timeout: None,
+        failure: None,
+      },
+      HookEntry::Detailed {
+        command,
+        timeout,
+        failure,
+      } => Self {
+        command,
+        timeout,
+        failure,
+      },
+    }
+  }
+}
+
+/// How a failing hook affects the deployment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+  /// Fail the deployment; files are only rolled back when `rollback_on_failure` is set
+  Abort,
+  /// Log the failure and carry on with the remaining hooks
+  Warn,
+  /// Fail the deployment and roll it back even if `rollback_on_failure` is off
+  Rollback,
+}
+
+/// Treatment of existing files when extracting in place
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+  /// Add and overwrite files, leaving everything else in place
+  #[default]
+  Merge,
+  /// Also delete files that are not in the archive
+  Mirror,
+}
+
+/// Post-deploy health check, retried until it passes or attempts run out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthCheckConfig {
+  #[serde(flatten)]
+  pub probe: HealthProbe,
+  /// Status an HTTP probe must return
+  #[serde(default = "default_expected_status")]
+  pub expected_status: u16,
+  /// Time limit for a single attempt
+  #[serde(default = "default_health_timeout")]
+  pub timeout_secs: u64,
+  /// Additional attempts after the first failure
+  #[serde(default = "default_health_retries")]
+  pub retries: u32,
+  /// Delay between attempts
+  #[serde(default = "default_health_interval")]
+  pub interval_secs: u64,
+}
+
+/// What a health check probes; exactly one of `http`, `tcp` or `command`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthProbe {
+  /// Plain `http://` URL fetched with GET
+  Http(String),
+  /// `host:port` that must accept a connection
+  Tcp(String),
+  /// Shell command that must exit with status 0
+  Command(String),
+}
+
+/// Directory layout of a deployed package
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployLayout {
+  /// Extract directly into `deploy_path`
+  #[default]
+  InPlace,
+  /// Extract into `deploy_path/releases/<deploy_id>` and switch the `current` symlink to it
+  Releases,
+}
+
+impl ServerPackageConfig {
+  /// Directory holding the live deployment
+  pub fn live_path(&self) -> PathBuf {
+    match self.layout {
+      DeployLayout::InPlace => PathBuf::from(&self.deploy_path),
+      DeployLayout::Releases => Path::new(&self.deploy_path).join("current"),
+    }
+  }
+
+  /// Whether anything is deployed yet, which decides between `on_first_install` and
+  /// `on_upgrade` hooks
+  pub fn has_live_deployment(&self) -> bool {
+    fs::read_dir(self.live_path())
+      .map(|mut entries| entries.next().is_some())
+      .unwrap_or(false)
+  }
+
+  /// Whether `key` may deploy this package, honoring the package-level override
+  pub fn permits_key(&self, package_name: &str, key: &AllowedKey) -> bool {
+    match &self.allowed_keys {
+      Some(entries) => entries
+        .iter()
+        .any(|entry| entry == key.public_key() || Some(entry.as_str()) == key.name()),
+      None => key.grants_package(package_name),
+    }
+  }
 }
 
 /// Server settings configuration
@@ -173,7 +490,398 @@ pub struct ServerPackageConfig {
 pub struct ServerSettings {
   pub port: u16,
   pub max_file_size: u64,
-  pub allowed_keys: Vec<String>,
+  pub allowed_keys: Vec<AllowedKey>,
+  /// Accept signatures over the archive only, without the signed request envelope, on the
+  /// unary `Deploy` RPC. Such requests can be replayed and re-targeted to another package or
+  /// version, so this is off by default; chunked uploads always require the envelope.
+  #[serde(default)]
+  pub allow_legacy_signatures: bool,
+  /// Maximum clock skew (seconds) for signed requests; nonces are remembered this long
+  #[serde(default = "default_replay_window")]
+  pub replay_window_secs: u64,
+  /// File persisting seen nonces; defaults to `nonce_cache.json` next to the executable
+  #[serde(default)]
+  pub nonce_cache_path: Option<String>,
+  /// OpenSSH authorized_keys file whose ed25519 keys may deploy every package;
+  /// the key comment is used as its name. Entries with options adeploy cannot enforce, such
+  /// as `from=` or `command=`, are skipped.
+  #[serde(default)]
+  pub authorized_keys_file: Option<String>,
+  /// File listing revoked public keys or fingerprints, one per line
+  #[serde(default)]
+  pub revoked_keys_file: Option<String>,
+  /// Serve over TLS when present
+  #[serde(default)]
+  pub tls: Option<ServerTlsSettings>,
+  /// Which deployments exclude each other: those of the same package, or all of them
+  #[serde(default)]
+  pub deploy_lock: DeployLockScope,
+  /// Seconds a deployment queues behind a running one before it is rejected; 0 rejects at once
+  #[serde(default)]
+  pub deploy_lock_wait_secs: u64,
+}
+
+/// Scope of the lock that keeps deployments from running concurrently
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployLockScope {
+  /// One deployment at a time per package
+  #[default]
+  Package,
+  /// One deployment at a time across the whole server
+  Global,
+}
+
+impl ServerSettings {
+  /// Find the allowlist entry for a client public key in any supported encoding
+  pub fn find_key(&self, public_key: &str) -> Option<AllowedKey> {
+    let wanted = Auth::parse_public_key(public_key).ok()?;
+    let matches = |entry: &AllowedKey| {
+      Auth::parse_public_key(entry.public_key()).is_ok_and(|key| key == wanted)
+    };
+
+    if let Some(entry) = self.allowed_keys.iter().find(|entry| matches(entry)) {
+      return Some(entry.clone());
+    }
+
+    match self.load_authorized_keys() {
+      Ok(entries) => entries.into_iter().find(|entry| matches(entry)),
+      Err(e) => {
+        warn!("{}", e);
+        None
+      }
+    }
+  }
+
+  /// Read the ed25519 keys of the configured authorized_keys file
+  pub fn load_authorized_keys(&self) -> Result<Vec<AllowedKey>> {
+    let Some(path) = &self.authorized_keys_file else {
+      return Ok(Vec::new());
+    };
+
+    let content = fs::read_to_string(path).map_err(|e| {
+      Box::new(AdeployError::Config(format!(
+        "Failed to read authorized keys file '{}': {}",
+        path, e
+      )))
+    })?;
+
+    Ok(
+      Auth::parse_authorized_keys(&content)
+        .into_iter()
+        .map(|entry| match (entry.comment, entry.expires_at) {
+          (None, None) => AllowedKey::Bare(entry.key),
+          (comment, expires_at) => AllowedKey::Named(NamedKey {
+            name: comment.unwrap_or_else(|| Auth::fingerprint(&entry.key).unwrap_or_default()),
+            key: entry.key,
+            comment: None,
+            packages: None,
+            not_before: None,
+            expires_at,
+          }),
+        })
+        .collect(),
+    )
+  }
+
+  /// Read the revocation list; blank lines and `#` comments are ignored
+  pub fn load_revoked_keys(&self) -> Result<HashSet<String>> {
+    let Some(path) = &self.revoked_keys_file else {
+      return Ok(HashSet::new());
+    };
+
+    let content = fs::read_to_string(path).map_err(|e| {
+      Box::new(AdeployError::Config(format!(
+        "Failed to read revoked keys file '{}': {}",
+        path, e
+      )))
+    })?;
+
+    Ok(
+      content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect(),
+    )
+  }
+}
+
+/// Entry in `allowed_keys`: a bare public key or a named key with package grants
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AllowedKey {
+  /// Bare base64 public key permitted to deploy every package
+  Bare(String),
+  Named(NamedKey),
+}
+
+/// Named deploy key restricted to a set of packages and an optional validity window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NamedKey {
+  pub name: String,
+  pub key: String,
+  #[serde(default)]
+  pub comment: Option<String>,
+  /// Package names or glob patterns; every package when omitted
+  #[serde(default)]
+  pub packages: Option<Vec<String>>,
+  #[serde(default, deserialize_with = "deserialize_timestamp")]
+  pub not_before: Option<DateTime<Utc>>,
+  #[serde(default, deserialize_with = "deserialize_timestamp")]
+  pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl AllowedKey {
+  pub fn public_key(&self) -> &str {
+    match self {
+      AllowedKey::Bare(key) => key.trim(),
+      AllowedKey::Named(named) => named.key.trim(),
+    }
+  }
+
+  pub fn name(&self) -> Option<&str> {
+    match self {
+      AllowedKey::Bare(_) => None,
+      AllowedKey::Named(named) => Some(&named.name),
+    }
+  }
+
+  pub fn not_before(&self) -> Option<DateTime<Utc>> {
+    match self {
+      AllowedKey::Bare(_) => None,
+      AllowedKey::Named(named) => named.not_before,
+    }
+  }
+
+  pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+    match self {
+      AllowedKey::Bare(_) => None,
+      AllowedKey::Named(named) => named.expires_at,
+    }
+  }
+
+  /// Whether the key's own package grants cover `package_name`
+  pub fn grants_package(&self, package_name: &str) -> bool {
+    match self {
+      AllowedKey::Bare(_) => true,
+      AllowedKey::Named(named) => match &named.packages {
+        Some(patterns) => patterns
+          .iter()
+          .any(|pattern| matches_pattern(pattern, package_name)),
+        None => true,
+      },
+    }
+  }
+}
+
+/// Accept TOML datetimes, RFC 3339 strings or plain dates (midnight UTC)
+fn deserialize_timestamp<'de, D>(
+  deserializer: D,
+) -> std::result::Result<Option<DateTime<Utc>>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum TimestampRepr {
+    Toml(toml::value::Datetime),
+    Text(String),
+  }
+
+  let text = match TimestampRepr::deserialize(deserializer)? {
+    TimestampRepr::Toml(datetime) => datetime.to_string(),
+    TimestampRepr::Text(text) => text,
+  };
+
+  if let Ok(datetime) = DateTime::parse_from_rfc3339(&text) {
+    return Ok(Some(datetime.with_timezone(&Utc)));
+  }
+
+  NaiveDate::parse_from_str(&text, "%Y-%m-%d")
+    .ok()
+    .and_then(|date| date.and_hms_opt(0, 0, 0))
+    .map(|datetime| Some(datetime.and_utc()))
+    .ok_or_else(|| D::Error::custom(format!("invalid timestamp '{}'", text)))
+}
+
+/// Accept a number of seconds or a number with an `s`, `m`, `h`, `d` or `w` suffix
+fn deserialize_duration_secs<'de, D>(deserializer: D) -> std::result::Result<Option<u64>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let (number, unit) = match NumberWithUnit::deserialize(deserializer)? {
+    NumberWithUnit::Number(number) => return Ok(Some(number)),
+    NumberWithUnit::Text(text) => {
+      split_unit(&text).ok_or_else(|| D::Error::custom(format!("invalid duration '{}'", text)))?
+    }
+  };
+
+  let multiplier = match unit.as_str() {
+    "" | "s" => 1,
+    "m" => 60,
+    "h" => 60 * 60,
+    "d" => 24 * 60 * 60,
+    "w" => 7 * 24 * 60 * 60,
+    _ => {
+      return Err(D::Error::custom(format!(
+        "invalid duration unit '{}'; use s, m, h, d or w",
+        unit
+      )))
+    }
+  };
+  Ok(Some(number.saturating_mul(multiplier)))
+}
+
+/// Hooks that run before any file changes, where there is nothing to roll back
+fn deserialize_pre_change_hooks<'de, D>(
+  deserializer: D,
+) -> std::result::Result<Vec<HookConfig>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let hooks = Vec::<HookConfig>::deserialize(deserializer)?;
+  if let Some(hook) = hooks
+    .iter()
+    .find(|hook| hook.failure == Some(FailurePolicy::Rollback))
+  {
+    return Err(D::Error::custom(format!(
+      "hook '{}' cannot use failure = \"rollback\" before anything has changed; use \"abort\" or \"warn\"",
+      hook.command
+    )));
+  }
+  Ok(hooks)
+}
+
+/// Accept a number of bytes or a number with a binary `K`, `M`, `G` or `T` suffix
+fn deserialize_byte_size<'de, D>(deserializer: D) -> std::result::Result<Option<u64>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let (number, unit) = match NumberWithUnit::deserialize(deserializer)? {
+    NumberWithUnit::Number(number) => return Ok(Some(number)),
+    NumberWithUnit::Text(text) => {
+      split_unit(&text).ok_or_else(|| D::Error::custom(format!("invalid size '{}'", text)))?
+    }
+  };
+
+  let unit = unit.to_ascii_uppercase();
+  let exponent = match unit.trim_end_matches("IB").trim_end_matches('B') {
+    "" => 0,
+    "K" => 1,
+    "M" => 2,
+    "G" => 3,
+    "T" => 4,
+    _ => {
+      return Err(D::Error::custom(format!(
+        "invalid size unit '{}'; use K, M, G or T",
+        unit
+      )))
+    }
+  };
+  Ok(Some(number.saturating_mul(1024u64.pow(exponent))))
+}
+
+/// Accept an octal string such as `"0755"` or an integer such as `0o755`
+fn deserialize_mode<'de, D>(deserializer: D) -> std::result::Result<Option<u32>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  parse_mode(NumberWithUnit::deserialize(deserializer)?).map(Some)
+}
+
+/// A table of globs to modes, each written as for `deserialize_mode`
+fn deserialize_mode_map<'de, D>(
+  deserializer: D,
+) -> std::result::Result<HashMap<String, u32>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  HashMap::<String, NumberWithUnit>::deserialize(deserializer)?
+    .into_iter()
+    .map(|(glob, mode)| Ok((glob, parse_mode(mode)?)))
+    .collect()
+}
+
+fn parse_mode<E: serde::de::Error>(value: NumberWithUnit) -> std::result::Result<u32, E> {
+  let mode = match value {
+    NumberWithUnit::Number(number) => number,
+    NumberWithUnit::Text(text) => {
+      let digits = text.trim();
+      u64::from_str_radix(digits.strip_prefix("0o").unwrap_or(digits), 8).map_err(|_| {
+        E::custom(format!(
+          "invalid mode '{}'; use an octal string such as \"0755\"",
+          text
+        ))
+      })?
+    }
+  };
+  if mode > 0o7777 {
+    return Err(E::custom(format!("mode {:o} is out of range", mode)));
+  }
+  Ok(mode as u32)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberWithUnit {
+  Number(u64),
+  Text(String),
+}
+
+/// Split `30d` into `(30, "d")`
+fn split_unit(text: &str) -> Option<(u64, String)> {
+  let text = text.trim();
+  let digits = text
+    .find(|c: char| !c.is_ascii_digit())
+    .unwrap_or(text.len());
+  let number = text[..digits].parse().ok()?;
+  Some((number, text[digits..].trim().to_string()))
+}
+
+/// Match a name against a glob pattern, falling back to equality for invalid patterns
+pub fn matches_pattern(pattern: &str, value: &str) -> bool {
+  match Pattern::new(pattern) {
+    Ok(compiled) => compiled.matches(value),
+    Err(_) => pattern == value,
+  }
+}
+
+/// Server-side TLS settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerTlsSettings {
+  /// PEM certificate chain presented to clients
+  pub cert: String,
+  /// PEM private key matching `cert`
+  pub key: String,
+  /// PEM CA bundle; when set, clients must present a certificate signed by it
+  #[serde(default)]
+  pub client_ca: Option<String>,
+}
+
+fn default_keep_releases() -> usize {
+  5
+}
+
+fn default_expected_status() -> u16 {
+  200
+}
+
+fn default_health_timeout() -> u64 {
+  5
+}
+
+fn default_health_retries() -> u32 {
+  3
+}
+
+fn default_health_interval() -> u64 {
+  2
+}
+
+fn default_replay_window() -> u64 {
+  300
 }
 
 /// Get server configuration by IP address, fallback to default if not found
@@ -187,6 +895,15 @@ pub fn get_remote_config<'a>(
     .or_else(|| client_config.remotes.get("default"))
 }
 
+/// Key pair location in the executable's `.key` directory, without generating it
+pub fn default_key_paths() -> Result<KeyPairPaths> {
+  let key_dir = executable_dir()?.join(".key");
+  Ok(KeyPairPaths::new(
+    key_dir.join("id_ed25519"),
+    key_dir.join("id_ed25519.pub"),
+  ))
+}
+
 fn executable_dir() -> Result<PathBuf> {
   let current_exe = env::current_exe().map_err(|e| {
     Box::new(AdeployError::FileSystem(format!(
@@ -1,6 +1,11 @@
 use std::{
-  fs, io,
+  collections::HashMap,
+  fs,
+  io::{self, BufWriter, Write},
   path::{Path, PathBuf},
+  process::{ExitStatus, Stdio},
+  sync::{Mutex, OnceLock},
+  time::Duration,
 };
 
 use chrono::{DateTime, Utc};
@@ -8,35 +13,196 @@ use flate2::{write::GzEncoder, Compression};
 use log2::*;
 use sha2::{Digest, Sha256};
 use tar::Builder;
-use tokio::{process::Command, task::spawn_blocking};
+use tempfile::{NamedTempFile, TempPath};
+use tokio::{
+  io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
+  process::{Child, Command},
+  sync::mpsc,
+  task::spawn_blocking,
+  time::timeout,
+};
 use uuid::Uuid;
 
 use crate::{
-  config::{ClientPackageConfig, ServerPackageConfig},
-  deploy_log::DeployLogEntry,
+  backup::{self, BackupManifest, DeploymentRecord, RetentionPolicy},
+  config::{ClientPackageConfig, DeployLayout, FailurePolicy, ServerPackageConfig, SyncMode},
+  deploy_log::{DeployLogEntry, DeployReporter, DeployStage},
   error::{AdeployError, Result},
+  hooks::{HookFailure, HookStage},
+  permissions::PermissionMap,
+  sandbox,
+  staging::StagedDeploy,
 };
 
+/// Directory under `deploy_path` holding one subdirectory per release
+const RELEASES_DIR: &str = "releases";
+/// Release directories are named `<timestamp>_<deploy_id>`, so their names sort in deploy order
+const RELEASE_TIMESTAMP_FORMAT: &str = "%Y%m%d_%H%M%S_%6f";
+/// Symlink under `deploy_path` pointing at the live release
+const CURRENT_LINK: &str = "current";
+/// Seconds a timed-out script gets to exit after SIGTERM when `script_kill_grace` is unset
+const DEFAULT_KILL_GRACE_SECS: u64 = 10;
+
+/// Gzipped tar archive written to a temporary file by the client.
+pub struct PackagedArchive {
+  pub path: TempPath,
+  pub hash: String,
+  pub size: u64,
+}
+
+/// Archive received from a client whose hash has already been verified.
+pub struct SpooledArchive {
+  pub path: TempPath,
+  pub size: u64,
+}
+
+/// Spools an incoming archive to a temporary file, hashing it as chunks arrive.
+pub struct ArchiveSpool {
+  file: tokio::fs::File,
+  path: TempPath,
+  hasher: Sha256,
+  size: u64,
+}
+
+impl ArchiveSpool {
+  pub fn new() -> Result<Self> {
+    let (file, path) = tempfile::Builder::new()
+      .prefix("adeploy-upload-")
+      .tempfile()
+      .map_err(|e| {
+        Box::new(AdeployError::FileSystem(format!(
+          "Failed to create upload spool file: {}",
+          e
+        )))
+      })?
+      .into_parts();
+
+    Ok(Self {
+      file: tokio::fs::File::from_std(file),
+      path,
+      hasher: Sha256::new(),
+      size: 0,
+    })
+  }
+
+  /// Number of bytes spooled so far
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  pub async fn write_chunk(&mut self, chunk: &[u8]) -> Result<()> {
+    self.file.write_all(chunk).await.map_err(|e| {
+      Box::new(AdeployError::FileSystem(format!(
+        "Failed to write upload spool file: {}",
+        e
+      )))
+    })?;
+    self.hasher.update(chunk);
+    self.size += chunk.len() as u64;
+    Ok(())
+  }
+
+  /// Flush the spool and verify the archive against the expected size and SHA-256 hash
+  pub async fn finish(mut self, expected_hash: &str, expected_size: u64) -> Result<SpooledArchive> {
+    self.file.flush().await.map_err(|e| {
+      Box::new(AdeployError::FileSystem(format!(
+        "Failed to flush upload spool file: {}",
+        e
+      )))
+    })?;
+
+    if self.size != expected_size {
+      error!(
+        "Size mismatch: expected {} bytes, received {}",
+        expected_size, self.size
+      );
+      return Err(Box::new(AdeployError::Deploy(format!(
+        "Size verification failed. Expected: {} bytes, Received: {} bytes",
+        expected_size, self.size
+      ))));
+    }
+
+    let actual_hash = format!("{:x}", self.hasher.finalize());
+    if actual_hash != expected_hash {
+      error!(
+        "Hash mismatch: expected {}, actual {}",
+        expected_hash, actual_hash
+      );
+      return Err(Box::new(AdeployError::Deploy(format!(
+        "Hash verification failed. Expected: {}, Actual: {}",
+        expected_hash, actual_hash
+      ))));
+    }
+
+    info!(
+      "Received archive ({} bytes, hash {})",
+      self.size, actual_hash
+    );
+    Ok(SpooledArchive {
+      path: self.path,
+      size: self.size,
+    })
+  }
+}
+
 /// Deployment manager
 pub struct DeployManager {
   pub deploy_id: String,
+  /// Version the client reported for the package being deployed
+  pub version: String,
   pub start_time: DateTime<Utc>,
+  /// Name of this deployment's directory under `releases`, fixed when first needed
+  release_name: OnceLock<String>,
+  /// Target of the `current` link before this deployment activated its release
+  previous_release: Mutex<Option<PathBuf>>,
+  /// Backup archive this deployment took of the live files, if any
+  backup_file: Mutex<Option<PathBuf>>,
+  /// Package being deployed
+  pub package_name: String,
+  /// Name of the client key that requested the deployment
+  key_name: String,
+  /// Metadata the client attached to the request
+  metadata: HashMap<String, String>,
 }
 
 impl DeployManager {
   pub fn new() -> Self {
     Self {
       deploy_id: Uuid::new_v4().to_string(),
+      version: String::new(),
       start_time: Utc::now(),
+      release_name: OnceLock::new(),
+      previous_release: Mutex::new(None),
+      backup_file: Mutex::new(None),
+      package_name: String::new(),
+      key_name: String::new(),
+      metadata: HashMap::new(),
     }
   }
 
-  /// Package files from sources with hash verification
+  pub fn with_version(mut self, version: &str) -> Self {
+    self.version = version.to_string();
+    self
+  }
+
+  pub fn with_package(mut self, package_name: &str) -> Self {
+    self.package_name = package_name.to_string();
+    self
+  }
+
+  /// Record who requested the deployment, for the environment hook scripts run in
+  pub fn with_client(mut self, key_name: &str, metadata: HashMap<String, String>) -> Self {
+    self.key_name = key_name.to_string();
+    self.metadata = metadata;
+    self
+  }
+
+  /// Package files from sources into a temporary archive with hash verification
   pub async fn package_files(
     &self,
     package_name: &str,
     config: &ClientPackageConfig,
-  ) -> Result<(Vec<u8>, String)> {
+  ) -> Result<PackagedArchive> {
     let package_name = package_name.to_string();
     let config = config.clone();
     spawn_blocking(move || Self::package_files_blocking(&package_name, &config))
@@ -52,143 +218,356 @@ impl DeployManager {
   fn package_files_blocking(
     package_name: &str,
     config: &ClientPackageConfig,
-  ) -> Result<(Vec<u8>, String)> {
+  ) -> Result<PackagedArchive> {
     info!("Packaging {} sources: {:?}", package_name, config.sources);
 
-    let mut archive = Vec::new();
-    {
-      let encoder = GzEncoder::new(&mut archive, Compression::default());
-      let mut tar = Builder::new(encoder);
+    let (file, path) = NamedTempFile::new()
+      .map_err(|e| {
+        Box::new(AdeployError::FileSystem(format!(
+          "Failed to create archive file: {}",
+          e
+        )))
+      })?
+      .into_parts();
 
-      for source_path in &config.sources {
-        let path = Path::new(source_path);
+    let encoder = GzEncoder::new(HashingWriter::new(file), Compression::default());
+    let mut tar = Builder::new(encoder);
 
-        if !path.exists() {
-          return Err(Box::new(AdeployError::FileSystem(format!(
-            "Source path '{}' does not exist",
-            source_path
-          ))));
-        }
+    for source_path in &config.sources {
+      let path = Path::new(source_path);
 
-        if path.is_file() {
-          let file_name = path
-            .file_name()
-            .ok_or_else(|| Box::new(AdeployError::FileSystem("Invalid file name".to_string())))?
-            .to_string_lossy()
-            .to_string();
+      if !path.exists() {
+        return Err(Box::new(AdeployError::FileSystem(format!(
+          "Source path '{}' does not exist",
+          source_path
+        ))));
+      }
 
-          tar.append_path_with_name(path, file_name).map_err(|e| {
-            Box::new(AdeployError::FileSystem(format!(
-              "Failed to add file '{}' to archive: {}",
-              source_path, e
-            )))
-          })?;
-          info!("Archived file {}", source_path);
-        } else if path.is_dir() {
-          tar.append_dir_all("", path).map_err(|e| {
-            Box::new(AdeployError::FileSystem(format!(
-              "Failed to add directory '{}' to archive: {}",
-              source_path, e
-            )))
-          })?;
+      if path.is_file() {
+        let file_name = path
+          .file_name()
+          .ok_or_else(|| Box::new(AdeployError::FileSystem("Invalid file name".to_string())))?
+          .to_string_lossy()
+          .to_string();
 
-          info!("Archived directory {}", source_path);
-        }
+        tar.append_path_with_name(path, file_name).map_err(|e| {
+          Box::new(AdeployError::FileSystem(format!(
+            "Failed to add file '{}' to archive: {}",
+            source_path, e
+          )))
+        })?;
+        info!("Archived file {}", source_path);
+      } else if path.is_dir() {
+        tar.append_dir_all("", path).map_err(|e| {
+          Box::new(AdeployError::FileSystem(format!(
+            "Failed to add directory '{}' to archive: {}",
+            source_path, e
+          )))
+        })?;
+
+        info!("Archived directory {}", source_path);
       }
+    }
 
-      tar.finish().map_err(|e| {
+    let writer = tar
+      .into_inner()
+      .and_then(|encoder| encoder.finish())
+      .map_err(|e| {
         Box::new(AdeployError::FileSystem(format!(
           "Failed to finalize archive: {}",
           e
         )))
       })?;
-    }
-
-    let mut hasher = Sha256::new();
-    hasher.update(&archive);
-    let hash = format!("{:x}", hasher.finalize());
+    let (hash, size) = writer.finish().map_err(|e| {
+      Box::new(AdeployError::FileSystem(format!(
+        "Failed to flush archive: {}",
+        e
+      )))
+    })?;
 
     info!(
       "Created package {} ({} bytes, hash {})",
-      package_name,
-      archive.len(),
-      hash
+      package_name, size, hash
     );
-    Ok((archive, hash))
+    Ok(PackagedArchive { path, hash, size })
   }
 
-  /// Extract and deploy files with hash verification
+  /// Extract and deploy files from a verified archive
   pub async fn extract_files(
     &self,
-    archive_data: Vec<u8>,
-    expected_hash: &str,
+    archive: &SpooledArchive,
     config: &ServerPackageConfig,
     package_name: &str,
+    reporter: &mut DeployReporter,
   ) -> Result<()> {
-    info!("Extracting files into {}", config.deploy_path);
-    info!("Archive size: {} bytes", archive_data.len());
-
-    let archive_data = self
-      .verify_archive_hash(archive_data, expected_hash)
-      .await?;
+    let target = self.extraction_path(config);
+    info!("Extracting files into {}", target.display());
+    info!("Archive size: {} bytes", archive.size);
 
     if config.backup_enabled {
       info!("Creating backup snapshot");
+      reporter.stage(DeployStage::Backup);
       self.create_backup(config, package_name).await?;
+      self.prune_backups(config, package_name, reporter).await;
     }
 
-    self.ensure_deploy_directory(&config.deploy_path).await?;
+    reporter.stage(DeployStage::Extract);
+    self.ensure_deploy_directory(&target).await?;
 
-    self
-      .unpack_archive(archive_data, &config.deploy_path)
-      .await?;
+    match config.layout {
+      // A fresh release directory is never live, so it can be unpacked into directly
+      DeployLayout::Releases => self.unpack_archive(&archive.path, &target, config).await?,
+      DeployLayout::InPlace => {
+        self
+          .stage_and_commit(archive, config, &target, reporter)
+          .await?
+      }
+    }
 
-    info!("Extraction complete: {}", config.deploy_path);
+    info!("Extraction complete: {}", target.display());
     Ok(())
   }
 
-  /// Execute before-deployment script
-  pub async fn execute_before_deploy_script(
+  /// Bring back the files saved in `backup`: in place, or as this deployment's release.
+  /// The live files are backed up first so the restore itself can be undone.
+  pub async fn restore_files(
     &self,
+    backup: &Path,
     config: &ServerPackageConfig,
-  ) -> Result<Vec<DeployLogEntry>> {
-    self
-      .run_deploy_script(config.before_deploy_script.as_deref(), "Before-deploy")
-      .await
+    package_name: &str,
+    reporter: &mut DeployReporter,
+  ) -> Result<()> {
+    if config.backup_enabled {
+      reporter.stage(DeployStage::Backup);
+      self.create_backup(config, package_name).await?;
+    }
+
+    reporter.stage(DeployStage::Restore);
+    reporter.push(DeployLogEntry::info(format!(
+      "Restoring backup {}{}",
+      backup.display(),
+      describe_backup(backup)
+    )));
+    match config.layout {
+      DeployLayout::Releases => {
+        let target = self.extraction_path(config);
+        self.ensure_deploy_directory(&target).await?;
+        let backup = backup.to_path_buf();
+        spawn_blocking(move || backup::restore_backup(&backup, &target))
+          .await
+          .map_err(|e| {
+            Box::new(AdeployError::FileSystem(format!(
+              "Backup restore task failed: {}",
+              e
+            )))
+          })??;
+      }
+      DeployLayout::InPlace => self.restore_in_place(backup, config).await?,
+    }
+
+    // Pruning waits until the restore is done, as it may select the restored backup
+    if config.backup_enabled {
+      self.prune_backups(config, package_name, reporter).await;
+    }
+    info!("Restored {} from {}", package_name, backup.display());
+    Ok(())
   }
 
-  /// Execute after-deployment script
-  pub async fn execute_after_deploy_script(
+  /// Point the `current` symlink at this deployment's release.
+  /// In-place deployments are live as soon as they are extracted.
+  pub async fn activate_release(
     &self,
     config: &ServerPackageConfig,
-  ) -> Result<Vec<DeployLogEntry>> {
-    self
-      .run_deploy_script(config.after_deploy_script.as_deref(), "After-deploy")
-      .await
+    reporter: &mut DeployReporter,
+  ) -> Result<()> {
+    if config.layout != DeployLayout::Releases {
+      return Ok(());
+    }
+
+    reporter.stage(DeployStage::Activate);
+    let deploy_path = PathBuf::from(&config.deploy_path);
+    let target = self.release_link_target();
+    let previous = spawn_blocking(move || -> Result<Option<PathBuf>> {
+      let previous = fs::read_link(deploy_path.join(CURRENT_LINK)).ok();
+      switch_current_link(&deploy_path, &target)?;
+      Ok(previous)
+    })
+    .await
+    .map_err(|e| {
+      Box::new(AdeployError::FileSystem(format!(
+        "Release activation task failed: {}",
+        e
+      )))
+    })??;
+    *self.previous_release.lock().unwrap() = previous;
+
+    info!("Activated release {}", self.release_name());
+    reporter.push(DeployLogEntry::info(format!(
+      "Activated release {}",
+      self.release_name()
+    )));
+    Ok(())
   }
 
-  /// Execute a shell script
-  async fn execute_script(&self, script_path: &str) -> Result<Vec<DeployLogEntry>> {
-    // Get adeploy executable directory
-    let exe_dir = std::env::current_exe()
-      .map_err(|e| {
-        Box::new(AdeployError::Deploy(format!(
-          "Failed to get current executable path: {}",
+  /// Remove releases beyond `keep_releases` once this deployment is known to be good
+  pub async fn prune_releases(&self, config: &ServerPackageConfig, reporter: &mut DeployReporter) {
+    if config.layout != DeployLayout::Releases {
+      return;
+    }
+
+    // The new release is already live, so a failed cleanup only warrants a warning
+    let deploy_path = PathBuf::from(&config.deploy_path);
+    let live_release = self.release_name().to_string();
+    let keep = config.keep_releases;
+    match spawn_blocking(move || remove_old_releases(&deploy_path, &live_release, keep)).await {
+      Ok(Ok(removed)) => {
+        for release in removed {
+          info!("Removed old release {}", release);
+          reporter.push(DeployLogEntry::info(format!(
+            "Removed old release {}",
+            release
+          )));
+        }
+      }
+      Ok(Err(e)) => {
+        warn!("Failed to prune old releases: {}", e);
+        reporter.push(DeployLogEntry::warn(format!(
+          "Failed to prune old releases: {}",
           e
-        )))
-      })?
-      .parent()
-      .ok_or_else(|| {
-        Box::new(AdeployError::Deploy(
-          "Failed to get parent directory of executable".to_string(),
-        ))
-      })?
-      .to_path_buf();
+        )));
+      }
+      Err(e) => warn!("Release pruning task failed: {}", e),
+    }
+  }
 
-    info!(
-      "Executing script in adeploy directory: {}",
-      exe_dir.display()
-    );
+  /// Return to the state before this deployment: restore the pre-deploy backup for in-place
+  /// packages, or discard the new release so the previous one stays live
+  pub async fn rollback(
+    &self,
+    config: &ServerPackageConfig,
+    package_name: &str,
+    reporter: &mut DeployReporter,
+  ) -> Result<()> {
+    reporter.stage(DeployStage::Rollback);
+    match config.layout {
+      DeployLayout::Releases => {
+        if self.is_live_release(config) {
+          self.restore_previous_release(config, reporter).await?;
+        }
+        self.discard_release(config).await;
+        reporter.push(DeployLogEntry::info(format!(
+          "Discarded release {}; the previous release remains live",
+          self.release_name()
+        )));
+        Ok(())
+      }
+      DeployLayout::InPlace => self.restore_backup(config, package_name, reporter).await,
+    }
+  }
+
+  /// Remove this deployment's release directory after a failed deployment
+  pub async fn discard_release(&self, config: &ServerPackageConfig) {
+    if config.layout != DeployLayout::Releases {
+      return;
+    }
+
+    let release_path = self.extraction_path(config);
+    if !release_path.exists() {
+      return;
+    }
+    if self.is_live_release(config) {
+      warn!(
+        "Keeping release {} because it is live",
+        release_path.display()
+      );
+      return;
+    }
+    match tokio::fs::remove_dir_all(&release_path).await {
+      Ok(()) => info!("Discarded release {}", release_path.display()),
+      Err(e) => warn!(
+        "Failed to discard release {}: {}",
+        release_path.display(),
+        e
+      ),
+    }
+  }
+
+  /// Run a stage's hooks in order. A failing hook whose policy is `warn` is reported and the
+  /// remaining hooks still run; any other failure stops the stage.
+  pub async fn run_hooks(
+    &self,
+    stage: HookStage,
+    config: &ServerPackageConfig,
+    reporter: &mut DeployReporter,
+  ) -> std::result::Result<(), HookFailure> {
+    let hooks = stage.hooks(config);
+    if hooks.is_empty() {
+      info!("No {} hooks configured", stage);
+      return Ok(());
+    }
+
+    for hook in &hooks {
+      info!("Running {} hook {}", stage, hook.command);
+      reporter.push(DeployLogEntry::info(format!(
+        "Running {} hook: {}",
+        stage, hook.command
+      )));
+      let Err(error) = self
+        .execute_script(&hook.command, hook.timeout, config, reporter)
+        .await
+      else {
+        info!("{} hook succeeded", stage);
+        continue;
+      };
+
+      let policy = stage.policy(hook, config);
+      if policy == FailurePolicy::Warn {
+        warn!("{} hook failed; continuing: {}", stage, error);
+        reporter.push(DeployLogEntry::warn(format!(
+          "{} hook failed; continuing: {}",
+          stage, error
+        )));
+        continue;
+      }
+      error!("{} hook failed: {}", stage, error);
+      return Err(HookFailure {
+        stage,
+        policy,
+        error,
+      });
+    }
+    Ok(())
+  }
+
+  /// Execute a shell script, reporting each output line as it is produced. A script still
+  /// running after `timeout_secs` is terminated together with everything it started.
+  async fn execute_script(
+    &self,
+    script_path: &str,
+    timeout_secs: Option<u64>,
+    config: &ServerPackageConfig,
+    reporter: &mut DeployReporter,
+  ) -> Result<()> {
+    let working_dir = match &config.working_directory {
+      Some(dir) => self.hook_base_path(config).join(dir),
+      // Get adeploy executable directory
+      None => std::env::current_exe()
+        .map_err(|e| {
+          Box::new(AdeployError::Deploy(format!(
+            "Failed to get current executable path: {}",
+            e
+          )))
+        })?
+        .parent()
+        .ok_or_else(|| {
+          Box::new(AdeployError::Deploy(
+            "Failed to get parent directory of executable".to_string(),
+          ))
+        })?
+        .to_path_buf(),
+    };
+
+    info!("Executing script in {}", working_dir.display());
 
     let mut command = if cfg!(target_os = "windows") {
       let mut cmd = Command::new("cmd");
@@ -200,35 +579,71 @@ impl DeployManager {
       cmd
     };
 
-    // Set working directory to adeploy executable directory
-    command.current_dir(&exe_dir);
+    command.current_dir(&working_dir);
+    sandbox::restrict(&mut command, config)?;
+    command.envs(self.script_environment(config));
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    // Lead a process group of its own, so a timeout reaches the script's children too
+    #[cfg(unix)]
+    command.process_group(0);
 
-    let output = command.output().await.map_err(|e| {
+    let mut child = command.spawn().map_err(|e| {
       Box::new(AdeployError::Deploy(format!(
         "Failed to execute script '{}': {}",
         script_path, e
       )))
     })?;
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let stderr = String::from_utf8_lossy(&output.stderr);
-
-    let mut logs: Vec<DeployLogEntry> = vec![];
-    if !stdout.is_empty() {
-      info!("Script stdout: {}", stdout.trim_end());
-      logs.extend(stdout.lines().map(DeployLogEntry::info));
+    let (line_tx, mut line_rx) = mpsc::unbounded_channel();
+    if let Some(stdout) = child.stdout.take() {
+      spawn_output_reader(stdout, ScriptOutput::Stdout, line_tx.clone());
     }
-    if !stderr.is_empty() {
-      warn!("Script stderr: {}", stderr.trim_end());
-      logs.extend(
-        stderr
-          .lines()
-          .map(|s| DeployLogEntry::warn(format!("STDERR: {}", s))),
-      );
+    if let Some(stderr) = child.stderr.take() {
+      spawn_output_reader(stderr, ScriptOutput::Stderr, line_tx.clone());
+    }
+    drop(line_tx);
+
+    let run = wait_for_script(&mut child, &mut line_rx, reporter);
+    let status = match timeout_secs {
+      Some(limit) => match timeout(Duration::from_secs(limit), run).await {
+        Ok(status) => status,
+        Err(_) => {
+          error!(
+            "Script {} timed out after {}s; terminating it",
+            script_path, limit
+          );
+          reporter.push(DeployLogEntry::error(format!(
+            "Script '{}' timed out after {}s; terminating it",
+            script_path, limit
+          )));
+          let grace = config.script_kill_grace.unwrap_or(DEFAULT_KILL_GRACE_SECS);
+          if !terminate_script(&mut child, Duration::from_secs(grace)).await {
+            warn!(
+              "Script {} did not exit within {}s of SIGTERM; killed",
+              script_path, grace
+            );
+            reporter.push(DeployLogEntry::warn(format!(
+              "Script did not exit within {}s of SIGTERM; killed",
+              grace
+            )));
+          }
+          return Err(Box::new(AdeployError::Deploy(format!(
+            "Script '{}' timed out after {}s",
+            script_path, limit
+          ))));
+        }
+      },
+      None => run.await,
     }
+    .map_err(|e| {
+      Box::new(AdeployError::Deploy(format!(
+        "Failed to wait for script '{}': {}",
+        script_path, e
+      )))
+    })?;
 
-    if !output.status.success() {
-      let exit_code = output.status.code().unwrap_or(-1);
+    if !status.success() {
+      let exit_code = status.code().unwrap_or(-1);
       error!("Script {} failed with exit code {}", script_path, exit_code);
       return Err(Box::new(AdeployError::Deploy(format!(
         "Script '{}' execution failed with exit code: {}",
@@ -237,10 +652,10 @@ impl DeployManager {
     }
 
     info!("Script {} completed", script_path);
-    Ok(logs)
+    Ok(())
   }
 
-  /// Create backup of existing deployment
+  /// Archive the live deployment into this deployment's backup file
   async fn create_backup(&self, config: &ServerPackageConfig, package_name: &str) -> Result<()> {
     if !config.backup_enabled {
       warn!("Backup disabled for {}", package_name);
@@ -257,103 +672,332 @@ impl DeployManager {
 
     info!("Creating backup at {}", backup_dir_path.display());
 
-    let backup_name = format!("backup_{}", self.start_time.format("%Y%m%d_%H%M%S"));
-    let backup_full_path = backup_dir_path.join(backup_name);
+    let backup_full_path = backup::new_backup_path(&backup_dir_path, self.start_time);
+    let live_path = config.live_path();
+    let manifest = BackupManifest {
+      package: package_name.to_string(),
+      created_at: self.start_time,
+      source: live_path.clone(),
+      deployment: DeploymentRecord::load(Path::new(&config.deploy_path)),
+    };
 
-    self.copy_existing_deploy(config, &backup_full_path).await?;
-    self.log_backup_contents(&backup_full_path)?;
+    let destination = backup_full_path.clone();
+    let size = spawn_blocking(move || backup::create_backup(&live_path, &destination, &manifest))
+      .await
+      .map_err(|e| {
+        Box::new(AdeployError::FileSystem(format!(
+          "Backup task failed: {}",
+          e
+        )))
+      })??;
+
+    match size {
+      Some(size) => {
+        info!(
+          "Backup stored at {} ({} bytes)",
+          backup_full_path.display(),
+          size
+        );
+        *self.backup_file.lock().unwrap() = Some(backup_full_path);
+      }
+      None => info!(
+        "No existing deployment at {}; skipping backup",
+        config.live_path().display()
+      ),
+    }
     Ok(())
   }
 
-  /// Copy directory recursively
-  async fn copy_directory(&self, src: &str, dst: &str) -> Result<()> {
-    info!("Copying {} -> {}", src, dst);
+  /// Remember this deployment as the live one, so later backups can say what they hold
+  pub async fn record_deployment(&self, config: &ServerPackageConfig) -> Result<()> {
+    let record = DeploymentRecord {
+      deploy_id: self.deploy_id.clone(),
+      version: self.version.clone(),
+      deployed_at: Utc::now(),
+    };
+    let deploy_path = PathBuf::from(&config.deploy_path);
+    spawn_blocking(move || record.save(&deploy_path))
+      .await
+      .map_err(|e| {
+        Box::new(AdeployError::FileSystem(format!(
+          "Deployment record task failed: {}",
+          e
+        )))
+      })?
+  }
+
+  /// Apply the package's backup retention policy; failures only warrant a warning
+  async fn prune_backups(
+    &self,
+    config: &ServerPackageConfig,
+    package_name: &str,
+    reporter: &mut DeployReporter,
+  ) {
+    let policy = RetentionPolicy::from_config(config);
+    if !policy.is_bounded() {
+      return;
+    }
 
-    let src_path = PathBuf::from(src);
-    let dst_path = PathBuf::from(dst);
+    let pruned = match self.resolve_backup_directory(config, package_name) {
+      Ok(dir) => spawn_blocking(move || backup::prune_backups(&dir, &policy, Utc::now()))
+        .await
+        .unwrap_or_else(|e| {
+          Err(Box::new(AdeployError::FileSystem(format!(
+            "Backup pruning task failed: {}",
+            e
+          ))))
+        }),
+      Err(e) => Err(e),
+    };
 
-    spawn_blocking(move || -> Result<()> {
-      copy_dir_recursive(&src_path, &dst_path).map_err(|e| {
+    match pruned {
+      Ok(removed) => {
+        for backup in removed {
+          reporter.push(DeployLogEntry::info(format!(
+            "Removed old backup {}",
+            backup.name
+          )));
+        }
+      }
+      Err(e) => {
+        warn!("Failed to prune backups of {}: {}", package_name, e);
+        reporter.push(DeployLogEntry::warn(format!(
+          "Failed to prune old backups: {}",
+          e
+        )));
+      }
+    }
+  }
+}
+
+impl DeployManager {
+  /// Variables hook scripts see on top of the inherited environment. The package's `env`
+  /// table comes first so it cannot override what adeploy reports about the deployment.
+  pub fn script_environment(&self, config: &ServerPackageConfig) -> Vec<(String, String)> {
+    let mut env = config
+      .env
+      .iter()
+      .map(|(name, value)| (name.clone(), value.clone()))
+      .collect::<Vec<_>>();
+
+    let mut metadata = self.metadata.iter().collect::<Vec<_>>();
+    metadata.sort();
+    env.extend(
+      metadata
+        .into_iter()
+        .map(|(key, value)| (format!("ADEPLOY_META_{}", env_name(key)), value.clone())),
+    );
+
+    let backup_path = self
+      .backup_file
+      .lock()
+      .unwrap()
+      .as_ref()
+      .map(|path| path.display().to_string())
+      .unwrap_or_default();
+    let previous_version = DeploymentRecord::load(Path::new(&config.deploy_path))
+      .map(|record| record.version)
+      .unwrap_or_default();
+    env.extend([
+      ("ADEPLOY_DEPLOY_ID".to_string(), self.deploy_id.clone()),
+      ("ADEPLOY_PACKAGE".to_string(), self.package_name.clone()),
+      ("ADEPLOY_VERSION".to_string(), self.version.clone()),
+      (
+        "ADEPLOY_DEPLOY_PATH".to_string(),
+        config.deploy_path.clone(),
+      ),
+      (
+        "ADEPLOY_RELEASE_PATH".to_string(),
+        self.extraction_path(config).display().to_string(),
+      ),
+      ("ADEPLOY_BACKUP_PATH".to_string(), backup_path),
+      ("ADEPLOY_PREVIOUS_VERSION".to_string(), previous_version),
+      ("ADEPLOY_KEY_NAME".to_string(), self.key_name.clone()),
+    ]);
+    env
+  }
+
+  /// Unpack beside the live directory, then move the validated entries into place
+  async fn stage_and_commit(
+    &self,
+    archive: &SpooledArchive,
+    config: &ServerPackageConfig,
+    deploy_path: &Path,
+    reporter: &mut DeployReporter,
+  ) -> Result<()> {
+    let staged = StagedDeploy::create(deploy_path, &self.deploy_id)?;
+    info!("Staging files in {}", staged.path().display());
+    self
+      .unpack_archive(&archive.path, staged.path(), config)
+      .await?;
+
+    let sync_mode = config.sync_mode;
+    let preserve = config.preserve.clone();
+    let entries = spawn_blocking(move || -> Result<usize> {
+      let entries = staged.validate()?;
+      staged.commit(sync_mode, &preserve)?;
+      Ok(entries)
+    })
+    .await
+    .map_err(|e| {
+      Box::new(AdeployError::FileSystem(format!(
+        "Staged commit task failed: {}",
+        e
+      )))
+    })??;
+
+    info!(
+      "Committed {} staged entries into {}",
+      entries,
+      deploy_path.display()
+    );
+    reporter.push(DeployLogEntry::info(format!(
+      "Committed {} staged entries",
+      entries
+    )));
+    Ok(())
+  }
+
+  /// Point `current` back at the release that was live before this deployment
+  async fn restore_previous_release(
+    &self,
+    config: &ServerPackageConfig,
+    reporter: &mut DeployReporter,
+  ) -> Result<()> {
+    let deploy_path = PathBuf::from(&config.deploy_path);
+    let previous = self.previous_release.lock().unwrap().clone();
+    let message = match &previous {
+      Some(target) => format!("Switched back to release {}", target.display()),
+      None => "Removed the current link; there was no previous release".to_string(),
+    };
+
+    spawn_blocking(move || match previous {
+      Some(target) => switch_current_link(&deploy_path, &target),
+      // Nothing was live before the first release
+      None => fs::remove_file(deploy_path.join(CURRENT_LINK)).map_err(|e| {
         Box::new(AdeployError::FileSystem(format!(
-          "Directory copy failed: {}",
+          "Failed to remove the current link: {}",
           e
         )))
-      })?;
-      Ok(())
+      }),
     })
     .await
     .map_err(|e| {
       Box::new(AdeployError::FileSystem(format!(
-        "Directory copy task failed: {}",
+        "Release rollback task failed: {}",
         e
       )))
     })??;
 
-    info!("Copied {} -> {}", src, dst);
+    info!("{}", message);
+    reporter.push(DeployLogEntry::info(message));
     Ok(())
   }
-}
 
-impl DeployManager {
-  async fn run_deploy_script(
+  /// Directory name of this deployment's release. The timestamp is taken on first use, which is
+  /// extraction, so releases of a package order by when they were unpacked under its lock.
+  pub fn release_name(&self) -> &str {
+    self.release_name.get_or_init(|| {
+      format!(
+        "{}_{}",
+        Utc::now().format(RELEASE_TIMESTAMP_FORMAT),
+        self.deploy_id
+      )
+    })
+  }
+
+  /// Relative target of the `current` link for this deployment's release
+  fn release_link_target(&self) -> PathBuf {
+    Path::new(RELEASES_DIR).join(self.release_name())
+  }
+
+  /// Whether `current` points at this deployment's release
+  fn is_live_release(&self, config: &ServerPackageConfig) -> bool {
+    fs::read_link(Path::new(&config.deploy_path).join(CURRENT_LINK))
+      .is_ok_and(|target| target == self.release_link_target())
+  }
+
+  /// Make the deploy directory an exact copy of the snapshot taken before this deployment
+  async fn restore_backup(
     &self,
-    script_path: Option<&str>,
-    stage_name: &str,
-  ) -> Result<Vec<DeployLogEntry>> {
-    let Some(path) = script_path else {
-      info!("No {} script configured", stage_name);
-      return Ok(vec![]);
+    config: &ServerPackageConfig,
+    package_name: &str,
+    reporter: &mut DeployReporter,
+  ) -> Result<()> {
+    if !config.backup_enabled {
+      return Err(Box::new(AdeployError::Deploy(format!(
+        "Cannot roll back {}: backup_enabled is off, so no pre-deploy backup exists",
+        package_name
+      ))));
+    }
+    let Some(snapshot) = self.backup_file.lock().unwrap().clone() else {
+      return Err(Box::new(AdeployError::Deploy(format!(
+        "Cannot roll back {}: there was no previous deployment to back up",
+        package_name
+      ))));
     };
 
-    info!("Running {} script {}", stage_name, path);
-    match self.execute_script(path).await {
-      Ok(logs) => {
-        info!("{} script succeeded", stage_name);
-        Ok(logs)
-      }
-      Err(e) => {
-        error!("{} script failed: {}", stage_name, e);
-        Err(e)
-      }
-    }
+    info!(
+      "Restoring {} from {}",
+      config.deploy_path,
+      snapshot.display()
+    );
+    reporter.push(DeployLogEntry::info(format!(
+      "Restoring backup {}{}",
+      snapshot.display(),
+      describe_backup(&snapshot)
+    )));
+    self.restore_in_place(&snapshot, config).await?;
+
+    info!("Restored {} from backup", config.deploy_path);
+    Ok(())
   }
 
-  async fn verify_archive_hash(
-    &self,
-    archive_data: Vec<u8>,
-    expected_hash: &str,
-  ) -> Result<Vec<u8>> {
-    let expected_hash = expected_hash.to_string();
-    let (archive_data, actual_hash) = spawn_blocking(move || -> Result<(Vec<u8>, String)> {
-      let mut hasher = Sha256::new();
-      hasher.update(&archive_data);
-      let actual_hash = format!("{:x}", hasher.finalize());
-      Ok((archive_data, actual_hash))
+  /// Make the deploy directory an exact copy of a backup, apart from preserved paths
+  async fn restore_in_place(&self, backup: &Path, config: &ServerPackageConfig) -> Result<()> {
+    let backup = backup.to_path_buf();
+    let deploy_path = PathBuf::from(&config.deploy_path);
+    let deploy_id = self.deploy_id.clone();
+    let preserve = config.preserve.clone();
+    spawn_blocking(move || -> Result<()> {
+      let staged = StagedDeploy::create(&deploy_path, &deploy_id)?;
+      backup::restore_backup(&backup, staged.path())?;
+      // Preserved paths keep whatever the application wrote since the backup
+      staged.commit(SyncMode::Mirror, &preserve)
     })
     .await
     .map_err(|e| {
-      Box::new(AdeployError::Deploy(format!(
-        "Hash computation task failed: {}",
+      Box::new(AdeployError::FileSystem(format!(
+        "Backup restore task failed: {}",
         e
       )))
-    })??;
+    })?
+  }
 
-    if actual_hash != expected_hash {
-      error!(
-        "Hash mismatch: expected {}, actual {}",
-        expected_hash, actual_hash
-      );
-      return Err(Box::new(AdeployError::Deploy(format!(
-        "Hash verification failed. Expected: {}, Actual: {}",
-        expected_hash, actual_hash
-      ))));
+  /// Directory a relative `working_directory` is resolved against: this deployment's release
+  /// once it has been unpacked, since it is not yet `current` while its hooks run, and
+  /// `deploy_path` before that
+  fn hook_base_path(&self, config: &ServerPackageConfig) -> PathBuf {
+    let release = self.extraction_path(config);
+    if release.is_dir() {
+      release
+    } else {
+      PathBuf::from(&config.deploy_path)
     }
+  }
 
-    Ok(archive_data)
+  /// Directory the archive is unpacked into for this deployment
+  fn extraction_path(&self, config: &ServerPackageConfig) -> PathBuf {
+    match config.layout {
+      DeployLayout::InPlace => PathBuf::from(&config.deploy_path),
+      DeployLayout::Releases => Path::new(&config.deploy_path)
+        .join(RELEASES_DIR)
+        .join(self.release_name()),
+    }
   }
 
-  async fn ensure_deploy_directory(&self, path: &str) -> Result<()> {
-    let deploy_path = PathBuf::from(path);
+  async fn ensure_deploy_directory(&self, path: &Path) -> Result<()> {
+    let deploy_path = path.to_path_buf();
     spawn_blocking(move || fs::create_dir_all(&deploy_path))
       .await
       .map_err(|e| {
@@ -371,17 +1015,44 @@ impl DeployManager {
       })
   }
 
-  async fn unpack_archive(&self, archive_data: Vec<u8>, deploy_path: &str) -> Result<()> {
-    let deploy_path = deploy_path.to_string();
+  /// Unpack an archive, then give its entries the package's ownership and modes
+  async fn unpack_archive(
+    &self,
+    archive_path: &Path,
+    deploy_path: &Path,
+    config: &ServerPackageConfig,
+  ) -> Result<()> {
+    let archive_path = archive_path.to_path_buf();
+    let deploy_path = deploy_path.to_path_buf();
+    let permissions = PermissionMap::from_config(config)?;
+    let keep_setuid = config.keep_setuid;
     spawn_blocking(move || -> Result<()> {
-      let decoder = flate2::read::GzDecoder::new(&archive_data[..]);
+      let file = fs::File::open(&archive_path).map_err(|e| {
+        Box::new(AdeployError::Deploy(format!(
+          "Failed to open archive: {}",
+          e
+        )))
+      })?;
+      let decoder = flate2::read::GzDecoder::new(io::BufReader::new(file));
       let mut archive = tar::Archive::new(decoder);
+      // Setuid, setgid and sticky bits are dropped unless permissions are preserved
+      archive.set_preserve_permissions(keep_setuid);
       archive.unpack(&deploy_path).map_err(|e| {
         Box::new(AdeployError::Deploy(format!(
           "Failed to extract archive: {}",
           e
         )))
       })?;
+
+      if let Some(permissions) = permissions {
+        let entries = permissions.apply(&deploy_path).map_err(|e| {
+          Box::new(AdeployError::FileSystem(format!(
+            "Failed to set ownership and modes: {}",
+            e
+          )))
+        })?;
+        info!("Applied ownership and modes to {} entries", entries);
+      }
       Ok(())
     })
     .await
@@ -399,81 +1070,300 @@ impl DeployManager {
     config: &ServerPackageConfig,
     package_name: &str,
   ) -> Result<PathBuf> {
-    match &config.backup_path {
-      Some(path) => {
-        info!("Using custom backup path {}", path);
-        Ok(Path::new(path).to_path_buf())
-      }
-      None => {
-        let current_exe = std::env::current_exe().map_err(|e| {
-          Box::new(AdeployError::FileSystem(format!(
-            "Failed to get current executable path: {}",
-            e
-          )))
-        })?;
+    if let Some(path) = &config.backup_path {
+      info!("Using custom backup path {}", path);
+    }
+    backup::backup_directory(config, package_name)
+  }
+}
 
-        let current_dir = current_exe.parent().ok_or_else(|| {
-          Box::new(AdeployError::FileSystem(
-            "Failed to get parent directory of executable".to_string(),
-          ))
-        })?;
+/// A single line of hook script output.
+enum ScriptOutput {
+  Stdout(String),
+  Stderr(String),
+}
 
-        Ok(current_dir.join(package_name))
+/// Report script output as it arrives, then wait for the script to exit
+async fn wait_for_script(
+  child: &mut Child,
+  lines: &mut mpsc::UnboundedReceiver<ScriptOutput>,
+  reporter: &mut DeployReporter,
+) -> io::Result<ExitStatus> {
+  while let Some(line) = lines.recv().await {
+    match line {
+      ScriptOutput::Stdout(line) => {
+        info!("Script stdout: {}", line);
+        reporter.push(DeployLogEntry::info(line));
+      }
+      ScriptOutput::Stderr(line) => {
+        warn!("Script stderr: {}", line);
+        reporter.push(DeployLogEntry::warn(format!("STDERR: {}", line)));
       }
     }
   }
+  child.wait().await
+}
 
-  async fn copy_existing_deploy(
-    &self,
-    config: &ServerPackageConfig,
-    backup_full_path: &Path,
-  ) -> Result<()> {
-    if Path::new(&config.deploy_path).exists() {
-      self
-        .copy_directory(&config.deploy_path, &backup_full_path.to_string_lossy())
-        .await?;
-      info!("Backup stored at {}", backup_full_path.display());
+/// Send SIGTERM to a script's process group and SIGKILL whatever is left once the whole group
+/// has exited or `grace` has passed. Returns whether the group exited within the grace period.
+#[cfg(unix)]
+async fn terminate_script(child: &mut Child, grace: Duration) -> bool {
+  let Some(pid) = child.id() else {
+    return true;
+  };
+  let group = -(pid as libc::pid_t);
+  let deadline = tokio::time::Instant::now() + grace;
+  // SAFETY: kill has no memory preconditions; the group is the one the script leads
+  unsafe { libc::kill(group, libc::SIGTERM) };
+
+  // Reap the script first so it no longer counts as a member of its group
+  let script_exited = timeout(grace, child.wait()).await.is_ok();
+  let mut exited = script_exited;
+  // Background children may outlive the script and get the rest of the grace period
+  while exited && process_group_alive(group) {
+    if tokio::time::Instant::now() >= deadline {
+      exited = false;
     } else {
-      info!(
-        "No existing deployment at {}; skipping backup",
-        config.deploy_path
-      );
+      tokio::time::sleep(Duration::from_millis(50)).await;
     }
-    Ok(())
   }
 
-  fn log_backup_contents(&self, backup_full_path: &Path) -> Result<()> {
-    if backup_full_path.exists() {
-      for entry in backup_full_path.read_dir()? {
-        let entry = entry?;
-        info!("Backup item: {}", entry.file_name().to_string_lossy());
+  // SAFETY: as above
+  unsafe { libc::kill(group, libc::SIGKILL) };
+  if !script_exited {
+    let _ = child.wait().await;
+  }
+  exited
+}
+
+/// Whether any process is left in a process group, given as a negative pid
+#[cfg(unix)]
+fn process_group_alive(group: libc::pid_t) -> bool {
+  // SAFETY: signal 0 only checks that the group exists and can be signalled
+  let result = unsafe { libc::kill(group, 0) };
+  if result != 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH) {
+    return false;
+  }
+  // Exited members can still be signalled until they are reaped, which orphans may never be
+  // when no init process runs, e.g. with adeploy as PID 1 in a container
+  #[cfg(target_os = "linux")]
+  let alive = group_has_running_member(-group);
+  #[cfg(not(target_os = "linux"))]
+  let alive = true;
+  alive
+}
+
+/// Whether a process group has a member that is not a zombie, per /proc/<pid>/stat
+#[cfg(target_os = "linux")]
+fn group_has_running_member(pgid: libc::pid_t) -> bool {
+  let Ok(entries) = fs::read_dir("/proc") else {
+    return true;
+  };
+  let pgid = pgid.to_string();
+  entries.flatten().any(|entry| {
+    let Ok(stat) = fs::read_to_string(entry.path().join("stat")) else {
+      return false;
+    };
+    // The fields after the parenthesised command name start with state, ppid and pgrp
+    let Some((_, fields)) = stat.rsplit_once(')') else {
+      return false;
+    };
+    let mut fields = fields.split_whitespace();
+    let state = fields.next();
+    let pgrp = fields.nth(1);
+    state != Some("Z") && pgrp == Some(pgid.as_str())
+  })
+}
+
+#[cfg(not(unix))]
+async fn terminate_script(child: &mut Child, _grace: Duration) -> bool {
+  let _ = child.kill().await;
+  true
+}
+
+/// Forward lines from a script output pipe, tolerating non UTF-8 output
+fn spawn_output_reader<R>(
+  pipe: R,
+  wrap: fn(String) -> ScriptOutput,
+  sender: mpsc::UnboundedSender<ScriptOutput>,
+) where
+  R: AsyncRead + Unpin + Send + 'static,
+{
+  tokio::spawn(async move {
+    let mut reader = BufReader::new(pipe);
+    let mut buffer = Vec::new();
+    loop {
+      buffer.clear();
+      match reader.read_until(b'\n', &mut buffer).await {
+        Ok(0) | Err(_) => break,
+        Ok(_) => {
+          let line = String::from_utf8_lossy(&buffer)
+            .trim_end_matches(['\r', '\n'])
+            .to_string();
+          if sender.send(wrap(line)).is_err() {
+            break;
+          }
+        }
       }
     }
-    Ok(())
+  });
+}
+
+/// Buffered file writer that hashes everything written through it.
+struct HashingWriter {
+  inner: BufWriter<fs::File>,
+  hasher: Sha256,
+  size: u64,
+}
+
+impl HashingWriter {
+  fn new(file: fs::File) -> Self {
+    Self {
+      inner: BufWriter::new(file),
+      hasher: Sha256::new(),
+      size: 0,
+    }
+  }
+
+  fn finish(mut self) -> io::Result<(String, u64)> {
+    self.inner.flush()?;
+    Ok((format!("{:x}", self.hasher.finalize()), self.size))
   }
 }
 
-fn copy_dir_recursive(src: &Path, dst: &Path) -> io::Result<()> {
-  if !dst.exists() {
-    fs::create_dir_all(dst)?;
+impl Write for HashingWriter {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = self.inner.write(buf)?;
+    self.hasher.update(&buf[..written]);
+    self.size += written as u64;
+    Ok(written)
   }
 
-  for entry in fs::read_dir(src)? {
-    let entry = entry?;
-    let file_type = entry.file_type()?;
-    let target = dst.join(entry.file_name());
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
 
-    if file_type.is_dir() {
-      copy_dir_recursive(&entry.path(), &target)?;
-    } else {
-      if let Some(parent) = target.parent() {
-        fs::create_dir_all(parent)?;
+/// " of deployment <id> (<version>)" when the backup's manifest names what it holds
+fn describe_backup(backup: &Path) -> String {
+  match backup::read_manifest(backup) {
+    Ok(Some(BackupManifest {
+      deployment: Some(record),
+      ..
+    })) => format!(" of deployment {} ({})", record.deploy_id, record.version),
+    Ok(_) => String::new(),
+    Err(e) => {
+      warn!("{}", e);
+      String::new()
+    }
+  }
+}
+
+/// Metadata key as an environment variable suffix: uppercase, with anything but
+/// letters, digits and `_` replaced by `_`
+fn env_name(key: &str) -> String {
+  key
+    .chars()
+    .map(|c| {
+      if c.is_ascii_alphanumeric() {
+        c.to_ascii_uppercase()
+      } else {
+        '_'
       }
-      fs::copy(entry.path(), &target)?;
+    })
+    .collect()
+}
+
+/// Atomically repoint `deploy_path/current` at a release, given relative to `deploy_path`
+fn switch_current_link(deploy_path: &Path, target: &Path) -> Result<()> {
+  let current = deploy_path.join(CURRENT_LINK);
+  if let Ok(metadata) = fs::symlink_metadata(&current) {
+    if !metadata.file_type().is_symlink() {
+      return Err(Box::new(AdeployError::FileSystem(format!(
+        "{} exists and is not a symlink; move it aside to use the releases layout",
+        current.display()
+      ))));
+    }
+  }
+
+  // Build the new link beside the old one, then rename it over `current` in one step
+  let release = target.file_name().unwrap_or_default().to_string_lossy();
+  let staged = deploy_path.join(format!(".{}-{}", CURRENT_LINK, release));
+  let link_error = |e: io::Error| {
+    Box::new(AdeployError::FileSystem(format!(
+      "Failed to point {} at {}: {}",
+      current.display(),
+      target.display(),
+      e
+    )))
+  };
+  symlink_dir(target, &staged).map_err(link_error)?;
+  replace_link(&staged, &current).map_err(|e| {
+    let _ = fs::remove_file(&staged);
+    link_error(e)
+  })
+}
+
+#[cfg(unix)]
+fn symlink_dir(target: &Path, link: &Path) -> io::Result<()> {
+  std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink_dir(target: &Path, link: &Path) -> io::Result<()> {
+  std::os::windows::fs::symlink_dir(target, link)
+}
+
+#[cfg(unix)]
+fn replace_link(staged: &Path, link: &Path) -> io::Result<()> {
+  fs::rename(staged, link)
+}
+
+/// Windows cannot rename over a directory symlink, so the swap is not atomic there
+#[cfg(windows)]
+fn replace_link(staged: &Path, link: &Path) -> io::Result<()> {
+  if fs::symlink_metadata(link).is_ok() {
+    fs::remove_dir(link)?;
+  }
+  fs::rename(staged, link)
+}
+
+/// Remove the oldest releases so at most `keep` remain, counting the live one. Releases are
+/// ordered by name; directories from before releases carried a timestamp count as the oldest.
+fn remove_old_releases(
+  deploy_path: &Path,
+  live_release: &str,
+  keep: usize,
+) -> io::Result<Vec<String>> {
+  let releases_dir = deploy_path.join(RELEASES_DIR);
+  let mut releases = Vec::new();
+  for entry in fs::read_dir(&releases_dir)? {
+    let entry = entry?;
+    let name = entry.file_name().to_string_lossy().into_owned();
+    if name == live_release || !entry.file_type()?.is_dir() {
+      continue;
     }
+    releases.push((has_release_timestamp(&name), name));
   }
 
-  Ok(())
+  releases.sort();
+  let excess = releases.len().saturating_sub(keep.saturating_sub(1));
+  let mut removed = Vec::new();
+  for (_, name) in releases.into_iter().take(excess) {
+    fs::remove_dir_all(releases_dir.join(&name))?;
+    removed.push(name);
+  }
+  Ok(removed)
+}
+
+/// Whether a release directory name starts with a `RELEASE_TIMESTAMP_FORMAT` timestamp
+fn has_release_timestamp(name: &str) -> bool {
+  let mut parts = name.splitn(4, '_');
+  [8, 6, 6].iter().all(|&len| {
+    parts
+      .next()
+      .is_some_and(|part| part.len() == len && part.bytes().all(|b| b.is_ascii_digit()))
+  }) && parts.next().is_some()
 }
 
 impl Default for DeployManager {
@@ -0,0 +1,251 @@
+//! Keeps deployments that write to the same files from running at the same time.
+
+use std::{
+  collections::HashMap,
+  fs::{self, File, OpenOptions, TryLockError},
+  io::Write,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use log2::*;
+use tokio::{
+  sync::{Mutex as AsyncMutex, OwnedMutexGuard},
+  task::spawn_blocking,
+  time::{sleep, Instant},
+};
+
+use crate::{
+  error::{AdeployError, Result},
+  staging::RESERVED_PREFIX,
+};
+
+/// How often a queued deployment retries a deploy path lock held by another process
+const PATH_LOCK_POLL: Duration = Duration::from_millis(100);
+
+/// Another deployment holds the lock that was requested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockBusy {
+  pub package_name: String,
+  pub deploy_id: String,
+}
+
+/// Deployment currently holding a lock.
+struct Holder {
+  package_name: String,
+  deploy_id: String,
+}
+
+#[derive(Default)]
+struct Slot {
+  lock: Arc<AsyncMutex<()>>,
+  holder: Mutex<Option<Holder>>,
+}
+
+/// Named locks handed out to deployments, queueing later requests for the same name.
+#[derive(Default)]
+pub struct DeployLocks {
+  slots: Mutex<HashMap<String, Arc<Slot>>>,
+}
+
+/// Held for the duration of a deployment; dropping it lets the next one in.
+pub struct DeployLockGuard {
+  slot: Arc<Slot>,
+  _guard: OwnedMutexGuard<()>,
+  path_lock: Option<PathLock>,
+}
+
+impl DeployLockGuard {
+  /// Also hold `path_lock` until this guard is dropped
+  pub fn with_path_lock(mut self, path_lock: PathLock) -> Self {
+    self.path_lock = Some(path_lock);
+    self
+  }
+}
+
+impl Drop for DeployLockGuard {
+  fn drop(&mut self) {
+    *self.slot.holder.lock().unwrap() = None;
+  }
+}
+
+impl DeployLocks {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Take the lock called `name` for a deployment, waiting up to `wait` for the current holder
+  pub async fn acquire(
+    &self,
+    name: &str,
+    package_name: &str,
+    deploy_id: &str,
+    wait: Duration,
+  ) -> std::result::Result<DeployLockGuard, LockBusy> {
+    let slot = self
+      .slots
+      .lock()
+      .unwrap()
+      .entry(name.to_string())
+      .or_default()
+      .clone();
+
+    let guard = match slot.lock.clone().try_lock_owned() {
+      Ok(guard) => Some(guard),
+      Err(_) if wait.is_zero() => None,
+      Err(_) => {
+        info!(
+          "Deployment {} of {} waits up to {}s for deployment {} to finish",
+          deploy_id,
+          package_name,
+          wait.as_secs(),
+          Self::busy(&slot).deploy_id
+        );
+        tokio::time::timeout(wait, slot.lock.clone().lock_owned())
+          .await
+          .ok()
+      }
+    };
+
+    let Some(guard) = guard else {
+      return Err(Self::busy(&slot));
+    };
+    *slot.holder.lock().unwrap() = Some(Holder {
+      package_name: package_name.to_string(),
+      deploy_id: deploy_id.to_string(),
+    });
+    Ok(DeployLockGuard {
+      slot,
+      _guard: guard,
+      path_lock: None,
+    })
+  }
+
+  fn busy(slot: &Slot) -> LockBusy {
+    match slot.holder.lock().unwrap().as_ref() {
+      Some(holder) => LockBusy {
+        package_name: holder.package_name.clone(),
+        deploy_id: holder.deploy_id.clone(),
+      },
+      // The holder finished between the failed attempt and this lookup
+      None => LockBusy {
+        package_name: String::new(),
+        deploy_id: "unknown".to_string(),
+      },
+    }
+  }
+}
+
+/// Advisory lock on a package's `deploy_path`, taken by every adeploy process that writes to
+/// it: the server and `adeploy server restore`. Released when dropped.
+pub struct PathLock {
+  _file: File,
+}
+
+impl PathLock {
+  /// File locked for `deploy_path`. Like the staging directory it lives beside `deploy_path`,
+  /// so it never shows up as a deployed file or creates a missing `deploy_path`.
+  pub fn lock_file(deploy_path: &Path) -> PathBuf {
+    match (deploy_path.parent(), deploy_path.file_name()) {
+      (Some(parent), Some(name)) => parent.join(format!(
+        ".{}{}lock",
+        name.to_string_lossy(),
+        RESERVED_PREFIX
+      )),
+      _ => deploy_path.join(format!("{}lock", RESERVED_PREFIX)),
+    }
+  }
+
+  /// Lock `deploy_path` for a deployment, retrying for up to `wait` while another process holds
+  /// it. The inner error names the deployment holding the lock.
+  pub async fn acquire(
+    deploy_path: &Path,
+    package_name: &str,
+    deploy_id: &str,
+    wait: Duration,
+  ) -> Result<std::result::Result<Self, LockBusy>> {
+    let path = Self::lock_file(deploy_path);
+    let deadline = Instant::now() + wait;
+    let mut waiting = false;
+    loop {
+      let attempt = {
+        let path = path.clone();
+        let holder = format!("{}\n{}\n", package_name, deploy_id);
+        spawn_blocking(move || Self::try_acquire(&path, &holder))
+          .await
+          .map_err(|e| {
+            Box::new(AdeployError::FileSystem(format!(
+              "Deploy lock task failed: {}",
+              e
+            )))
+          })??
+      };
+      if let Some(lock) = attempt {
+        return Ok(Ok(lock));
+      }
+
+      let busy = Self::holder(&path);
+      if Instant::now() >= deadline {
+        return Ok(Err(busy));
+      }
+      if !waiting {
+        waiting = true;
+        info!(
+          "Deployment {} of {} waits up to {}s for deployment {} in another process to finish",
+          deploy_id,
+          package_name,
+          wait.as_secs(),
+          busy.deploy_id
+        );
+      }
+      sleep(PATH_LOCK_POLL.min(deadline - Instant::now())).await;
+    }
+  }
+
+  /// Take the lock without waiting, recording `holder` in the file; `None` while it is held
+  fn try_acquire(path: &Path, holder: &str) -> Result<Option<Self>> {
+    let lock_error = |e: std::io::Error| {
+      Box::new(AdeployError::FileSystem(format!(
+        "Failed to lock {}: {}",
+        path.display(),
+        e
+      )))
+    };
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent).map_err(lock_error)?;
+    }
+    let mut file = OpenOptions::new()
+      .create(true)
+      .truncate(false)
+      .read(true)
+      .write(true)
+      .open(path)
+      .map_err(lock_error)?;
+
+    match file.try_lock() {
+      Ok(()) => {}
+      Err(TryLockError::WouldBlock) => return Ok(None),
+      Err(TryLockError::Error(e)) => return Err(lock_error(e)),
+    }
+    file.set_len(0).map_err(lock_error)?;
+    file.write_all(holder.as_bytes()).map_err(lock_error)?;
+    Ok(Some(Self { _file: file }))
+  }
+
+  /// Deployment recorded in the lock file by its current holder
+  fn holder(path: &Path) -> LockBusy {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    let mut lines = content.lines();
+    match (lines.next(), lines.next()) {
+      (Some(package_name), Some(deploy_id)) => LockBusy {
+        package_name: package_name.to_string(),
+        deploy_id: deploy_id.to_string(),
+      },
+      _ => LockBusy {
+        package_name: String::new(),
+        deploy_id: "unknown".to_string(),
+      },
+    }
+  }
+}
@@ -1,5 +1,7 @@
 //! Structured deployment log types used between the server and client.
 
+use tokio::sync::mpsc::UnboundedSender;
+
 #[derive(Clone, Copy, Debug)]
 pub enum LogLevel {
   Info,
@@ -33,3 +35,67 @@ impl DeployLogEntry {
     Self::new(LogLevel::Error, message)
   }
 }
+
+/// Deployment stages reported to live progress listeners.
+#[derive(Clone, Copy, Debug)]
+pub enum DeployStage {
+  Verify,
+  Backup,
+  BeforeScript,
+  Extract,
+  AfterScript,
+  Activate,
+  Rollback,
+  HealthCheck,
+  Restore,
+}
+
+/// Progress event emitted while a deployment is running.
+#[derive(Clone, Debug)]
+pub enum DeployProgress {
+  Stage(DeployStage),
+  Log(DeployLogEntry),
+}
+
+/// Collects deployment logs and forwards them to a live listener when one is attached.
+#[derive(Default)]
+pub struct DeployReporter {
+  entries: Vec<DeployLogEntry>,
+  listener: Option<UnboundedSender<DeployProgress>>,
+}
+
+impl DeployReporter {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn with_listener(listener: UnboundedSender<DeployProgress>) -> Self {
+    Self {
+      entries: Vec::new(),
+      listener: Some(listener),
+    }
+  }
+
+  /// Announce a stage transition to the live listener
+  pub fn stage(&mut self, stage: DeployStage) {
+    self.send(DeployProgress::Stage(stage));
+  }
+
+  pub fn push(&mut self, entry: DeployLogEntry) {
+    self.send(DeployProgress::Log(entry.clone()));
+    self.entries.push(entry);
+  }
+
+  pub fn into_entries(self) -> Vec<DeployLogEntry> {
+    self.entries
+  }
+
+  fn send(&mut self, progress: DeployProgress) {
+    if let Some(listener) = &self.listener {
+      // A disconnected client must not abort the deployment itself
+      if listener.send(progress).is_err() {
+        self.listener = None;
+      }
+    }
+  }
+}
@@ -0,0 +1,194 @@
+//! Post-deploy health checks
+
+use std::{process::Stdio, time::Duration};
+
+use log2::*;
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::TcpStream,
+  process::Command,
+  time::{sleep, timeout},
+};
+
+use crate::{
+  config::{HealthCheckConfig, HealthProbe, ServerPackageConfig},
+  deploy_log::{DeployLogEntry, DeployReporter},
+  error::{AdeployError, Result},
+  sandbox,
+};
+
+/// Largest HTTP response head read while looking for the status line
+const MAX_STATUS_LINE: usize = 8 * 1024;
+
+/// Probe until the check passes or every attempt has failed. Command probes run under the
+/// package's hook restrictions.
+pub async fn run_health_check(
+  check: &HealthCheckConfig,
+  config: &ServerPackageConfig,
+  reporter: &mut DeployReporter,
+) -> Result<()> {
+  let attempts = check.retries.saturating_add(1);
+  let attempt_timeout = Duration::from_secs(check.timeout_secs);
+  let description = describe(&check.probe);
+
+  let mut last_failure = String::new();
+  for attempt in 1..=attempts {
+    let outcome = match timeout(attempt_timeout, probe(check, config)).await {
+      Ok(outcome) => outcome,
+      Err(_) => Err(format!("timed out after {}s", check.timeout_secs)),
+    };
+
+    match outcome {
+      Ok(()) => {
+        info!(
+          "Health check {} passed on attempt {}/{}",
+          description, attempt, attempts
+        );
+        reporter.push(DeployLogEntry::info(format!(
+          "Health check {} passed on attempt {}/{}",
+          description, attempt, attempts
+        )));
+        return Ok(());
+      }
+      Err(failure) => {
+        warn!(
+          "Health check {} attempt {}/{} failed: {}",
+          description, attempt, attempts, failure
+        );
+        reporter.push(DeployLogEntry::warn(format!(
+          "Health check attempt {}/{} failed: {}",
+          attempt, attempts, failure
+        )));
+        last_failure = failure;
+      }
+    }
+
+    if attempt < attempts {
+      sleep(Duration::from_secs(check.interval_secs)).await;
+    }
+  }
+
+  Err(Box::new(AdeployError::Deploy(format!(
+    "Health check {} failed after {} attempts: {}",
+    description, attempts, last_failure
+  ))))
+}
+
+fn describe(probe: &HealthProbe) -> String {
+  match probe {
+    HealthProbe::Http(url) => format!("GET {}", url),
+    HealthProbe::Tcp(address) => format!("TCP {}", address),
+    HealthProbe::Command(command) => format!("command '{}'", command),
+  }
+}
+
+async fn probe(
+  check: &HealthCheckConfig,
+  config: &ServerPackageConfig,
+) -> std::result::Result<(), String> {
+  match &check.probe {
+    HealthProbe::Http(url) => probe_http(url, check.expected_status).await,
+    HealthProbe::Tcp(address) => TcpStream::connect(address)
+      .await
+      .map(|_| ())
+      .map_err(|e| format!("connection to {} failed: {}", address, e)),
+    HealthProbe::Command(command) => probe_command(command, config).await,
+  }
+}
+
+/// Issue a minimal HTTP/1.0 GET and compare the response status
+async fn probe_http(url: &str, expected_status: u16) -> std::result::Result<(), String> {
+  let rest = url
+    .strip_prefix("http://")
+    .ok_or_else(|| format!("unsupported URL '{}'; only http:// is supported", url))?;
+  let (authority, path) = match rest.find('/') {
+    Some(index) => rest.split_at(index),
+    None => (rest, "/"),
+  };
+  let address = if authority.contains(':') {
+    authority.to_string()
+  } else {
+    format!("{}:80", authority)
+  };
+
+  let mut stream = TcpStream::connect(&address)
+    .await
+    .map_err(|e| format!("connection to {} failed: {}", address, e))?;
+  let request = format!(
+    "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: adeploy\r\nConnection: close\r\n\r\n",
+    path, authority
+  );
+  stream
+    .write_all(request.as_bytes())
+    .await
+    .map_err(|e| format!("request to {} failed: {}", url, e))?;
+
+  let mut head = Vec::new();
+  let mut buffer = [0u8; 1024];
+  while !head.contains(&b'\n') && head.len() < MAX_STATUS_LINE {
+    let read = stream
+      .read(&mut buffer)
+      .await
+      .map_err(|e| format!("reading response from {} failed: {}", url, e))?;
+    if read == 0 {
+      break;
+    }
+    head.extend_from_slice(&buffer[..read]);
+  }
+
+  let status_line = String::from_utf8_lossy(&head);
+  let status = status_line
+    .lines()
+    .next()
+    .and_then(|line| line.split_whitespace().nth(1))
+    .and_then(|code| code.parse::<u16>().ok())
+    .ok_or_else(|| format!("malformed HTTP response from {}", url))?;
+
+  if status == expected_status {
+    Ok(())
+  } else {
+    Err(format!(
+      "{} returned status {}, expected {}",
+      url, status, expected_status
+    ))
+  }
+}
+
+async fn probe_command(
+  command: &str,
+  config: &ServerPackageConfig,
+) -> std::result::Result<(), String> {
+  let mut process = if cfg!(target_os = "windows") {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+  } else {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+  };
+  sandbox::restrict(&mut process, config).map_err(|e| e.to_string())?;
+
+  // Dropping the future on timeout must not leave the probe running
+  let output = process
+    .stdin(Stdio::null())
+    .kill_on_drop(true)
+    .output()
+    .await
+    .map_err(|e| format!("failed to run '{}': {}", command, e))?;
+
+  if output.status.success() {
+    return Ok(());
+  }
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  Err(format!(
+    "'{}' exited with code {}{}",
+    command,
+    output.status.code().unwrap_or(-1),
+    stderr
+      .lines()
+      .last()
+      .map(|line| format!(": {}", line))
+      .unwrap_or_default()
+  ))
+}
@@ -0,0 +1,111 @@
+//! Lifecycle stages that run hook commands, and the failure policy each one applies
+
+use std::fmt;
+
+use crate::{
+  config::{DeployLayout, FailurePolicy, HookConfig, ServerPackageConfig},
+  error::AdeployError,
+};
+
+/// Point in a deployment at which hooks run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookStage {
+  BeforeDeploy,
+  BeforeBackup,
+  OnFirstInstall,
+  OnUpgrade,
+  AfterDeploy,
+  AfterHealthCheck,
+  OnFailure,
+  OnRollback,
+}
+
+/// A hook failure that stops the deployment, with the policy deciding how it is cleaned up
+#[derive(Debug)]
+pub struct HookFailure {
+  pub stage: HookStage,
+  pub policy: FailurePolicy,
+  pub error: Box<AdeployError>,
+}
+
+impl HookStage {
+  /// Name of the stage's list in the `hooks` table
+  pub fn name(self) -> &'static str {
+    match self {
+      HookStage::BeforeDeploy => "before_deploy",
+      HookStage::BeforeBackup => "before_backup",
+      HookStage::OnFirstInstall => "on_first_install",
+      HookStage::OnUpgrade => "on_upgrade",
+      HookStage::AfterDeploy => "after_deploy",
+      HookStage::AfterHealthCheck => "after_health_check",
+      HookStage::OnFailure => "on_failure",
+      HookStage::OnRollback => "on_rollback",
+    }
+  }
+
+  /// Commands to run, starting with the package's `before_deploy_script` or
+  /// `after_deploy_script` when this stage has one
+  pub fn hooks(self, config: &ServerPackageConfig) -> Vec<HookConfig> {
+    let script = match self {
+      HookStage::BeforeDeploy => config
+        .before_deploy_script
+        .as_ref()
+        .map(|command| (command, config.before_deploy_timeout)),
+      HookStage::AfterDeploy => config
+        .after_deploy_script
+        .as_ref()
+        .map(|command| (command, config.after_deploy_timeout)),
+      _ => None,
+    };
+    let hooks = &config.hooks;
+    let listed = match self {
+      HookStage::BeforeDeploy => &hooks.before_deploy,
+      HookStage::BeforeBackup => &hooks.before_backup,
+      HookStage::OnFirstInstall => &hooks.on_first_install,
+      HookStage::OnUpgrade => &hooks.on_upgrade,
+      HookStage::AfterDeploy => &hooks.after_deploy,
+      HookStage::AfterHealthCheck => &hooks.after_health_check,
+      HookStage::OnFailure => &hooks.on_failure,
+      HookStage::OnRollback => &hooks.on_rollback,
+    };
+
+    script
+      .map(|(command, timeout)| HookConfig {
+        command: command.clone(),
+        timeout,
+        failure: None,
+      })
+      .into_iter()
+      .chain(listed.iter().cloned())
+      .collect()
+  }
+
+  /// Policy applied when `hook` fails
+  pub fn policy(self, hook: &HookConfig, config: &ServerPackageConfig) -> FailurePolicy {
+    match self {
+      // The deployment has already failed, so these can only be reported
+      HookStage::OnFailure | HookStage::OnRollback => FailurePolicy::Warn,
+      _ => hook.failure.unwrap_or_else(|| self.default_policy(config)),
+    }
+  }
+
+  fn default_policy(self, config: &ServerPackageConfig) -> FailurePolicy {
+    match self {
+      HookStage::BeforeDeploy | HookStage::BeforeBackup => FailurePolicy::Abort,
+      // A release only goes live once its hooks succeed, while an in-place deployment is kept
+      // despite them unless it should roll back
+      HookStage::OnFirstInstall | HookStage::OnUpgrade | HookStage::AfterDeploy
+        if config.layout == DeployLayout::Releases || config.rollback_on_failure =>
+      {
+        FailurePolicy::Abort
+      }
+      _ => FailurePolicy::Warn,
+    }
+  }
+}
+
+impl fmt::Display for HookStage {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
@@ -0,0 +1,244 @@
+//! Client key lifecycle and server allowlist editing
+
+use std::{fs, path::Path};
+
+use chrono::Utc;
+use log2::*;
+use toml_edit::{Array, DocumentMut, InlineTable, Item, Value};
+
+use crate::{
+  auth::Auth,
+  config::{KeyPairPaths, ServerConfig},
+  error::{AdeployError, Result},
+};
+
+/// Outcome of replacing a key pair
+#[derive(Debug)]
+pub struct RotatedKey {
+  /// Fingerprint of the key that was replaced
+  pub previous_fingerprint: String,
+  /// Where the previous key pair was kept
+  pub retired: KeyPairPaths,
+}
+
+/// Generate a key pair at `paths`, refusing to overwrite one unless `force` is set
+pub fn generate_key(paths: &KeyPairPaths, passphrase: Option<&str>, force: bool) -> Result<()> {
+  if !force && (paths.private_key.exists() || paths.public_key.exists()) {
+    return Err(Box::new(AdeployError::Config(format!(
+      "A key pair already exists at {}; use `adeploy key rotate` to replace it",
+      paths.private_key.display()
+    ))));
+  }
+
+  if let Some(key_dir) = paths.private_key.parent() {
+    fs::create_dir_all(key_dir).map_err(|e| {
+      Box::new(AdeployError::FileSystem(format!(
+        "Failed to create key directory: {}",
+        e
+      )))
+    })?;
+  }
+
+  Auth::generate_key_pair_with_passphrase(
+    &paths.public_key.to_string_lossy(),
+    &paths.private_key.to_string_lossy(),
+    passphrase,
+  )
+}
+
+/// Public key as an `ssh-ed25519 AAAA...` line ready for `adeploy server authorize`
+pub fn public_key_line(paths: &KeyPairPaths) -> Result<String> {
+  let public_key = Auth::load_public_key(&paths.public_key)?;
+  let key = Auth::parse_public_key(&public_key)?;
+  Ok(Auth::format_openssh_public_key(&key, None))
+}
+
+/// SHA256 fingerprint of the public key
+pub fn key_fingerprint(paths: &KeyPairPaths) -> Result<String> {
+  Auth::fingerprint(&Auth::load_public_key(&paths.public_key)?)
+}
+
+/// Retire the current key pair next to it and generate a replacement. The replacement is
+/// generated beside the current pair first, so a failure leaves the current key in place.
+pub fn rotate_key(paths: &KeyPairPaths, passphrase: Option<&str>) -> Result<RotatedKey> {
+  let previous_fingerprint = key_fingerprint(paths)?;
+
+  let suffix = format!("retired-{}", Utc::now().format("%Y%m%d_%H%M%S"));
+  let retired = KeyPairPaths::new(
+    paths.private_key.with_extension(&suffix),
+    paths.public_key.with_extension(format!("pub.{}", suffix)),
+  );
+  let pending = KeyPairPaths::new(
+    paths.private_key.with_extension("new"),
+    paths.public_key.with_extension("pub.new"),
+  );
+
+  if let Err(e) = generate_key(&pending, passphrase, true) {
+    remove_key_files(&pending);
+    return Err(e);
+  }
+
+  // Retire the current pair, then move the new one into place; undo every completed step if
+  // a later one fails
+  let steps = [
+    (&paths.private_key, &retired.private_key),
+    (&paths.public_key, &retired.public_key),
+    (&pending.private_key, &paths.private_key),
+    (&pending.public_key, &paths.public_key),
+  ];
+  for (index, (from, to)) in steps.iter().enumerate() {
+    if let Err(e) = fs::rename(from, to) {
+      for (done_from, done_to) in steps[..index].iter().rev() {
+        if let Err(undo) = fs::rename(done_to, done_from) {
+          error!(
+            "Failed to move {} back to {}: {}",
+            done_to.display(),
+            done_from.display(),
+            undo
+          );
+        }
+      }
+      remove_key_files(&pending);
+      return Err(Box::new(AdeployError::FileSystem(format!(
+        "Failed to move {} to {}: {}",
+        from.display(),
+        to.display(),
+        e
+      ))));
+    }
+  }
+
+  Ok(RotatedKey {
+    previous_fingerprint,
+    retired,
+  })
+}
+
+fn remove_key_files(paths: &KeyPairPaths) {
+  let _ = fs::remove_file(&paths.private_key);
+  let _ = fs::remove_file(&paths.public_key);
+}
+
+/// Add a named entry to `allowed_keys` in a server config, keeping its comments and layout
+pub fn authorize_key(
+  config_path: &Path,
+  public_key: &str,
+  name: &str,
+  packages: &[String],
+) -> Result<()> {
+  let key = Auth::parse_public_key(public_key)?;
+  let encoded_key = Auth::encode_public_key(&key);
+
+  let content = fs::read_to_string(config_path).map_err(|e| {
+    Box::new(AdeployError::Config(format!(
+      "Failed to read config file {}: {}",
+      config_path.display(),
+      e
+    )))
+  })?;
+  let config: ServerConfig = parse_server_config(&content)?;
+
+  if let Some(existing) = config.server.find_key(&encoded_key) {
+    return Err(Box::new(AdeployError::Config(format!(
+      "Key {} is already authorized as '{}'",
+      Auth::fingerprint(&encoded_key)?,
+      existing.name().unwrap_or("unnamed")
+    ))));
+  }
+  if config
+    .server
+    .allowed_keys
+    .iter()
+    .any(|entry| entry.name() == Some(name))
+  {
+    return Err(Box::new(AdeployError::Config(format!(
+      "A key named '{}' is already authorized",
+      name
+    ))));
+  }
+
+  let mut document = content.parse::<DocumentMut>().map_err(|e| {
+    Box::new(AdeployError::Config(format!(
+      "Failed to parse TOML config: {}",
+      e
+    )))
+  })?;
+  let allowed_keys = document["server"]
+    .as_table_like_mut()
+    .ok_or_else(|| Box::new(AdeployError::Config("Missing [server] section".to_string())))?
+    .entry("allowed_keys")
+    .or_insert(Item::Value(Value::Array(Array::new())))
+    .as_array_mut()
+    .ok_or_else(|| {
+      Box::new(AdeployError::Config(
+        "server.allowed_keys must be an array".to_string(),
+      ))
+    })?;
+
+  let mut entry = InlineTable::new();
+  entry.insert("name", name.into());
+  entry.insert("key", encoded_key.as_str().into());
+  if !packages.is_empty() {
+    entry.insert("packages", Value::Array(packages.iter().collect()));
+  }
+  // A comment after the last entry lives in the array's trailing decor; keep it on that entry
+  let trailing_comment = allowed_keys
+    .trailing()
+    .as_str()
+    .unwrap_or_default()
+    .trim_end()
+    .to_string();
+  let mut entry = Value::InlineTable(entry);
+  entry
+    .decor_mut()
+    .set_prefix(format!("{}\n  ", trailing_comment));
+  allowed_keys.push_formatted(entry);
+  allowed_keys.set_trailing("\n");
+  allowed_keys.set_trailing_comma(true);
+
+  // Never write a file the server would fail to load
+  let updated = document.to_string();
+  parse_server_config(&updated)?;
+  write_atomically(config_path, &updated)?;
+
+  info!(
+    "Authorized key '{}' ({}) in {}",
+    name,
+    Auth::fingerprint(&encoded_key)?,
+    config_path.display()
+  );
+  Ok(())
+}
+
+fn parse_server_config(content: &str) -> Result<ServerConfig> {
+  toml::from_str(content).map_err(|e| {
+    Box::new(AdeployError::Config(format!(
+      "Failed to parse TOML config: {}",
+      e
+    )))
+  })
+}
+
+/// Replace a file through a sibling temporary file so readers never see partial content
+fn write_atomically(path: &Path, content: &str) -> Result<()> {
+  let temp_path = path.with_extension("toml.tmp");
+  fs::write(&temp_path, content).map_err(|e| {
+    Box::new(AdeployError::FileSystem(format!(
+      "Failed to write {}: {}",
+      temp_path.display(),
+      e
+    )))
+  })?;
+
+  if let Ok(metadata) = fs::metadata(path) {
+    let _ = fs::set_permissions(&temp_path, metadata.permissions());
+  }
+
+  fs::rename(&temp_path, path).map_err(|e| {
+    Box::new(AdeployError::FileSystem(format!(
+      "Failed to replace {}: {}",
+      path.display(),
+      e
+    )))
+  })
+}
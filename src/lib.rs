@@ -1,12 +1,26 @@
 //! ADeploy - Universal deployment tool library
 
+#[cfg(unix)]
+pub mod account;
 pub mod auth;
+pub mod backup;
 pub mod client;
 pub mod config;
 pub mod deploy;
+pub mod deploy_lock;
 pub mod deploy_log;
 pub mod error;
+pub mod health;
+pub mod hooks;
+pub mod keys;
+pub mod passphrase;
+pub mod permissions;
+pub mod replay;
+pub mod sandbox;
 pub mod server;
+pub mod signer;
+pub mod staging;
+pub mod tls;
 
 // Include the generated gRPC code
 pub mod adeploy {
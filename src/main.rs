@@ -1,16 +1,30 @@
-use std::{path::PathBuf, process, sync::Arc};
+use std::{fs, path::PathBuf, process, sync::Arc, time::Duration};
 
 use clap::{Args, Parser, Subcommand};
 use log2::*;
 use tokio::runtime::Builder as RuntimeBuilder;
 
+#[cfg(unix)]
+mod account;
 mod auth;
+mod backup;
 mod client;
 mod config;
 mod deploy;
+mod deploy_lock;
 mod deploy_log;
 mod error;
+mod health;
+mod hooks;
+mod keys;
+mod passphrase;
+mod permissions;
+mod replay;
+mod sandbox;
 mod server;
+mod signer;
+mod staging;
+mod tls;
 use crate::error::{AdeployError, Result};
 
 // Generated gRPC bindings
@@ -43,6 +57,11 @@ enum Commands {
     #[command(subcommand)]
     action: Option<ServerAction>,
   },
+  /// Manage the client signing key
+  Key {
+    #[command(subcommand)]
+    action: KeyAction,
+  },
   /// Deploy to a server (explicit client mode)
   Client {
     /// Server host
@@ -51,6 +70,16 @@ enum Commands {
     #[arg(value_name = "PACKAGE", num_args = 1..)]
     packages: Vec<String>,
   },
+  /// Ask a server to restore a package from one of its backups
+  Rollback {
+    /// Server host
+    host: String,
+    /// Package to restore
+    package: String,
+    /// Backup to restore (the most recent when omitted)
+    #[arg(long)]
+    backup: Option<String>,
+  },
 }
 
 #[derive(Subcommand)]
@@ -67,6 +96,76 @@ enum ServerAction {
   Stop(ServiceTargetArgs),
   /// Show the current service status
   Status(ServiceTargetArgs),
+  /// Allow a client public key to deploy
+  Authorize(AuthorizeArgs),
+  /// Delete backups beyond each package's retention limits
+  PruneBackups(PruneBackupsArgs),
+  /// Restore a package from one of its backups, running its hooks
+  Restore(RestoreArgs),
+}
+
+#[derive(Subcommand)]
+enum KeyAction {
+  /// Generate the client key pair
+  Generate(KeyGenerateArgs),
+  /// Print the public key as an OpenSSH line
+  Show,
+  /// Print the SHA256 fingerprint of the public key
+  Fingerprint,
+  /// Replace the key pair, keeping the old one as a retired copy
+  Rotate(KeyEncryptArgs),
+}
+
+#[derive(Args, Clone)]
+struct KeyGenerateArgs {
+  /// Overwrite an existing key pair
+  #[arg(long)]
+  force: bool,
+  #[command(flatten)]
+  encrypt: KeyEncryptArgs,
+}
+
+#[derive(Args, Clone)]
+struct KeyEncryptArgs {
+  /// Encrypt the private key with a passphrase (prompted, or from ADEPLOY_KEY_PASSPHRASE)
+  #[arg(long)]
+  encrypt: bool,
+}
+
+#[derive(Args, Clone)]
+struct AuthorizeArgs {
+  /// Public key (`ssh-ed25519 AAAA...` line or raw base64) or a path to a .pub file
+  #[arg(value_name = "PUBKEY")]
+  public_key: String,
+  /// Name recorded for the key in logs
+  #[arg(long)]
+  name: String,
+  /// Package names or glob patterns the key may deploy (all packages when omitted)
+  #[arg(long, value_delimiter = ',', num_args = 1..)]
+  packages: Vec<String>,
+}
+
+#[derive(Args, Clone)]
+struct PruneBackupsArgs {
+  /// Packages to prune (all configured packages when omitted)
+  #[arg(value_name = "PACKAGE")]
+  packages: Vec<String>,
+  /// Only list the backups that would be deleted
+  #[arg(long)]
+  dry_run: bool,
+}
+
+#[derive(Args, Clone)]
+struct RestoreArgs {
+  /// Package to restore
+  #[arg(value_name = "PACKAGE")]
+  package: String,
+  /// Backup to restore, as listed in the package's backup directory
+  #[arg(long, conflicts_with = "latest")]
+  backup: Option<String>,
+  /// Restore the most recent backup (the default)
+  #[arg(long)]
+  latest: bool,
 }
 
 #[derive(Args, Clone, Default)]
@@ -139,10 +238,24 @@ fn run_cli(cli: Cli) -> Result<()> {
       let action = action.unwrap_or(ServerAction::Run(ServiceRunArgs::default()));
       handle_server(action)?;
     }
+    Some(Commands::Key { action }) => handle_key(action)?,
     Some(Commands::Client { host, packages }) => {
       let runtime = build_runtime()?;
       runtime.block_on(run_client_mode(&host, packages));
     }
+    Some(Commands::Rollback {
+      host,
+      package,
+      backup,
+    }) => {
+      let provider = config::ConfigProviderImpl;
+      build_runtime()?.block_on(client::restore(
+        &host,
+        &package,
+        backup.as_deref(),
+        &provider,
+      ))?;
+    }
     None => {
       let host = default_host
         .unwrap_or_else(|| usage_and_exit("Host is required when not using subcommands"));
@@ -167,11 +280,111 @@ async fn run_client_mode(host: &str, packages: Vec<String>) {
   }
 }
 
+fn prune_backups(args: PruneBackupsArgs) -> Result<()> {
+  let provider: &dyn config::ConfigProvider = &config::ConfigProviderImpl;
+  let config_path = provider.get_config_path(config::ConfigType::Server)?;
+  let config = provider.load_server_config(&config_path)?;
+
+  let mut package_names = if args.packages.is_empty() {
+    config.packages.keys().cloned().collect()
+  } else {
+    args.packages
+  };
+  package_names.sort();
+
+  for package_name in package_names {
+    let package_config = config.packages.get(&package_name).ok_or_else(|| {
+      Box::new(AdeployError::Config(format!(
+        "Package '{}' not configured",
+        package_name
+      )))
+    })?;
+    if !backup::RetentionPolicy::from_config(package_config).is_bounded() {
+      info!("{}: no backup retention limits configured", package_name);
+      continue;
+    }
+
+    let removed = backup::prune_package_backups(package_config, &package_name, args.dry_run)?;
+    let freed: u64 = removed.iter().map(|backup| backup.size).sum();
+    // Actual removals are already logged as they happen
+    if args.dry_run {
+      for backup in &removed {
+        info!(
+          "{}: would remove {} ({} bytes)",
+          package_name, backup.name, backup.size
+        );
+      }
+    }
+    info!(
+      "{}: {} {} backups, {} bytes",
+      package_name,
+      if args.dry_run {
+        "would remove"
+      } else {
+        "removed"
+      },
+      removed.len(),
+      freed
+    );
+  }
+
+  Ok(())
+}
+
+fn restore_package(args: RestoreArgs) -> Result<()> {
+  let provider: &dyn config::ConfigProvider = &config::ConfigProviderImpl;
+  let config_path = provider.get_config_path(config::ConfigType::Server)?;
+  let config = provider.load_server_config(&config_path)?;
+  let package_config = config.packages.get(&args.package).ok_or_else(|| {
+    Box::new(AdeployError::Config(format!(
+      "Package '{}' not configured",
+      args.package
+    )))
+  })?;
+
+  build_runtime()?.block_on(async {
+    // Show the restore's own log lines as they are produced
+    let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+    let printer = tokio::spawn(async move {
+      while let Some(progress) = progress_rx.recv().await {
+        match progress {
+          deploy_log::DeployProgress::Stage(stage) => info!("Stage: {:?}", stage),
+          deploy_log::DeployProgress::Log(entry) => match entry.level {
+            deploy_log::LogLevel::Info => info!("{}", entry.message),
+            deploy_log::LogLevel::Warn => warn!("{}", entry.message),
+            deploy_log::LogLevel::Error => error!("{}", entry.message),
+          },
+        }
+      }
+    });
+
+    let mut reporter = deploy_log::DeployReporter::with_listener(progress_tx);
+    let result = server::restore_package(
+      package_config,
+      &args.package,
+      args.backup.as_deref(),
+      Duration::from_secs(config.server.deploy_lock_wait_secs),
+      &mut reporter,
+    )
+    .await;
+    drop(reporter);
+    let _ = printer.await;
+
+    let deploy_id = result?;
+    info!("Restored {} (ID: {})", args.package, deploy_id);
+    Ok(())
+  })
+}
+
 fn usage_and_exit(message: &str) -> ! {
   error!("{message}");
   error!("Usage: adeploy <HOST> <PACKAGE> [PACKAGE...]");
   error!("   or: adeploy client <HOST> <PACKAGE> [PACKAGE...]");
-  error!("   or: adeploy server [run|install|start|stop|status|uninstall]");
+  error!("   or: adeploy rollback <HOST> <PACKAGE> [--backup <NAME>]");
+  error!(
+    "   or: adeploy server [run|install|start|stop|status|uninstall|authorize|prune-backups|restore]"
+  );
+  error!("   or: adeploy key [generate|show|fingerprint|rotate]");
   std::process::exit(1);
 }
 
@@ -186,6 +399,48 @@ fn build_runtime() -> Result<tokio::runtime::Runtime> {
     })
 }
 
+fn handle_key(action: KeyAction) -> Result<()> {
+  let paths = config::default_key_paths()?;
+  let new_key_passphrase = |args: &KeyEncryptArgs| -> Result<Option<String>> {
+    if args.encrypt {
+      passphrase::obtain_new().map(Some)
+    } else {
+      passphrase::from_environment()
+    }
+  };
+
+  match action {
+    KeyAction::Generate(args) => {
+      let passphrase = new_key_passphrase(&args.encrypt)?;
+      keys::generate_key(&paths, passphrase.as_deref(), args.force)?;
+      info!(
+        "Generated key pair in {}",
+        paths
+          .private_key
+          .parent()
+          .unwrap_or(&paths.private_key)
+          .display()
+      );
+      println!("{}", keys::public_key_line(&paths)?);
+    }
+    KeyAction::Show => println!("{}", keys::public_key_line(&paths)?),
+    KeyAction::Fingerprint => println!("{}", keys::key_fingerprint(&paths)?),
+    KeyAction::Rotate(args) => {
+      let passphrase = new_key_passphrase(&args)?;
+      let rotated = keys::rotate_key(&paths, passphrase.as_deref())?;
+      info!(
+        "Retired key {} to {}",
+        rotated.previous_fingerprint,
+        rotated.retired.private_key.display()
+      );
+      info!("Authorize the new key on each server, then revoke the retired fingerprint");
+      println!("{}", keys::public_key_line(&paths)?);
+    }
+  }
+
+  Ok(())
+}
+
 fn handle_server(action: ServerAction) -> Result<()> {
   match action {
     ServerAction::Run(opts) => {
@@ -277,6 +532,19 @@ fn handle_server(action: ServerAction) -> Result<()> {
         server::format_service_status(&status)
       );
     }
+    ServerAction::Authorize(args) => {
+      let provider: &dyn config::ConfigProvider = &config::ConfigProviderImpl;
+      let config_path = provider.get_config_path(config::ConfigType::Server)?;
+
+      // Accept a path to a .pub file as well as the key itself
+      let public_key = match fs::read_to_string(&args.public_key) {
+        Ok(content) => content,
+        Err(_) => args.public_key.clone(),
+      };
+      keys::authorize_key(&config_path, &public_key, &args.name, &args.packages)?;
+    }
+    ServerAction::PruneBackups(args) => prune_backups(args)?,
+    ServerAction::Restore(args) => restore_package(args)?,
   }
 
   Ok(())
@@ -0,0 +1,138 @@
+//! Passphrase sources for encrypted private keys
+
+use std::{
+  env,
+  io::{self, IsTerminal},
+  sync::OnceLock,
+};
+
+use crate::error::{AdeployError, Result};
+
+/// Environment variable holding the private key passphrase
+pub const PASSPHRASE_ENV: &str = "ADEPLOY_KEY_PASSPHRASE";
+/// Environment variable naming a file descriptor to read the passphrase from
+pub const PASSPHRASE_FD_ENV: &str = "ADEPLOY_KEY_PASSPHRASE_FD";
+
+/// Passphrase supplied non-interactively through the environment or a file descriptor.
+/// The descriptor can only be drained once, so its passphrase is cached for the process.
+pub fn from_environment() -> Result<Option<String>> {
+  static FROM_FD: OnceLock<Option<String>> = OnceLock::new();
+
+  if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+    return Ok(Some(passphrase));
+  }
+  if let Some(passphrase) = FROM_FD.get() {
+    return Ok(passphrase.clone());
+  }
+
+  let passphrase = match env::var(PASSPHRASE_FD_ENV) {
+    Ok(fd) => Some(read_from_fd(&fd)?),
+    Err(_) => None,
+  };
+  Ok(FROM_FD.get_or_init(|| passphrase).clone())
+}
+
+/// Resolve a passphrase from the environment, falling back to a terminal prompt
+pub fn obtain(prompt: &str) -> Result<String> {
+  if let Some(passphrase) = from_environment()? {
+    return Ok(passphrase);
+  }
+
+  if !io::stdin().is_terminal() {
+    return Err(Box::new(AdeployError::Auth(format!(
+      "Private key is encrypted; set {} or {} when running non-interactively",
+      PASSPHRASE_ENV, PASSPHRASE_FD_ENV
+    ))));
+  }
+
+  rpassword::prompt_password(prompt).map_err(|e| {
+    Box::new(AdeployError::Auth(format!(
+      "Failed to read passphrase: {}",
+      e
+    )))
+  })
+}
+
+/// Resolve a passphrase for a new key, asking twice when prompting
+pub fn obtain_new() -> Result<String> {
+  let passphrase = match from_environment()? {
+    Some(passphrase) => passphrase,
+    None => {
+      let passphrase = obtain("New key passphrase: ")?;
+      let confirmation = obtain("Repeat passphrase: ")?;
+      if passphrase != confirmation {
+        return Err(Box::new(AdeployError::Auth(
+          "Passphrases do not match".to_string(),
+        )));
+      }
+      passphrase
+    }
+  };
+  // An empty passphrase would leave the "encrypted" key unprotected, wherever it came from
+  if passphrase.is_empty() {
+    return Err(Box::new(AdeployError::Auth(
+      "Passphrase must not be empty".to_string(),
+    )));
+  }
+
+  Ok(passphrase)
+}
+
+/// Read a single line from an inherited file descriptor, leaving the descriptor open
+#[cfg(unix)]
+fn read_from_fd(fd: &str) -> Result<String> {
+  use std::{fs::File, io::Read, mem::ManuallyDrop, os::unix::io::FromRawFd};
+
+  /// Longest passphrase accepted from a file descriptor
+  const MAX_PASSPHRASE_LEN: usize = 4096;
+
+  let fd: i32 = fd.trim().parse().map_err(|_| {
+    Box::new(AdeployError::Config(format!(
+      "{} must be a file descriptor number, got '{}'",
+      PASSPHRASE_FD_ENV, fd
+    )))
+  })?;
+
+  // SAFETY: the descriptor is handed to us by the parent process for this purpose. It is
+  // wrapped in ManuallyDrop because we do not own it and must never close it.
+  let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+  let mut line = Vec::new();
+  let mut byte = [0u8; 1];
+  // Read byte by byte so nothing past the first line is consumed
+  loop {
+    let read = file.read(&mut byte).map_err(|e| {
+      Box::new(AdeployError::Auth(format!(
+        "Failed to read passphrase from fd {}: {}",
+        fd, e
+      )))
+    })?;
+    if read == 0 || byte[0] == b'\n' {
+      break;
+    }
+    if line.len() == MAX_PASSPHRASE_LEN {
+      return Err(Box::new(AdeployError::Auth(format!(
+        "Passphrase from fd {} is longer than {} bytes",
+        fd, MAX_PASSPHRASE_LEN
+      ))));
+    }
+    line.push(byte[0]);
+  }
+  if line.last() == Some(&b'\r') {
+    line.pop();
+  }
+
+  String::from_utf8(line).map_err(|_| {
+    Box::new(AdeployError::Auth(format!(
+      "Passphrase from fd {} is not valid UTF-8",
+      fd
+    )))
+  })
+}
+
+#[cfg(not(unix))]
+fn read_from_fd(_fd: &str) -> Result<String> {
+  Err(Box::new(AdeployError::Config(format!(
+    "{} is only supported on Unix",
+    PASSPHRASE_FD_ENV
+  ))))
+}
@@ -0,0 +1,162 @@
+//! Ownership and modes given to files unpacked from a deployment archive
+
+use std::{fs, io, path::Path};
+
+use glob::Pattern;
+#[cfg(not(unix))]
+use log2::*;
+
+#[cfg(unix)]
+use crate::account;
+use crate::{
+  config::ServerPackageConfig,
+  error::{AdeployError, Result},
+  staging::PATH_MATCH_OPTIONS,
+};
+
+/// A package's `owner`, `group`, `file_mode`, `dir_mode` and `modes`, resolved for extraction
+pub struct PermissionMap {
+  uid: Option<u32>,
+  gid: Option<u32>,
+  file_mode: Option<u32>,
+  dir_mode: Option<u32>,
+  /// Per-glob modes, longest glob first
+  overrides: Vec<(Pattern, u32)>,
+}
+
+impl PermissionMap {
+  /// Resolve the package's settings, or `None` when extracted files keep their archive modes
+  pub fn from_config(config: &ServerPackageConfig) -> Result<Option<Self>> {
+    let mut globs = config.modes.iter().collect::<Vec<_>>();
+    globs.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    let overrides = globs
+      .into_iter()
+      .map(|(glob, mode)| {
+        Pattern::new(glob.trim_end_matches('/'))
+          .map(|pattern| (pattern, *mode))
+          .map_err(|e| {
+            Box::new(AdeployError::Config(format!(
+              "Invalid modes pattern '{}': {}",
+              glob, e
+            )))
+          })
+      })
+      .collect::<Result<Vec<_>>>()?;
+    let (uid, gid) = resolve_owner(config)?;
+
+    if uid.is_none()
+      && gid.is_none()
+      && config.file_mode.is_none()
+      && config.dir_mode.is_none()
+      && overrides.is_empty()
+    {
+      return Ok(None);
+    }
+    Ok(Some(Self {
+      uid,
+      gid,
+      file_mode: config.file_mode,
+      dir_mode: config.dir_mode,
+      overrides,
+    }))
+  }
+
+  /// Apply to every entry below `root`, each directory after its contents; returns the
+  /// number of entries
+  pub fn apply(&self, root: &Path) -> io::Result<usize> {
+    self.apply_dir(root, "")
+  }
+
+  fn apply_dir(&self, dir: &Path, relative: &str) -> io::Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+      let entry = entry?;
+      let name = entry.file_name();
+      let relative = if relative.is_empty() {
+        name.to_string_lossy().into_owned()
+      } else {
+        format!("{}/{}", relative, name.to_string_lossy())
+      };
+      let path = entry.path();
+      let file_type = entry.file_type()?;
+      if file_type.is_dir() {
+        count += self.apply_dir(&path, &relative)?;
+      }
+      self
+        .apply_entry(&path, &relative, file_type)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+      count += 1;
+    }
+    Ok(count)
+  }
+
+  /// Mode configured for an entry, if any
+  fn mode_for(&self, relative: &str, is_dir: bool) -> Option<u32> {
+    self
+      .overrides
+      .iter()
+      .find(|(pattern, _)| pattern.matches_with(relative, PATH_MATCH_OPTIONS))
+      .map(|(_, mode)| *mode)
+      .or(if is_dir {
+        self.dir_mode
+      } else {
+        self.file_mode
+      })
+  }
+
+  #[cfg(unix)]
+  fn apply_entry(&self, path: &Path, relative: &str, file_type: fs::FileType) -> io::Result<()> {
+    use std::os::unix::fs::{lchown, PermissionsExt};
+
+    // Changing the owner clears setuid and setgid, so the mode is read first and written after
+    let current = fs::symlink_metadata(path)?.permissions().mode() & 0o7777;
+    let chown = self.uid.is_some() || self.gid.is_some();
+    if chown {
+      lchown(path, self.uid, self.gid)?;
+    }
+    // Symlink modes are meaningless, and chmod would follow the link
+    if file_type.is_symlink() {
+      return Ok(());
+    }
+
+    let mode = self
+      .mode_for(relative, file_type.is_dir())
+      .unwrap_or(current);
+    if mode != current || chown {
+      fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+  }
+
+  #[cfg(not(unix))]
+  fn apply_entry(&self, _path: &Path, _relative: &str, _file_type: fs::FileType) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+#[cfg(unix)]
+fn resolve_owner(config: &ServerPackageConfig) -> Result<(Option<u32>, Option<u32>)> {
+  let owner = config
+    .owner
+    .as_deref()
+    .map(|owner| account::lookup_user(owner, "owner"))
+    .transpose()?;
+  let gid = match &config.group {
+    Some(group) => Some(account::lookup_group(group, "group")?),
+    None => owner.as_ref().and_then(|owner| owner.gid),
+  };
+  Ok((owner.map(|owner| owner.uid), gid))
+}
+
+#[cfg(not(unix))]
+fn resolve_owner(config: &ServerPackageConfig) -> Result<(Option<u32>, Option<u32>)> {
+  if config.owner.is_some()
+    || config.group.is_some()
+    || config.file_mode.is_some()
+    || config.dir_mode.is_some()
+    || !config.modes.is_empty()
+  {
+    warn!("owner, group and file modes only apply on Unix; ignoring them");
+  }
+  Ok((None, None))
+}
@@ -0,0 +1,134 @@
+//! Replay protection for signed deploy requests.
+
+use std::{
+  collections::HashMap,
+  fs,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+};
+
+use log2::*;
+
+use crate::error::{AdeployError, Result};
+
+/// Why a request was rejected by the replay guard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayRejection {
+  /// Timestamp is further from the server clock than the allowed window
+  OutsideWindow { skew_secs: i64 },
+  /// Nonce was already used within the window
+  NonceReused,
+}
+
+/// Remembers request nonces for the clock-skew window, persisting them across restarts.
+pub struct ReplayGuard {
+  path: PathBuf,
+  seen: HashMap<String, i64>,
+  /// Bumped for every accepted nonce, so an older snapshot never overwrites a newer one
+  generation: u64,
+  /// Generation last written to disk
+  persisted: Arc<Mutex<u64>>,
+}
+
+/// Copy of the seen nonces, written to disk without holding the guard
+pub struct NonceSnapshot {
+  path: PathBuf,
+  seen: HashMap<String, i64>,
+  generation: u64,
+  persisted: Arc<Mutex<u64>>,
+}
+
+impl ReplayGuard {
+  /// Load previously seen nonces; a missing or unreadable cache starts empty
+  pub fn load(path: PathBuf) -> Self {
+    let seen = match fs::read_to_string(&path) {
+      Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+        warn!("Ignoring corrupt nonce cache {}: {}", path.display(), e);
+        HashMap::new()
+      }),
+      Err(_) => HashMap::new(),
+    };
+
+    Self {
+      path,
+      seen,
+      generation: 0,
+      persisted: Arc::new(Mutex::new(0)),
+    }
+  }
+
+  /// Accept a request timestamp and nonce once, rejecting stale or replayed requests.
+  /// Accepted nonces are kept in memory; persist a `snapshot` to remember them across restarts.
+  pub fn check(
+    &mut self,
+    nonce: &str,
+    timestamp: i64,
+    now: i64,
+    window_secs: u64,
+  ) -> std::result::Result<(), ReplayRejection> {
+    let window = i64::try_from(window_secs).unwrap_or(i64::MAX);
+    let skew_secs = now.saturating_sub(timestamp);
+    if skew_secs.saturating_abs() > window {
+      return Err(ReplayRejection::OutsideWindow { skew_secs });
+    }
+
+    // Nonces only need to be remembered while their timestamp is still acceptable
+    self
+      .seen
+      .retain(|_, seen_at| seen_at.saturating_add(window) >= now);
+
+    if self.seen.contains_key(nonce) {
+      return Err(ReplayRejection::NonceReused);
+    }
+
+    self.seen.insert(nonce.to_string(), timestamp);
+    self.generation += 1;
+    Ok(())
+  }
+
+  /// Capture the seen nonces for writing to disk once the guard is released
+  pub fn snapshot(&self) -> NonceSnapshot {
+    NonceSnapshot {
+      path: self.path.clone(),
+      seen: self.seen.clone(),
+      generation: self.generation,
+      persisted: self.persisted.clone(),
+    }
+  }
+}
+
+impl NonceSnapshot {
+  /// Write the nonce cache, unless a newer snapshot has already been written. Blocks, so
+  /// async callers should run it on the blocking pool.
+  pub fn persist(self) -> Result<()> {
+    let mut persisted = self.persisted.lock().unwrap();
+    if *persisted >= self.generation {
+      return Ok(());
+    }
+
+    if let Some(parent) = self.path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+
+    let content =
+      serde_json::to_string(&self.seen).map_err(|e| Box::new(AdeployError::Serde(e)))?;
+    let tmp_path = temp_path(&self.path);
+    fs::write(&tmp_path, content)
+      .and_then(|_| fs::rename(&tmp_path, &self.path))
+      .map_err(|e| {
+        Box::new(AdeployError::FileSystem(format!(
+          "Failed to persist nonce cache {}: {}",
+          self.path.display(),
+          e
+        )))
+      })?;
+    *persisted = self.generation;
+    Ok(())
+  }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+  let mut name = path.file_name().unwrap_or_default().to_os_string();
+  name.push(".tmp");
+  path.with_file_name(name)
+}
@@ -0,0 +1,123 @@
+//! Restrictions applied to hook scripts: the account they run as, resource limits and the
+//! environment they inherit
+
+use glob::Pattern;
+use log2::*;
+use tokio::process::Command;
+
+#[cfg(unix)]
+use crate::account;
+use crate::{
+  config::ServerPackageConfig,
+  error::{AdeployError, Result},
+};
+
+/// Apply the package's `env_allowlist`, `run_as_user`, `run_as_group` and `hook_limits` to a
+/// hook command. Variables set on `command` afterwards are passed through unfiltered.
+pub fn restrict(command: &mut Command, config: &ServerPackageConfig) -> Result<()> {
+  if let Some(allowlist) = &config.env_allowlist {
+    let patterns = allowlist
+      .iter()
+      .map(|pattern| {
+        Pattern::new(pattern).map_err(|e| {
+          Box::new(AdeployError::Config(format!(
+            "Invalid env_allowlist pattern '{}': {}",
+            pattern, e
+          )))
+        })
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    command.env_clear();
+    command.envs(std::env::vars_os().filter(|(name, _)| {
+      let name = name.to_string_lossy();
+      patterns.iter().any(|pattern| pattern.matches(&name))
+    }));
+  }
+
+  restrict_process(command, config)
+}
+
+#[cfg(unix)]
+fn restrict_process(command: &mut Command, config: &ServerPackageConfig) -> Result<()> {
+  let account = config
+    .run_as_user
+    .as_deref()
+    .map(|user| account::lookup_user(user, "run_as_user"))
+    .transpose()?;
+  let gid = match (&config.run_as_group, &account) {
+    (Some(group), _) => Some(account::lookup_group(group, "run_as_group")?),
+    (None, Some(account)) => Some(account.gid.ok_or_else(|| {
+      Box::new(AdeployError::Config(format!(
+        "run_as_group is required because uid {} has no passwd entry",
+        account.uid
+      )))
+    })?),
+    (None, None) => None,
+  };
+
+  if let Some(account) = &account {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let euid = unsafe { libc::geteuid() };
+    if euid != 0 && euid != account.uid {
+      return Err(Box::new(AdeployError::Config(format!(
+        "run_as_user '{}' requires the server to run as root",
+        config.run_as_user.as_deref().unwrap_or_default()
+      ))));
+    }
+    info!("Running hook as uid {}", account.uid);
+    command.uid(account.uid);
+    if let Some(home) = &account.home {
+      command.env("HOME", home);
+    }
+    if let Some(name) = &account.name {
+      command.env("USER", name).env("LOGNAME", name);
+    }
+  }
+  if let Some(gid) = gid {
+    command.gid(gid);
+  }
+
+  let limits = &config.hook_limits;
+  let limits = [
+    (libc::RLIMIT_CPU, limits.cpu_time),
+    (libc::RLIMIT_AS, limits.address_space),
+    (libc::RLIMIT_NOFILE, limits.open_files),
+  ]
+  .into_iter()
+  .filter_map(|(resource, limit)| limit.map(|limit| (resource, limit as libc::rlim_t)))
+  .collect::<Vec<_>>();
+  if !limits.is_empty() {
+    // SAFETY: the closure runs in the forked child and only calls setrlimit, which is
+    // async-signal-safe, and does not allocate
+    unsafe {
+      command.pre_exec(move || {
+        for (resource, limit) in &limits {
+          let rlimit = libc::rlimit {
+            rlim_cur: *limit,
+            rlim_max: *limit,
+          };
+          if libc::setrlimit(*resource, &rlimit) != 0 {
+            return Err(std::io::Error::last_os_error());
+          }
+        }
+        Ok(())
+      });
+    }
+  }
+  Ok(())
+}
+
+#[cfg(not(unix))]
+fn restrict_process(_command: &mut Command, config: &ServerPackageConfig) -> Result<()> {
+  let limits = &config.hook_limits;
+  if config.run_as_user.is_some()
+    || config.run_as_group.is_some()
+    || limits.cpu_time.is_some()
+    || limits.address_space.is_some()
+    || limits.open_files.is_some()
+  {
+    warn!("run_as_user, run_as_group and hook_limits only apply on Unix; ignoring them");
+  }
+  Ok(())
+}
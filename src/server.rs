@@ -1,54 +1,451 @@
 use std::{
-  convert::TryInto, env, ffi::OsString, future::Future, path::PathBuf, sync::Arc, time::Duration,
+  collections::HashMap,
+  convert::TryInto,
+  env,
+  ffi::OsString,
+  future::Future,
+  path::PathBuf,
+  sync::Arc,
+  time::{Duration, Instant},
 };
 
 use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
 use log2::*;
 use service_manager::{
   ServiceInstallCtx, ServiceLabel, ServiceLevel, ServiceManager, ServiceStartCtx, ServiceStatus,
   ServiceStatusCtx, ServiceStopCtx, ServiceUninstallCtx,
 };
-use tokio::sync::{watch, RwLock};
-use tonic::{transport::Server, Request, Response, Status};
+use tokio::{
+  sync::{mpsc, watch, Mutex, RwLock},
+  task::spawn_blocking,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{metadata::MetadataValue, transport::Server, Request, Response, Status, Streaming};
 
 use crate::{
   adeploy::{
+    deploy_chunk, deploy_event,
     deploy_service_server::{DeployService, DeployServiceServer},
-    DeployRequest, DeployResponse,
+    DeployChunk, DeployEvent, DeployHeader, DeployRequest, DeployResponse, RestoreRequest,
+  },
+  auth::{Auth, AuthRejection, SignedEnvelope, SignedRestore, AUTH_REJECTION_METADATA},
+  backup::{self, BackupEntry},
+  config::{
+    ConfigProvider, ConfigType, DeployLockScope, FailurePolicy, ServerConfig, ServerPackageConfig,
+    ServerSettings,
   },
-  auth::Auth,
-  config::{ConfigProvider, ConfigType, ServerConfig},
-  deploy::DeployManager,
-  deploy_log::{DeployLogEntry, LogLevel},
+  deploy::{ArchiveSpool, DeployManager, SpooledArchive},
+  deploy_lock::{DeployLockGuard, DeployLocks, LockBusy, PathLock},
+  deploy_log::{DeployLogEntry, DeployProgress, DeployReporter, DeployStage, LogLevel},
   error::{AdeployError, Result},
+  health,
+  hooks::{HookFailure, HookStage},
+  replay::{ReplayGuard, ReplayRejection},
+  tls,
 };
 
 const DEFAULT_MAX_MESSAGE_SIZE: u64 = 100 * 1024 * 1024;
 
+/// Data a client signature is checked against.
+enum SignedPayload<'a> {
+  /// Canonical envelope binding the archive hash to the request fields
+  Envelope(SignedEnvelope<'a>),
+  /// Archive-only signature from older clients
+  Legacy(&'a [u8]),
+  /// Request to restore a server-side backup
+  Restore(SignedRestore<'a>),
+}
+
+/// Where a deployment's files come from
+#[derive(Clone, Copy)]
+enum DeploySource<'a> {
+  /// Archive uploaded by the client
+  Archive(&'a SpooledArchive),
+  /// Backup kept on the server
+  Backup(&'a BackupEntry),
+}
+
+/// Key and package settings resolved for an authorized request
+struct Authorization {
+  package_config: ServerPackageConfig,
+  max_file_size: u64,
+  key: KeyIdentity,
+}
+
+/// How a client key is referred to in logs
+struct KeyIdentity {
+  name: String,
+  fingerprint: String,
+}
+
 /// ADeploy gRPC service implementation
 #[derive(Clone)]
 pub struct AdeployService {
   config: Arc<RwLock<ServerConfig>>,
+  replay_guard: Arc<Mutex<ReplayGuard>>,
+  deploy_locks: Arc<DeployLocks>,
 }
 
 impl AdeployService {
-  pub fn new(config: Arc<RwLock<ServerConfig>>) -> Self {
-    Self { config }
+  pub fn new(config: Arc<RwLock<ServerConfig>>, replay_guard: ReplayGuard) -> Self {
+    Self {
+      config,
+      replay_guard: Arc::new(Mutex::new(replay_guard)),
+      deploy_locks: Arc::new(DeployLocks::new()),
+    }
   }
 }
 
 #[tonic::async_trait]
 impl DeployService for AdeployService {
+  type DeployProgressStream = ReceiverStream<std::result::Result<DeployEvent, Status>>;
+  type RestoreStream = ReceiverStream<std::result::Result<DeployEvent, Status>>;
+
   async fn deploy(
     &self,
     request: Request<DeployRequest>,
   ) -> std::result::Result<Response<DeployResponse>, Status> {
-    let mut req = request.into_inner();
+    let req = request.into_inner();
 
     info!("Received deploy request for {}", req.package_name);
 
+    let signed = if req.nonce.is_empty() {
+      SignedPayload::Legacy(&req.file_data)
+    } else {
+      SignedPayload::Envelope(SignedEnvelope {
+        package_name: &req.package_name,
+        version: &req.version,
+        file_hash: &req.file_hash,
+        metadata: &req.metadata,
+        timestamp: req.timestamp,
+        nonce: &req.nonce,
+      })
+    };
+    let authorization = self
+      .authorize(&req.package_name, &req.public_key, &req.signature, signed)
+      .await?;
+    let max_file_size = authorization.max_file_size;
+
+    if max_file_size > 0 && req.file_data.len() as u64 > max_file_size {
+      error!(
+        "Payload for {} exceeds configured max_file_size {}",
+        req.package_name, max_file_size
+      );
+      return Err(Status::resource_exhausted(format!(
+        "Archive size exceeds configured max_file_size ({} bytes)",
+        max_file_size
+      )));
+    }
+
+    let file_size = req.file_data.len() as u64;
+    let mut spool = ArchiveSpool::new().map_err(|e| Status::internal(e.to_string()))?;
+    spool
+      .write_chunk(&req.file_data)
+      .await
+      .map_err(|e| Status::internal(e.to_string()))?;
+    drop(req.file_data);
+
+    let reporter = DeployReporter::new();
+    let archive = match spool.finish(&req.file_hash, file_size).await {
+      Ok(archive) => archive,
+      Err(e) => return Ok(Self::failure_response(String::new(), e, reporter)),
+    };
+
+    let deploy_manager = DeployManager::new()
+      .with_version(&req.version)
+      .with_package(&req.package_name)
+      .with_client(&authorization.key.name, req.metadata);
+    let _lock = self
+      .lock_deployment(&req.package_name, &deploy_manager.deploy_id)
+      .await?;
+    Ok(
+      self
+        .run_deployment(
+          &req.package_name,
+          deploy_manager,
+          &authorization,
+          DeploySource::Archive(&archive),
+          reporter,
+        )
+        .await,
+    )
+  }
+
+  async fn deploy_stream(
+    &self,
+    request: Request<Streaming<DeployChunk>>,
+  ) -> std::result::Result<Response<DeployResponse>, Status> {
+    let (header, authorization, spool) = self.receive_upload(request.into_inner()).await?;
+
+    let reporter = DeployReporter::new();
+    let archive = match spool.finish(&header.file_hash, header.file_size).await {
+      Ok(archive) => archive,
+      Err(e) => return Ok(Self::failure_response(String::new(), e, reporter)),
+    };
+
+    let deploy_manager = DeployManager::new()
+      .with_version(&header.version)
+      .with_package(&header.package_name)
+      .with_client(&authorization.key.name, header.metadata.clone());
+    let _lock = self
+      .lock_deployment(&header.package_name, &deploy_manager.deploy_id)
+      .await?;
+    Ok(
+      self
+        .run_deployment(
+          &header.package_name,
+          deploy_manager,
+          &authorization,
+          DeploySource::Archive(&archive),
+          reporter,
+        )
+        .await,
+    )
+  }
+
+  async fn deploy_progress(
+    &self,
+    request: Request<Streaming<DeployChunk>>,
+  ) -> std::result::Result<Response<Self::DeployProgressStream>, Status> {
+    let (header, authorization, spool) = self.receive_upload(request.into_inner()).await?;
+
+    // Queue or reject before the progress stream opens, so a busy package is a request error
+    let deploy_manager = DeployManager::new()
+      .with_version(&header.version)
+      .with_package(&header.package_name)
+      .with_client(&authorization.key.name, header.metadata.clone());
+    let lock = self
+      .lock_deployment(&header.package_name, &deploy_manager.deploy_id)
+      .await?;
+
+    let (event_tx, event_rx) = mpsc::channel(64);
+    let service = self.clone();
+    tokio::spawn(async move {
+      service
+        .stream_deployment(header, deploy_manager, authorization, spool, event_tx)
+        .await;
+      drop(lock);
+    });
+
+    Ok(Response::new(ReceiverStream::new(event_rx)))
+  }
+
+  async fn restore(
+    &self,
+    request: Request<RestoreRequest>,
+  ) -> std::result::Result<Response<Self::RestoreStream>, Status> {
+    let req = request.into_inner();
+
+    info!("Received restore request for {}", req.package_name);
+
+    let signed = SignedPayload::Restore(SignedRestore {
+      package_name: &req.package_name,
+      backup: &req.backup,
+      timestamp: req.timestamp,
+      nonce: &req.nonce,
+    });
+    let authorization = self
+      .authorize(&req.package_name, &req.public_key, &req.signature, signed)
+      .await?;
+
+    // An unknown backup is a request error rather than a failed deployment
+    let requested = Some(req.backup.as_str()).filter(|name| !name.is_empty());
+    let backup = backup::backup_directory(&authorization.package_config, &req.package_name)
+      .and_then(|dir| backup::find_backup(&dir, requested))
+      .map_err(|e| {
+        error!("Cannot restore {}: {}", req.package_name, e);
+        Status::not_found(e.to_string())
+      })?;
+
+    let deploy_manager = DeployManager::new()
+      .with_version(&restored_version(&backup))
+      .with_package(&req.package_name)
+      .with_client(&authorization.key.name, HashMap::new());
+    let lock = self
+      .lock_deployment(&req.package_name, &deploy_manager.deploy_id)
+      .await?;
+
+    let (event_tx, event_rx) = mpsc::channel(64);
+    let service = self.clone();
+    tokio::spawn(async move {
+      service
+        .stream_restore(
+          req.package_name,
+          deploy_manager,
+          backup,
+          authorization,
+          event_tx,
+        )
+        .await;
+      drop(lock);
+    });
+
+    Ok(Response::new(ReceiverStream::new(event_rx)))
+  }
+}
+
+impl AdeployService {
+  /// Read the header and archive chunks of an upload, authorizing before any data is spooled
+  async fn receive_upload(
+    &self,
+    mut stream: Streaming<DeployChunk>,
+  ) -> std::result::Result<(DeployHeader, Authorization, ArchiveSpool), Status> {
+    let header = match stream.message().await? {
+      Some(DeployChunk {
+        payload: Some(deploy_chunk::Payload::Header(header)),
+      }) => header,
+      _ => {
+        error!("Chunked upload did not start with a deploy header");
+        return Err(Status::invalid_argument(
+          "First upload message must be a deploy header",
+        ));
+      }
+    };
+
+    info!(
+      "Received chunked deploy request for {}",
+      header.package_name
+    );
+
+    // No client ever sent chunked uploads without the envelope, so legacy signatures are never
+    // accepted here
+    if header.nonce.is_empty() {
+      warn!(
+        "Rejected chunked deploy of {} without a signed request envelope",
+        header.package_name
+      );
+      return Err(unauthenticated(
+        AuthRejection::LegacySignature,
+        "Chunked uploads must sign the request envelope",
+      ));
+    }
+    let signed = SignedPayload::Envelope(SignedEnvelope {
+      package_name: &header.package_name,
+      version: &header.version,
+      file_hash: &header.file_hash,
+      metadata: &header.metadata,
+      timestamp: header.timestamp,
+      nonce: &header.nonce,
+    });
+    let authorization = self
+      .authorize(
+        &header.package_name,
+        &header.public_key,
+        &header.signature,
+        signed,
+      )
+      .await?;
+    let max_file_size = authorization.max_file_size;
+
+    if max_file_size > 0 && header.file_size > max_file_size {
+      error!(
+        "Payload for {} exceeds configured max_file_size {}",
+        header.package_name, max_file_size
+      );
+      return Err(Status::resource_exhausted(format!(
+        "Archive size exceeds configured max_file_size ({} bytes)",
+        max_file_size
+      )));
+    }
+
+    let mut spool = ArchiveSpool::new().map_err(|e| Status::internal(e.to_string()))?;
+    while let Some(chunk) = stream.message().await? {
+      let data = match chunk.payload {
+        Some(deploy_chunk::Payload::Data(data)) => data,
+        Some(deploy_chunk::Payload::Header(_)) => {
+          return Err(Status::invalid_argument(
+            "Deploy header may only be sent once",
+          ));
+        }
+        None => continue,
+      };
+
+      if max_file_size > 0 && spool.size() + data.len() as u64 > max_file_size {
+        error!(
+          "Upload for {} exceeds configured max_file_size {}",
+          header.package_name, max_file_size
+        );
+        return Err(Status::resource_exhausted(format!(
+          "Archive size exceeds configured max_file_size ({} bytes)",
+          max_file_size
+        )));
+      }
+
+      spool
+        .write_chunk(&data)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+    }
+
+    Ok((header, authorization, spool))
+  }
+
+  /// Authorize a request and record the decision with the key name and fingerprint
+  async fn authorize(
+    &self,
+    package_name: &str,
+    public_key: &str,
+    signature: &str,
+    signed: SignedPayload<'_>,
+  ) -> std::result::Result<Authorization, Status> {
+    let key = self.identify_key(public_key).await;
+    let result = self
+      .check_authorization(package_name, public_key, signature, signed)
+      .await;
+
+    match result {
+      Ok((package_config, max_file_size)) => {
+        info!(
+          "Accepted deploy of {} by key '{}' ({})",
+          package_name, key.name, key.fingerprint
+        );
+        Ok(Authorization {
+          package_config,
+          max_file_size,
+          key,
+        })
+      }
+      Err(status) => {
+        warn!(
+          "Rejected deploy of {} by key '{}' ({}): {}",
+          package_name,
+          key.name,
+          key.fingerprint,
+          status.message()
+        );
+        Err(status)
+      }
+    }
+  }
+
+  async fn identify_key(&self, public_key: &str) -> KeyIdentity {
+    let settings = self.config.read().await.server.clone();
+    let lookup_key = public_key.to_string();
+    // find_key may read authorized_keys_file, so it runs off the runtime and outside the lock
+    let name = match spawn_blocking(move || settings.find_key(&lookup_key)).await {
+      Ok(Some(entry)) => entry.name().unwrap_or("unnamed").to_string(),
+      Ok(None) => "unknown".to_string(),
+      Err(e) => {
+        warn!("Key lookup task failed: {}", e);
+        "unknown".to_string()
+      }
+    };
+    let fingerprint = Auth::fingerprint(public_key).unwrap_or_else(|_| "invalid-key".to_string());
+
+    KeyIdentity { name, fingerprint }
+  }
+
+  /// Verify the client key and signature, then resolve the package configuration
+  async fn check_authorization(
+    &self,
+    package_name: &str,
+    public_key: &str,
+    signature: &str,
+    signed: SignedPayload<'_>,
+  ) -> std::result::Result<(ServerPackageConfig, u64), Status> {
     // Verify signature against allowlist
-    let signature = match general_purpose::STANDARD.decode(&req.signature) {
+    let signature = match general_purpose::STANDARD.decode(signature) {
       Ok(sig) => sig,
       Err(e) => {
         error!("Invalid signature format: {}", e);
@@ -59,124 +456,421 @@ impl DeployService for AdeployService {
       }
     };
 
-    let (allowed_keys, package_config, max_file_size) = {
+    let (settings, package_config) = {
       let config = self.config.read().await;
-      let allowed_keys = config.server.allowed_keys.clone();
-      let package_config = config.packages.get(&req.package_name).cloned();
-      (allowed_keys, package_config, config.server.max_file_size)
+      (
+        config.server.clone(),
+        config.packages.get(package_name).cloned(),
+      )
     };
+    let max_file_size = settings.max_file_size;
+    let allow_legacy = settings.allow_legacy_signatures;
+    let replay_window = settings.replay_window_secs;
+
+    // The key files are read from the snapshot, off the runtime and without the config lock
+    let lookup_key = public_key.to_string();
+    let (allowed_key, revoked) =
+      spawn_blocking(move || (settings.find_key(&lookup_key), settings.load_revoked_keys()))
+        .await
+        .map_err(|e| {
+          error!("Key lookup task failed: {}", e);
+          Status::internal("Failed to check the client key")
+        })?;
 
     // Ensure the provided public key is allowed
-    let is_allowed = allowed_keys
-      .iter()
-      .any(|allowed_key| allowed_key == &req.public_key);
+    let Some(allowed_key) = allowed_key else {
+      error!("Public key not allowed for {}", package_name);
+      return Err(unauthenticated(
+        AuthRejection::KeyNotAllowed,
+        "Client public key not allowed",
+      ));
+    };
+
+    // Fail closed when the revocation list cannot be read
+    let revoked = revoked.map_err(|e| {
+      error!("{}", e);
+      Status::unavailable("Revoked keys list is unavailable")
+    })?;
+    let fingerprint = Auth::fingerprint(public_key).unwrap_or_default();
+    let is_revoked = revoked.iter().any(|entry| {
+      *entry == fingerprint || Auth::fingerprint(entry).is_ok_and(|fp| fp == fingerprint)
+    });
+    if is_revoked {
+      error!("Revoked key used for {}", package_name);
+      return Err(unauthenticated(
+        AuthRejection::KeyRevoked,
+        "Client key has been revoked",
+      ));
+    }
 
-    if !is_allowed {
-      error!("Public key not allowed for {}", req.package_name);
-      return Err(Status::unauthenticated("Client public key not allowed"));
+    let now = Utc::now();
+    if let Some(not_before) = allowed_key.not_before() {
+      if now < not_before {
+        error!("Key used for {} before it became valid", package_name);
+        return Err(unauthenticated(
+          AuthRejection::KeyNotYetValid,
+          format!("Client key is not valid before {}", not_before.to_rfc3339()),
+        ));
+      }
+    }
+    if let Some(expires_at) = allowed_key.expires_at() {
+      if now >= expires_at {
+        error!("Expired key used for {}", package_name);
+        return Err(unauthenticated(
+          AuthRejection::KeyExpired,
+          format!("Client key expired at {}", expires_at.to_rfc3339()),
+        ));
+      }
     }
 
-    match Auth::verify_signature(&req.public_key, &req.file_data, &signature) {
+    let (signed_data, replay_fields) = match signed {
+      SignedPayload::Envelope(envelope) => (
+        envelope.canonical_bytes(),
+        Some((envelope.nonce.to_string(), envelope.timestamp)),
+      ),
+      SignedPayload::Restore(request) => (
+        request.canonical_bytes(),
+        Some((request.nonce.to_string(), request.timestamp)),
+      ),
+      SignedPayload::Legacy(data) => {
+        if !allow_legacy {
+          error!(
+            "Rejected legacy signature for {}; envelope signatures are required",
+            package_name
+          );
+          return Err(unauthenticated(
+            AuthRejection::LegacySignature,
+            "Legacy signatures are disabled; the client must sign the request envelope",
+          ));
+        }
+        warn!(
+          "Accepting legacy archive-only signature for {}",
+          package_name
+        );
+        (data.to_vec(), None)
+      }
+    };
+
+    match Auth::verify_signature(public_key, &signed_data, &signature) {
       Ok(valid) => {
         if !valid {
-          error!("Signature verification failed for {}", req.package_name);
-          return Err(Status::unauthenticated("Invalid Ed25519 signature"));
+          error!("Signature verification failed for {}", package_name);
+          return Err(unauthenticated(
+            AuthRejection::InvalidSignature,
+            "Invalid Ed25519 signature",
+          ));
         }
       }
       Err(e) => {
         error!("Ed25519 signature verification error: {}", e);
-        return Err(Status::unauthenticated(format!("Auth error: {}", e)));
+        return Err(unauthenticated(
+          AuthRejection::InvalidSignature,
+          format!("Auth error: {}", e),
+        ));
       }
     }
 
-    // Ensure package configuration exists
-    let package_config = match package_config {
-      Some(config) => config,
-      None => {
-        error!("Package {} is not configured", req.package_name);
-        return Err(Status::not_found(format!(
-          "Package '{}' not configured",
-          req.package_name
-        )));
+    // Only remember nonces of correctly signed requests
+    if let Some((nonce, timestamp)) = replay_fields {
+      if replay_window > 0 {
+        let now = now.timestamp();
+        let (result, snapshot) = {
+          let mut guard = self.replay_guard.lock().await;
+          let result = guard.check(&nonce, timestamp, now, replay_window);
+          let snapshot = result.is_ok().then(|| guard.snapshot());
+          (result, snapshot)
+        };
+
+        match result {
+          Ok(()) => {
+            // Written off the runtime without holding the guard; a failure only means the
+            // nonce is forgotten after a restart
+            if let Some(snapshot) = snapshot {
+              match spawn_blocking(move || snapshot.persist()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("{}", e),
+                Err(e) => warn!("Nonce cache task failed: {}", e),
+              }
+            }
+          }
+          Err(ReplayRejection::OutsideWindow { skew_secs }) => {
+            error!(
+              "Request for {} is {}s away from server time (window {}s)",
+              package_name, skew_secs, replay_window
+            );
+            return Err(unauthenticated(
+              AuthRejection::ClockSkew,
+              format!(
+                "Request timestamp is {}s away from server time, outside the allowed {}s window",
+                skew_secs, replay_window
+              ),
+            ));
+          }
+          Err(ReplayRejection::NonceReused) => {
+            error!("Replayed request nonce for {}", package_name);
+            return Err(unauthenticated(
+              AuthRejection::ReplayedNonce,
+              "Request nonce has already been used",
+            ));
+          }
+        }
       }
+    }
+
+    // Ensure package configuration exists
+    let Some(package_config) = package_config else {
+      error!("Package {} is not configured", package_name);
+      return Err(Status::not_found(format!(
+        "Package '{}' not configured",
+        package_name
+      )));
     };
 
-    if max_file_size > 0 && req.file_data.len() as u64 > max_file_size {
+    // Ensure the key may deploy this particular package
+    if !package_config.permits_key(package_name, &allowed_key) {
+      let key_label = allowed_key.name().unwrap_or("unnamed");
       error!(
-        "Payload for {} exceeds configured max_file_size {}",
-        req.package_name, max_file_size
+        "Key '{}' is not permitted to deploy {}",
+        key_label, package_name
       );
-      return Err(Status::resource_exhausted(format!(
-        "Archive size exceeds configured max_file_size ({} bytes)",
-        max_file_size
+      return Err(Status::permission_denied(format!(
+        "Key '{}' is not permitted to deploy package '{}'",
+        key_label, package_name
       )));
     }
 
-    let package_name = req.package_name.clone();
-    let file_hash = req.file_hash.clone();
-    let file_data = std::mem::take(&mut req.file_data);
+    Ok((package_config, max_file_size))
+  }
 
-    // Initialize deployment manager
-    let deploy_manager = DeployManager::new();
-    let deploy_id = deploy_manager.deploy_id.clone();
+  /// Serialize deployments per package, or across the server with `deploy_lock = "global"`,
+  /// queueing for up to `deploy_lock_wait_secs` before rejecting the request
+  async fn lock_deployment(
+    &self,
+    package_name: &str,
+    deploy_id: &str,
+  ) -> std::result::Result<DeployLockGuard, Status> {
+    let (scope, wait_secs, deploy_path) = {
+      let config = self.config.read().await;
+      (
+        config.server.deploy_lock,
+        config.server.deploy_lock_wait_secs,
+        config
+          .packages
+          .get(package_name)
+          .map(|package| PathBuf::from(&package.deploy_path)),
+      )
+    };
+    let lock_name = match scope {
+      DeployLockScope::Package => package_name,
+      DeployLockScope::Global => "",
+    };
+
+    let wait = Duration::from_secs(wait_secs);
+    let started = Instant::now();
+    let guard = self
+      .deploy_locks
+      .acquire(lock_name, package_name, deploy_id, wait)
+      .await
+      .map_err(|busy| Self::lock_busy(package_name, busy))?;
+
+    // Other processes writing to the same files, such as `adeploy server restore`, only see the
+    // lock file
+    let Some(deploy_path) = deploy_path else {
+      return Ok(guard);
+    };
+    // Both locks share one deploy_lock_wait_secs budget
+    let remaining = wait.saturating_sub(started.elapsed());
+    match PathLock::acquire(&deploy_path, package_name, deploy_id, remaining).await {
+      Ok(Ok(path_lock)) => Ok(guard.with_path_lock(path_lock)),
+      Ok(Err(busy)) => Err(Self::lock_busy(package_name, busy)),
+      Err(e) => {
+        error!("{}", e);
+        Err(Status::internal(e.to_string()))
+      }
+    }
+  }
+
+  fn lock_busy(package_name: &str, busy: LockBusy) -> Status {
+    let message = format!(
+      "Deployment {} of '{}' is in progress; retry once it finishes",
+      busy.deploy_id, busy.package_name
+    );
+    warn!("Rejected deployment of {}: {}", package_name, message);
+    Status::failed_precondition(message)
+  }
 
-    info!("Starting deployment {} for {}", deploy_id, package_name);
+  /// Run a deployment while forwarding its progress to a streaming client
+  async fn stream_deployment(
+    &self,
+    header: DeployHeader,
+    deploy_manager: DeployManager,
+    authorization: Authorization,
+    spool: ArchiveSpool,
+    event_tx: mpsc::Sender<std::result::Result<DeployEvent, Status>>,
+  ) {
+    Self::stream_progress(event_tx, |mut reporter| async move {
+      reporter.stage(DeployStage::Verify);
+      match spool.finish(&header.file_hash, header.file_size).await {
+        Ok(archive) => {
+          self
+            .run_deployment(
+              &header.package_name,
+              deploy_manager,
+              &authorization,
+              DeploySource::Archive(&archive),
+              reporter,
+            )
+            .await
+        }
+        Err(e) => Self::failure_response(String::new(), e, reporter),
+      }
+    })
+    .await;
+  }
+
+  /// Restore a backup while forwarding its progress to a streaming client
+  async fn stream_restore(
+    &self,
+    package_name: String,
+    deploy_manager: DeployManager,
+    backup: BackupEntry,
+    authorization: Authorization,
+    event_tx: mpsc::Sender<std::result::Result<DeployEvent, Status>>,
+  ) {
+    Self::stream_progress(event_tx, |reporter| async move {
+      self
+        .run_deployment(
+          &package_name,
+          deploy_manager,
+          &authorization,
+          DeploySource::Backup(&backup),
+          reporter,
+        )
+        .await
+    })
+    .await;
+  }
+
+  /// Forward every stage and log produced by `run` as events, followed by its result
+  async fn stream_progress<F, Fut>(
+    event_tx: mpsc::Sender<std::result::Result<DeployEvent, Status>>,
+    run: F,
+  ) where
+    F: FnOnce(DeployReporter) -> Fut,
+    Fut: Future<Output = Response<DeployResponse>>,
+  {
+    let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+    let forward_tx = event_tx.clone();
+    let forwarder = tokio::spawn(async move {
+      while let Some(progress) = progress_rx.recv().await {
+        let event = Self::encode_progress(progress);
+        if forward_tx.send(Ok(event)).await.is_err() {
+          warn!("Progress stream closed by client; deployment continues");
+          break;
+        }
+      }
+    });
+
+    let response = run(DeployReporter::with_listener(progress_tx)).await;
+    let _ = forwarder.await;
+
+    // Logs have already been streamed as they were produced
+    let mut response = response.into_inner();
+    response.logs.clear();
+    let _ = event_tx
+      .send(Ok(DeployEvent {
+        event: Some(deploy_event::Event::Result(response)),
+      }))
+      .await;
+  }
+
+  async fn run_deployment(
+    &self,
+    package_name: &str,
+    deploy_manager: DeployManager,
+    authorization: &Authorization,
+    source: DeploySource<'_>,
+    mut reporter: DeployReporter,
+  ) -> Response<DeployResponse> {
+    let deploy_id = deploy_manager.deploy_id.clone();
+    let key = &authorization.key;
+
+    info!(
+      "Starting deployment {} for {} authorized by key '{}' ({})",
+      deploy_id, package_name, key.name, key.fingerprint
+    );
+    reporter.push(DeployLogEntry::info(format!(
+      "Authorized by key '{}' ({})",
+      key.name, key.fingerprint
+    )));
 
-    // Execute deployment synchronously for now
-    // TODO: Implement proper async deployment with Send-safe types
     match Self::execute_deployment(
       &deploy_manager,
-      &package_config,
-      file_data,
-      file_hash,
-      &package_name,
+      &authorization.package_config,
+      source,
+      package_name,
+      &mut reporter,
     )
     .await
     {
-      Ok(logs) => {
+      Ok(()) => {
         info!("Deployment {} completed for {}", deploy_id, package_name);
 
-        Ok(Response::new(DeployResponse {
+        Response::new(DeployResponse {
           success: true,
           message: "Deployment completed successfully".to_string(),
           deploy_id,
-          logs: Self::encode_logs(logs),
-        }))
+          logs: Self::encode_logs(reporter.into_entries()),
+        })
       }
       Err(e) => {
         error!(
           "Deployment {} failed for {}: {}",
           deploy_id, package_name, e
         );
+        Self::failure_response(deploy_id, e, reporter)
+      }
+    }
+  }
 
-        // Always collect logs on failure
-        let mut logs = vec![DeployLogEntry::error(format!("Deployment failed: {}", e))];
+  fn failure_response(
+    deploy_id: String,
+    e: Box<AdeployError>,
+    mut reporter: DeployReporter,
+  ) -> Response<DeployResponse> {
+    // Always collect logs on failure
+    reporter.push(DeployLogEntry::error(format!("Deployment failed: {}", e)));
+
+    // Include additional details when available
+    if let AdeployError::Deploy(msg) = e.as_ref() {
+      reporter.push(DeployLogEntry::error(format!("Details: {}", msg)));
+    }
 
-        // Include additional details when available
-        if let AdeployError::Deploy(msg) = e.as_ref() {
-          logs.push(DeployLogEntry::error(format!("Details: {}", msg)));
-        }
+    Response::new(DeployResponse {
+      success: false,
+      message: e.to_string(),
+      deploy_id,
+      logs: Self::encode_logs(reporter.into_entries()),
+    })
+  }
 
-        Ok(Response::new(DeployResponse {
-          success: false,
-          message: e.to_string(),
-          deploy_id,
-          logs: Self::encode_logs(logs),
-        }))
-      }
+  fn encode_logs(logs: Vec<DeployLogEntry>) -> Vec<crate::adeploy::DeployLog> {
+    logs.into_iter().map(Self::encode_log).collect()
+  }
+
+  fn encode_log(entry: DeployLogEntry) -> crate::adeploy::DeployLog {
+    crate::adeploy::DeployLog {
+      level: Self::map_log_level(entry.level) as i32,
+      message: entry.message,
     }
   }
-}
 
-impl AdeployService {
-  fn encode_logs(logs: Vec<DeployLogEntry>) -> Vec<crate::adeploy::DeployLog> {
-    logs
-      .into_iter()
-      .map(|entry| crate::adeploy::DeployLog {
-        level: Self::map_log_level(entry.level) as i32,
-        message: entry.message,
-      })
-      .collect()
+  fn encode_progress(progress: DeployProgress) -> DeployEvent {
+    let event = match progress {
+      DeployProgress::Stage(stage) => deploy_event::Event::Stage(Self::map_stage(stage) as i32),
+      DeployProgress::Log(entry) => deploy_event::Event::Log(Self::encode_log(entry)),
+    };
+    DeployEvent { event: Some(event) }
   }
 
   fn map_log_level(level: LogLevel) -> crate::adeploy::deploy_log::Level {
@@ -187,85 +881,315 @@ impl AdeployService {
     }
   }
 
+  fn map_stage(stage: DeployStage) -> crate::adeploy::DeployStage {
+    match stage {
+      DeployStage::Verify => crate::adeploy::DeployStage::Verify,
+      DeployStage::Backup => crate::adeploy::DeployStage::Backup,
+      DeployStage::BeforeScript => crate::adeploy::DeployStage::BeforeScript,
+      DeployStage::Extract => crate::adeploy::DeployStage::Extract,
+      DeployStage::AfterScript => crate::adeploy::DeployStage::AfterScript,
+      DeployStage::Activate => crate::adeploy::DeployStage::Activate,
+      DeployStage::Rollback => crate::adeploy::DeployStage::Rollback,
+      DeployStage::HealthCheck => crate::adeploy::DeployStage::HealthCheck,
+      DeployStage::Restore => crate::adeploy::DeployStage::Restore,
+    }
+  }
+
   async fn execute_deployment(
     deploy_manager: &DeployManager,
-    package_config: &crate::config::ServerPackageConfig,
-    file_data: Vec<u8>,
-    file_hash: String,
+    package_config: &ServerPackageConfig,
+    source: DeploySource<'_>,
+    package_name: &str,
+    logs: &mut DeployReporter,
+  ) -> Result<()> {
+    let result =
+      Self::run_deployment_stages(deploy_manager, package_config, source, package_name, logs).await;
+    if result.is_err() {
+      // Failure hooks only ever warn, so there is no further failure to handle
+      let _ = deploy_manager
+        .run_hooks(HookStage::OnFailure, package_config, logs)
+        .await;
+    }
+    result
+  }
+
+  async fn run_deployment_stages(
+    deploy_manager: &DeployManager,
+    package_config: &ServerPackageConfig,
+    source: DeploySource<'_>,
     package_name: &str,
-  ) -> Result<Vec<DeployLogEntry>> {
-    let mut logs = Vec::new();
+    logs: &mut DeployReporter,
+  ) -> Result<()> {
     logs.push(DeployLogEntry::info(format!(
       "[{}] Starting deployment execution",
       deploy_manager.deploy_id
     )));
+    let upgrade = package_config.has_live_deployment();
+
+    // Nothing has changed yet, so a failing before-deploy or before-backup hook never needs a
+    // rollback
+    logs.stage(DeployStage::BeforeScript);
+    let mut stages = vec![HookStage::BeforeDeploy];
+    if package_config.backup_enabled {
+      stages.push(HookStage::BeforeBackup);
+    }
+    for stage in stages {
+      if let Err(failure) = deploy_manager.run_hooks(stage, package_config, logs).await {
+        logs.push(DeployLogEntry::error(format!(
+          "{} hook failed: {}",
+          failure.stage, failure.error
+        )));
+        return Err(failure.error);
+      }
+    }
 
-    // Run before-deploy hook
-    logs.push(DeployLogEntry::info("Running Before-deploy script..."));
-    match deploy_manager
-      .execute_before_deploy_script(package_config)
-      .await
-    {
-      Ok(pre_logs) => {
-        logs.extend(pre_logs);
-        logs.push(DeployLogEntry::info("Before-deploy script succeeded"));
+    // Extract the verified archive, or bring back the requested backup
+    let (files, failure) = match source {
+      DeploySource::Archive(archive) => {
+        logs.push(DeployLogEntry::info("Extracting files..."));
+        let files = deploy_manager
+          .extract_files(archive, package_config, package_name, logs)
+          .await
+          .map(|()| "Files extracted and deployed successfully");
+        (files, "File extraction failed")
       }
-      Err(e) => {
-        error!("Before-deploy script failed: {}", e);
-        logs.push(DeployLogEntry::error(format!(
-          "Before-deploy script failed: {}",
-          e
+      DeploySource::Backup(backup) => {
+        logs.push(DeployLogEntry::info(format!(
+          "Restoring files from {}...",
+          backup.name
         )));
+        let files = deploy_manager
+          .restore_files(&backup.path, package_config, package_name, logs)
+          .await
+          .map(|()| "Files restored from backup");
+        (files, "Restore failed")
+      }
+    };
+    match files {
+      Ok(message) => {
+        logs.push(DeployLogEntry::info(message));
+      }
+      Err(e) => {
+        error!("{}: {}", failure, e);
+        logs.push(DeployLogEntry::error(format!("{}: {}", failure, e)));
+        Self::abandon_deployment(
+          deploy_manager,
+          package_config,
+          package_name,
+          package_config.rollback_on_failure,
+          logs,
+        )
+        .await;
         return Err(e);
       }
     }
 
-    // Extract archive and verify hash
-    logs.push(DeployLogEntry::info("Extracting files..."));
-    match deploy_manager
-      .extract_files(file_data, &file_hash, package_config, package_name)
-      .await
-    {
-      Ok(()) => {
-        logs.push(DeployLogEntry::info(
-          "Files extracted and deployed successfully",
-        ));
+    // Hooks for the new files; a release only goes live once they pass their policies
+    logs.stage(DeployStage::AfterScript);
+    let install_stage = if upgrade {
+      HookStage::OnUpgrade
+    } else {
+      HookStage::OnFirstInstall
+    };
+    for stage in [install_stage, HookStage::AfterDeploy] {
+      if let Err(failure) = deploy_manager.run_hooks(stage, package_config, logs).await {
+        return Err(
+          Self::hook_failed(deploy_manager, package_config, package_name, failure, logs).await,
+        );
       }
-      Err(e) => {
-        error!("File extraction failed: {}", e);
-        logs.push(DeployLogEntry::error(format!(
-          "File extraction failed: {}",
-          e
-        )));
+    }
+
+    // Switch the live release, if this package uses the releases layout
+    if let Err(e) = deploy_manager.activate_release(package_config, logs).await {
+      error!("Release activation failed: {}", e);
+      logs.push(DeployLogEntry::error(format!(
+        "Release activation failed: {}",
+        e
+      )));
+      Self::abandon_deployment(
+        deploy_manager,
+        package_config,
+        package_name,
+        package_config.rollback_on_failure,
+        logs,
+      )
+      .await;
+      return Err(e);
+    }
+
+    // Only a healthy deployment counts as successful
+    if let Some(check) = &package_config.health_check {
+      logs.stage(DeployStage::HealthCheck);
+      logs.push(DeployLogEntry::info("Running health check..."));
+      if let Err(e) = health::run_health_check(check, package_config, logs).await {
+        error!("Health check failed: {}", e);
+        logs.push(DeployLogEntry::error(format!("Health check failed: {}", e)));
+        Self::abandon_deployment(
+          deploy_manager,
+          package_config,
+          package_name,
+          package_config.rollback_on_failure,
+          logs,
+        )
+        .await;
         return Err(e);
       }
     }
 
-    // Run after-deploy hook
-    logs.push(DeployLogEntry::info("Running After-deploy script..."));
-    match deploy_manager
-      .execute_after_deploy_script(package_config)
+    if let Err(failure) = deploy_manager
+      .run_hooks(HookStage::AfterHealthCheck, package_config, logs)
       .await
     {
-      Ok(post_logs) => {
-        logs.extend(post_logs);
-        logs.push(DeployLogEntry::info("After-deploy script succeeded"));
-      }
-      Err(e) => {
-        error!("After-deploy script failed: {}", e);
-        logs.push(DeployLogEntry::error(format!(
-          "After-deploy script failed: {}",
-          e
-        )));
-        // Deployment succeeds even if the After-deploy script fails
-      }
+      return Err(
+        Self::hook_failed(deploy_manager, package_config, package_name, failure, logs).await,
+      );
+    }
+
+    deploy_manager.prune_releases(package_config, logs).await;
+    if let Err(e) = deploy_manager.record_deployment(package_config).await {
+      warn!("Failed to record deployment: {}", e);
+      logs.push(DeployLogEntry::warn(format!(
+        "Failed to record deployment: {}",
+        e
+      )));
     }
 
     logs.push(DeployLogEntry::info(format!(
       "[{}] Deployment completed successfully",
       deploy_manager.deploy_id
     )));
-    Ok(logs)
+    Ok(())
+  }
+
+  /// Fail the deployment for a hook, rolling back when its policy asks for it
+  async fn hook_failed(
+    deploy_manager: &DeployManager,
+    package_config: &ServerPackageConfig,
+    package_name: &str,
+    failure: HookFailure,
+    logs: &mut DeployReporter,
+  ) -> Box<AdeployError> {
+    logs.push(DeployLogEntry::error(format!(
+      "{} hook failed: {}",
+      failure.stage, failure.error
+    )));
+    let rollback = failure.policy == FailurePolicy::Rollback || package_config.rollback_on_failure;
+    Self::abandon_deployment(deploy_manager, package_config, package_name, rollback, logs).await;
+    failure.error
+  }
+
+  /// Clean up after a failed deployment, rolling back if `rollback` is set
+  async fn abandon_deployment(
+    deploy_manager: &DeployManager,
+    package_config: &ServerPackageConfig,
+    package_name: &str,
+    rollback: bool,
+    logs: &mut DeployReporter,
+  ) {
+    if !rollback {
+      deploy_manager.discard_release(package_config).await;
+      return;
+    }
+
+    warn!(
+      "[{}] Rolling back failed deployment of {}",
+      deploy_manager.deploy_id, package_name
+    );
+    logs.push(DeployLogEntry::warn("Rolling back failed deployment..."));
+    match deploy_manager
+      .rollback(package_config, package_name, logs)
+      .await
+    {
+      Ok(()) => {
+        info!("[{}] Rollback completed", deploy_manager.deploy_id);
+        logs.push(DeployLogEntry::info("Rollback completed"));
+        // Rollback hooks only ever warn
+        let _ = deploy_manager
+          .run_hooks(HookStage::OnRollback, package_config, logs)
+          .await;
+      }
+      Err(e) => {
+        error!("[{}] Rollback failed: {}", deploy_manager.deploy_id, e);
+        logs.push(DeployLogEntry::error(format!("Rollback failed: {}", e)));
+      }
+    }
+  }
+}
+
+/// Version recorded in a backup's manifest, so the restore reports what it brings back
+fn restored_version(backup: &BackupEntry) -> String {
+  match backup::read_manifest(&backup.path) {
+    Ok(manifest) => manifest
+      .and_then(|manifest| manifest.deployment)
+      .map(|record| record.version)
+      .unwrap_or_default(),
+    Err(e) => {
+      warn!("{}", e);
+      String::new()
+    }
+  }
+}
+
+/// Restore a package from the backup named `backup_name`, or the newest one, running its
+/// hooks and health check as a deployment would. Waits up to `wait` for a deployment holding
+/// the package's deploy path, including one run by a server process. Returns the restore's
+/// deploy id.
+pub async fn restore_package(
+  package_config: &ServerPackageConfig,
+  package_name: &str,
+  backup_name: Option<&str>,
+  wait: Duration,
+  reporter: &mut DeployReporter,
+) -> Result<String> {
+  let dir = backup::backup_directory(package_config, package_name)?;
+  let backup = backup::find_backup(&dir, backup_name)?;
+  let deploy_manager = DeployManager::new()
+    .with_version(&restored_version(&backup))
+    .with_package(package_name);
+
+  let deploy_path = PathBuf::from(&package_config.deploy_path);
+  let _lock = PathLock::acquire(&deploy_path, package_name, &deploy_manager.deploy_id, wait)
+    .await?
+    .map_err(|busy| {
+      Box::new(AdeployError::Deploy(format!(
+        "Deployment {} of '{}' is in progress; retry once it finishes",
+        busy.deploy_id, busy.package_name
+      )))
+    })?;
+  info!(
+    "Starting restore {} of {} from {}",
+    deploy_manager.deploy_id, package_name, backup.name
+  );
+
+  // Records the restore as the live deployment once it succeeds
+  AdeployService::execute_deployment(
+    &deploy_manager,
+    package_config,
+    DeploySource::Backup(&backup),
+    package_name,
+    reporter,
+  )
+  .await?;
+  Ok(deploy_manager.deploy_id)
+}
+
+/// Build an Unauthenticated status tagged with a machine-readable reason
+fn unauthenticated(reason: AuthRejection, message: impl Into<String>) -> Status {
+  let mut status = Status::unauthenticated(message);
+  status.metadata_mut().insert(
+    AUTH_REJECTION_METADATA,
+    MetadataValue::from_static(reason.as_str()),
+  );
+  status
+}
+
+/// `replay_window_secs = 0` turns off both the timestamp and the nonce check
+fn warn_if_replay_protection_disabled(settings: &ServerSettings) {
+  if settings.replay_window_secs == 0 {
+    warn!(
+      "REPLAY PROTECTION IS DISABLED: replay_window_secs is 0, so request timestamps and \
+       nonces are not checked and any captured signed request can be replayed"
+    );
   }
 }
 
@@ -288,12 +1212,21 @@ where
     "Loaded server configuration; configured port {}",
     config.server.port
   );
+  warn_if_replay_protection_disabled(&config.server);
 
   let addr = format!("0.0.0.0:{}", port)
     .parse()
     .map_err(|e| Box::new(AdeployError::Network(format!("Invalid address: {}", e))))?;
 
   let message_limit = resolve_message_limit(config.server.max_file_size);
+  let tls_settings = config.server.tls.clone();
+  let nonce_cache_path = match &config.server.nonce_cache_path {
+    Some(path) => PathBuf::from(path),
+    None => env::current_exe()
+      .map_err(|e| service_error("Failed to resolve current executable path", e))?
+      .with_file_name("nonce_cache.json"),
+  };
+  let replay_guard = ReplayGuard::load(nonce_cache_path);
   let shared_config = Arc::new(RwLock::new(config));
   let (shutdown_tx, shutdown_rx) = watch::channel(false);
   let _watcher_guard = WatcherGuard {
@@ -306,11 +1239,29 @@ where
     shutdown_rx,
   );
 
-  let adeploy_service = AdeployService::new(shared_config);
+  let adeploy_service = AdeployService::new(shared_config, replay_guard);
+
+  let mut builder = Server::builder();
+  if let Some(tls_settings) = &tls_settings {
+    let tls_config = tls::server_tls_config(tls_settings)?;
+    builder = builder
+      .tls_config(tls_config)
+      .map_err(|e| Box::new(AdeployError::Config(format!("Invalid TLS config: {}", e))))?;
+    info!(
+      "TLS enabled{}",
+      if tls_settings.client_ca.is_some() {
+        " with client certificate verification"
+      } else {
+        ""
+      }
+    );
+  } else {
+    warn!("TLS is not configured; serving plaintext gRPC");
+  }
 
   info!("Binding ADeploy server on {}", addr);
 
-  Server::builder()
+  builder
     .add_service(
       DeployServiceServer::new(adeploy_service)
         .max_decoding_message_size(message_limit)
@@ -415,9 +1366,9 @@ fn spawn_config_watcher(
         Ok(mut new_config) => {
           last_error = None;
 
-          let existing_port = {
+          let (existing_port, existing_tls) = {
             let guard = shared_config.read().await;
-            guard.server.port
+            (guard.server.port, guard.server.tls.clone())
           };
 
           if new_config.server.port != existing_port {
@@ -430,6 +1381,15 @@ fn spawn_config_watcher(
             new_config.server.port = existing_port;
           }
 
+          if new_config.server.tls != existing_tls {
+            warn!(
+              "Ignoring TLS settings change in {}; restart the server to apply it",
+              config_path.display()
+            );
+            new_config.server.tls = existing_tls;
+          }
+
+          warn_if_replay_protection_disabled(&new_config.server);
           {
             let mut guard = shared_config.write().await;
             *guard = new_config;
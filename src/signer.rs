@@ -0,0 +1,239 @@
+//! Request signers backed by local key files or a running ssh-agent
+
+use std::{env, path::PathBuf};
+
+use base64::Engine;
+
+use crate::{
+  auth::{push_ssh_string, Auth, SshReader, SSH_ED25519},
+  config::SshAgentSettings,
+  error::{AdeployError, Result},
+};
+
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+/// Upper bound on agent replies, matching OpenSSH's own limit
+#[cfg(unix)]
+const MAX_AGENT_REPLY: usize = 256 * 1024;
+
+/// Produces Ed25519 signatures for deploy requests
+pub trait Signer: Send + Sync {
+  /// Raw base64 public key sent alongside each signature
+  fn public_key(&self) -> &str;
+
+  /// Sign data and return the 64-byte Ed25519 signature
+  fn sign(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Signer holding a private key loaded from disk
+pub struct KeyFileSigner {
+  auth: Auth,
+  public_key: String,
+}
+
+impl KeyFileSigner {
+  pub fn new(auth: Auth, public_key: String) -> Self {
+    Self { auth, public_key }
+  }
+}
+
+impl Signer for KeyFileSigner {
+  fn public_key(&self) -> &str {
+    &self.public_key
+  }
+
+  fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+    self.auth.sign_data(data)
+  }
+}
+
+/// Ed25519 identity offered by an ssh-agent
+#[derive(Debug, Clone)]
+pub struct AgentIdentity {
+  /// SSH wire-format public key blob
+  pub key_blob: Vec<u8>,
+  /// Raw base64 public key
+  pub public_key: String,
+  pub fingerprint: String,
+  pub comment: String,
+}
+
+/// Signer delegating to an identity held by ssh-agent
+pub struct AgentSigner {
+  socket: PathBuf,
+  identity: AgentIdentity,
+}
+
+impl AgentSigner {
+  /// Connect to the configured agent socket (or `SSH_AUTH_SOCK`) and select an identity
+  pub fn connect(settings: &SshAgentSettings) -> Result<Self> {
+    let socket = settings
+      .socket
+      .as_ref()
+      .map(PathBuf::from)
+      .or_else(|| env::var_os("SSH_AUTH_SOCK").map(PathBuf::from))
+      .ok_or_else(|| {
+        Box::new(AdeployError::Auth(
+          "SSH_AUTH_SOCK is not set; start ssh-agent or configure ssh_agent.socket".to_string(),
+        ))
+      })?;
+
+    Self::with_socket(socket, settings.identity.as_deref())
+  }
+
+  /// Select the ed25519 identity matching a fingerprint or comment, or the first one
+  pub fn with_socket(socket: PathBuf, selector: Option<&str>) -> Result<Self> {
+    let identities = list_identities(&socket)?;
+
+    let identity = match selector {
+      Some(selector) => identities
+        .iter()
+        .find(|identity| identity.fingerprint == selector || identity.comment == selector),
+      None => identities.first(),
+    };
+
+    let Some(identity) = identity.cloned() else {
+      let available = identities
+        .iter()
+        .map(|identity| format!("{} ({})", identity.fingerprint, identity.comment))
+        .collect::<Vec<_>>();
+      return Err(Box::new(AdeployError::Auth(format!(
+        "No matching ed25519 identity in ssh-agent{}; available: [{}]",
+        selector
+          .map(|selector| format!(" for '{}'", selector))
+          .unwrap_or_default(),
+        available.join(", ")
+      ))));
+    };
+
+    Ok(Self { socket, identity })
+  }
+
+  pub fn identity(&self) -> &AgentIdentity {
+    &self.identity
+  }
+}
+
+impl Signer for AgentSigner {
+  fn public_key(&self) -> &str {
+    &self.identity.public_key
+  }
+
+  fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+    let mut request = vec![SSH_AGENTC_SIGN_REQUEST];
+    push_ssh_string(&mut request, &self.identity.key_blob);
+    push_ssh_string(&mut request, data);
+    request.extend_from_slice(&0u32.to_be_bytes());
+
+    let response = agent_request(&self.socket, &request)?;
+    let mut reader = SshReader::new(&response);
+    match reader.read_u8()? {
+      SSH_AGENT_SIGN_RESPONSE => {}
+      SSH_AGENT_FAILURE => {
+        return Err(Box::new(AdeployError::Auth(
+          "ssh-agent refused to sign the request".to_string(),
+        )));
+      }
+      other => return Err(unexpected_reply(other)),
+    }
+
+    let mut signature = SshReader::new(reader.read_string()?);
+    if signature.read_string()? != SSH_ED25519.as_bytes() {
+      return Err(Box::new(AdeployError::Auth(
+        "ssh-agent returned a non-ed25519 signature".to_string(),
+      )));
+    }
+    let bytes = signature.read_string()?;
+    if bytes.len() != 64 {
+      return Err(Box::new(AdeployError::Auth(
+        "ssh-agent returned a malformed signature".to_string(),
+      )));
+    }
+
+    Ok(bytes.to_vec())
+  }
+}
+
+/// List the ed25519 identities held by the agent; other key types are skipped
+pub fn list_identities(socket: &std::path::Path) -> Result<Vec<AgentIdentity>> {
+  let response = agent_request(socket, &[SSH_AGENTC_REQUEST_IDENTITIES])?;
+  let mut reader = SshReader::new(&response);
+  match reader.read_u8()? {
+    SSH_AGENT_IDENTITIES_ANSWER => {}
+    other => return Err(unexpected_reply(other)),
+  }
+
+  let count = reader.read_u32()?;
+  let mut identities = Vec::new();
+  for _ in 0..count {
+    let key_blob = reader.read_string()?;
+    let comment = String::from_utf8_lossy(reader.read_string()?).into_owned();
+
+    let encoded = base64::engine::general_purpose::STANDARD.encode(key_blob);
+    let Ok(key) = Auth::parse_public_key(&encoded) else {
+      continue;
+    };
+    let public_key = Auth::encode_public_key(&key);
+    identities.push(AgentIdentity {
+      key_blob: key_blob.to_vec(),
+      fingerprint: Auth::fingerprint(&public_key)?,
+      public_key,
+      comment,
+    });
+  }
+
+  Ok(identities)
+}
+
+fn unexpected_reply(code: u8) -> Box<AdeployError> {
+  Box::new(AdeployError::Auth(format!(
+    "Unexpected ssh-agent reply type {}",
+    code
+  )))
+}
+
+/// Send one length-prefixed message to the agent and read its reply
+#[cfg(unix)]
+fn agent_request(socket: &std::path::Path, message: &[u8]) -> Result<Vec<u8>> {
+  use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+  };
+
+  let agent_error = |e: std::io::Error| {
+    Box::new(AdeployError::Auth(format!(
+      "ssh-agent at {} is unavailable: {}",
+      socket.display(),
+      e
+    )))
+  };
+
+  let mut stream = UnixStream::connect(socket).map_err(agent_error)?;
+  let mut framed = Vec::with_capacity(message.len() + 4);
+  push_ssh_string(&mut framed, message);
+  stream.write_all(&framed).map_err(agent_error)?;
+
+  let mut len = [0u8; 4];
+  stream.read_exact(&mut len).map_err(agent_error)?;
+  let len = u32::from_be_bytes(len) as usize;
+  if len > MAX_AGENT_REPLY {
+    return Err(Box::new(AdeployError::Auth(format!(
+      "ssh-agent reply of {} bytes exceeds the protocol limit",
+      len
+    ))));
+  }
+  let mut reply = vec![0u8; len];
+  stream.read_exact(&mut reply).map_err(agent_error)?;
+
+  Ok(reply)
+}
+
+#[cfg(not(unix))]
+fn agent_request(_socket: &std::path::Path, _message: &[u8]) -> Result<Vec<u8>> {
+  Err(Box::new(AdeployError::Auth(
+    "ssh-agent signing is only supported on Unix".to_string(),
+  )))
+}
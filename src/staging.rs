@@ -0,0 +1,399 @@
+//! Staged extraction and transactional commit for in-place deployments
+
+use std::{
+  collections::HashSet,
+  fs, io,
+  path::{Path, PathBuf},
+};
+
+use glob::{MatchOptions, Pattern};
+use log2::*;
+use tempfile::TempDir;
+
+use crate::{
+  config::SyncMode,
+  error::{AdeployError, Result},
+};
+
+/// Names starting with this at the top of `deploy_path` belong to adeploy, not the package
+pub(crate) const RESERVED_PREFIX: &str = ".adeploy-";
+
+/// `*` and `?` in path globs such as `preserve` never cross a path separator
+pub(crate) const PATH_MATCH_OPTIONS: MatchOptions = MatchOptions {
+  case_sensitive: true,
+  require_literal_separator: true,
+  require_literal_leading_dot: false,
+};
+
+/// Archive contents unpacked beside `deploy_path`, waiting to be moved into place.
+/// The staging directory is removed when this is dropped.
+pub struct StagedDeploy {
+  staging: TempDir,
+  deploy_path: PathBuf,
+  undo_path: PathBuf,
+}
+
+impl StagedDeploy {
+  /// Create an empty staging directory next to `deploy_path`
+  pub fn create(deploy_path: &Path, deploy_id: &str) -> Result<Self> {
+    let parent = deploy_path.parent().ok_or_else(|| {
+      Box::new(AdeployError::FileSystem(format!(
+        "Deploy path {} has no parent directory for staging",
+        deploy_path.display()
+      )))
+    })?;
+    let name = deploy_path
+      .file_name()
+      .map(|name| name.to_string_lossy().into_owned())
+      .unwrap_or_default();
+
+    let staging = tempfile::Builder::new()
+      .prefix(&format!(".{}.adeploy-staging-", name))
+      .tempdir_in(parent)
+      .map_err(|e| {
+        Box::new(AdeployError::FileSystem(format!(
+          "Failed to create staging directory in {}: {}",
+          parent.display(),
+          e
+        )))
+      })?;
+
+    // Replaced files wait beside `deploy_path` too, where nothing serving it can see them
+    Ok(Self {
+      staging,
+      deploy_path: deploy_path.to_path_buf(),
+      undo_path: parent.join(format!(".{}{}undo-{}", name, RESERVED_PREFIX, deploy_id)),
+    })
+  }
+
+  pub fn path(&self) -> &Path {
+    self.staging.path()
+  }
+
+  /// Check the staged tree only holds entries that can be committed; returns the entry count
+  pub fn validate(&self) -> Result<usize> {
+    count_entries(self.staging.path()).map_err(|e| {
+      Box::new(AdeployError::Deploy(format!(
+        "Staged files failed validation: {}",
+        e
+      )))
+    })
+  }
+
+  /// Move every staged entry into `deploy_path`, restoring replaced entries if any move fails.
+  /// `Mirror` also removes entries missing from the staged tree; paths matching a `preserve`
+  /// glob are left alone either way.
+  pub fn commit(self, mode: SyncMode, preserve: &[String]) -> Result<()> {
+    let preserve = preserve
+      .iter()
+      .map(|glob| {
+        Pattern::new(glob.trim_end_matches('/')).map_err(|e| {
+          Box::new(AdeployError::Config(format!(
+            "Invalid preserve pattern '{}': {}",
+            glob, e
+          )))
+        })
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    fs::create_dir_all(&self.undo_path).map_err(|e| {
+      Box::new(AdeployError::FileSystem(format!(
+        "Failed to create undo directory {}: {}",
+        self.undo_path.display(),
+        e
+      )))
+    })?;
+
+    let mut transaction = Transaction {
+      undo_path: &self.undo_path,
+      prune: mode == SyncMode::Mirror,
+      preserve,
+      steps: Vec::new(),
+    };
+    let outcome = transaction.commit_dir(self.staging.path(), &self.deploy_path, Path::new(""));
+
+    if let Err(e) = outcome {
+      error!(
+        "Commit into {} failed after {} steps: {}",
+        self.deploy_path.display(),
+        transaction.steps.len(),
+        e
+      );
+      let restored = transaction.rollback();
+      let _ = fs::remove_dir_all(&self.undo_path);
+      return Err(Box::new(AdeployError::FileSystem(match restored {
+        Ok(()) => format!(
+          "Failed to commit staged files into {}: {}; previous files restored",
+          self.deploy_path.display(),
+          e
+        ),
+        Err(restore_error) => format!(
+          "Failed to commit staged files into {}: {}; restoring previous files also failed: {}",
+          self.deploy_path.display(),
+          e,
+          restore_error
+        ),
+      })));
+    }
+
+    // Replaced files are no longer needed once every entry is in place
+    if let Err(e) = fs::remove_dir_all(&self.undo_path) {
+      warn!(
+        "Failed to remove undo directory {}: {}",
+        self.undo_path.display(),
+        e
+      );
+    }
+    Ok(())
+  }
+}
+
+/// A reversible change made to `deploy_path` while committing
+enum CommitStep {
+  /// Entry moved in where nothing existed before
+  Created(PathBuf),
+  /// Existing entry moved aside into the undo directory
+  Displaced { original: PathBuf, saved: PathBuf },
+}
+
+struct Transaction<'a> {
+  undo_path: &'a Path,
+  /// Remove target entries that are not staged
+  prune: bool,
+  preserve: Vec<Pattern>,
+  steps: Vec<CommitStep>,
+}
+
+impl Transaction<'_> {
+  /// Merge a staged directory into its target, descending into directories present on both sides.
+  /// `relative` is the target's path below `deploy_path`, used for preserve matching.
+  fn commit_dir(&mut self, staged: &Path, target: &Path, relative: &Path) -> io::Result<()> {
+    let mut staged_names = HashSet::new();
+    for entry in fs::read_dir(staged)? {
+      let entry = entry?;
+      staged_names.insert(entry.file_name());
+      let source = entry.path();
+      let destination = target.join(entry.file_name());
+      let relative = relative.join(entry.file_name());
+      if self.is_preserved(&relative) {
+        info!("Preserving {}", relative.display());
+        continue;
+      }
+
+      match fs::symlink_metadata(&destination) {
+        Ok(existing) if existing.is_dir() && entry.file_type()?.is_dir() => {
+          self.commit_dir(&source, &destination, &relative)?;
+          continue;
+        }
+        Ok(_) => self.displace(&destination)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+      }
+
+      // Recorded first, so rollback also removes whatever a failed move left behind
+      self.steps.push(CommitStep::Created(destination.clone()));
+      move_entry(&source, &destination)?;
+    }
+
+    if self.prune {
+      let existing = fs::read_dir(target)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+      for path in existing {
+        let Some(name) = path.file_name() else {
+          continue;
+        };
+        // Undo directories and the deployment record are never part of an archive
+        let reserved =
+          relative.as_os_str().is_empty() && name.to_string_lossy().starts_with(RESERVED_PREFIX);
+        if !staged_names.contains(name) && !reserved {
+          self.remove_unstaged(&path, &relative.join(name))?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Move aside an entry the archive no longer ships, keeping anything preserved beneath it
+  fn remove_unstaged(&mut self, path: &Path, relative: &Path) -> io::Result<()> {
+    if self.is_preserved(relative) {
+      info!("Preserving {}", relative.display());
+      return Ok(());
+    }
+
+    let is_dir = fs::symlink_metadata(path)?.is_dir();
+    if is_dir && !self.preserve.is_empty() {
+      let children = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+      for name in children {
+        self.remove_unstaged(&path.join(&name), &relative.join(&name))?;
+      }
+      // Keep the directory when something inside it is preserved
+      if fs::read_dir(path)?.next().is_some() {
+        return Ok(());
+      }
+    }
+
+    info!(
+      "Removing {}, which is not in the archive",
+      relative.display()
+    );
+    self.displace(path)
+  }
+
+  fn is_preserved(&self, relative: &Path) -> bool {
+    let relative = relative
+      .components()
+      .map(|component| component.as_os_str().to_string_lossy())
+      .collect::<Vec<_>>()
+      .join("/");
+    self
+      .preserve
+      .iter()
+      .any(|pattern| pattern.matches_with(&relative, PATH_MATCH_OPTIONS))
+  }
+
+  /// Move an entry into the undo directory, which sits on another filesystem when
+  /// `deploy_path` is a mount point
+  fn displace(&mut self, original: &Path) -> io::Result<()> {
+    let saved = self.undo_path.join(self.steps.len().to_string());
+    let cross_device = match fs::rename(original, &saved) {
+      Ok(()) => false,
+      Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+        copy_complete_entry(original, &saved)?;
+        true
+      }
+      Err(e) => return Err(e),
+    };
+    self.steps.push(CommitStep::Displaced {
+      original: original.to_path_buf(),
+      saved,
+    });
+    // The saved copy is complete, so rollback can restore it even if this removal stops partway
+    if cross_device {
+      remove_entry(original)?;
+    }
+    Ok(())
+  }
+
+  /// Undo every recorded step in reverse order, continuing past individual failures
+  fn rollback(&mut self) -> io::Result<()> {
+    let mut first_error = None;
+    for step in self.steps.drain(..).rev() {
+      let result = match &step {
+        CommitStep::Created(path) => match remove_entry(path) {
+          Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+          result => result,
+        },
+        CommitStep::Displaced { original, saved } => restore_displaced(original, saved),
+      };
+      if let Err(e) = result {
+        let path = match &step {
+          CommitStep::Created(path) => path,
+          CommitStep::Displaced { original, .. } => original,
+        };
+        error!("Failed to restore {}: {}", path.display(), e);
+        first_error.get_or_insert(e);
+      }
+    }
+
+    match first_error {
+      Some(e) => Err(e),
+      None => Ok(()),
+    }
+  }
+}
+
+/// Put a displaced entry back, replacing anything a failed move left at its original path
+fn restore_displaced(original: &Path, saved: &Path) -> io::Result<()> {
+  if fs::symlink_metadata(original).is_ok() {
+    remove_entry(original)?;
+  }
+  move_entry(saved, original)
+}
+
+/// Rename an entry, copying it instead when staging and deploy_path are on different filesystems
+fn move_entry(source: &Path, destination: &Path) -> io::Result<()> {
+  match fs::rename(source, destination) {
+    Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+      copy_complete_entry(source, destination)?;
+      remove_entry(source)
+    }
+    result => result,
+  }
+}
+
+/// Copy an entry, removing whatever was copied if the copy fails partway
+fn copy_complete_entry(source: &Path, destination: &Path) -> io::Result<()> {
+  let copied = copy_entry(source, destination);
+  if copied.is_err() && fs::symlink_metadata(destination).is_ok() {
+    if let Err(e) = remove_entry(destination) {
+      error!(
+        "Failed to remove partial copy {}: {}",
+        destination.display(),
+        e
+      );
+    }
+  }
+  copied
+}
+
+/// Copy a file, symlink or directory tree, recreating symlinks rather than following them
+pub(crate) fn copy_entry(source: &Path, destination: &Path) -> io::Result<()> {
+  let metadata = fs::symlink_metadata(source)?;
+  if metadata.file_type().is_symlink() {
+    copy_symlink(source, destination)
+  } else if metadata.is_dir() {
+    fs::create_dir(destination)?;
+    for entry in fs::read_dir(source)? {
+      let entry = entry?;
+      copy_entry(&entry.path(), &destination.join(entry.file_name()))?;
+    }
+    fs::set_permissions(destination, metadata.permissions())
+  } else {
+    fs::copy(source, destination).map(|_| ())
+  }
+}
+
+#[cfg(unix)]
+fn copy_symlink(source: &Path, destination: &Path) -> io::Result<()> {
+  std::os::unix::fs::symlink(fs::read_link(source)?, destination)
+}
+
+#[cfg(windows)]
+fn copy_symlink(source: &Path, destination: &Path) -> io::Result<()> {
+  let target = fs::read_link(source)?;
+  if fs::metadata(source).map(|m| m.is_dir()).unwrap_or(false) {
+    std::os::windows::fs::symlink_dir(target, destination)
+  } else {
+    std::os::windows::fs::symlink_file(target, destination)
+  }
+}
+
+fn remove_entry(path: &Path) -> io::Result<()> {
+  if fs::symlink_metadata(path)?.is_dir() {
+    fs::remove_dir_all(path)
+  } else {
+    fs::remove_file(path)
+  }
+}
+
+/// Count staged entries, rejecting anything but files, directories and symlinks
+fn count_entries(dir: &Path) -> io::Result<usize> {
+  let mut count = 0;
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    let file_type = entry.file_type()?;
+    if file_type.is_dir() {
+      count += count_entries(&entry.path())?;
+    } else if !file_type.is_file() && !file_type.is_symlink() {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} is not a regular file", entry.path().display()),
+      ));
+    }
+    count += 1;
+  }
+  Ok(count)
+}
@@ -0,0 +1,65 @@
+//! TLS configuration for the gRPC server listener and client channels.
+
+use std::fs;
+
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+
+use crate::{
+  config::{RemoteTlsSettings, ServerTlsSettings},
+  error::{AdeployError, Result},
+};
+
+/// Build the listener TLS config; client certificates are required when `client_ca` is set
+pub fn server_tls_config(settings: &ServerTlsSettings) -> Result<ServerTlsConfig> {
+  let identity = load_identity(&settings.cert, &settings.key)?;
+  let mut tls = ServerTlsConfig::new().identity(identity);
+
+  if let Some(client_ca) = &settings.client_ca {
+    tls = tls.client_ca_root(load_certificate(client_ca)?);
+  }
+
+  Ok(tls)
+}
+
+/// Build the channel TLS config for a remote, falling back to the system roots without a CA bundle
+pub fn client_tls_config(settings: &RemoteTlsSettings) -> Result<ClientTlsConfig> {
+  let mut tls = match &settings.ca {
+    Some(ca) => ClientTlsConfig::new().ca_certificate(load_certificate(ca)?),
+    None => ClientTlsConfig::new().with_native_roots(),
+  };
+
+  match (&settings.client_cert, &settings.client_key) {
+    (Some(cert), Some(key)) => tls = tls.identity(load_identity(cert, key)?),
+    (None, None) => {}
+    _ => {
+      return Err(Box::new(AdeployError::Config(
+        "Both client_cert and client_key must be set for mutual TLS".to_string(),
+      )));
+    }
+  }
+
+  if let Some(server_name) = &settings.server_name {
+    tls = tls.domain_name(server_name);
+  }
+
+  Ok(tls)
+}
+
+fn load_identity(cert_path: &str, key_path: &str) -> Result<Identity> {
+  let cert = read_pem(cert_path, "certificate")?;
+  let key = read_pem(key_path, "private key")?;
+  Ok(Identity::from_pem(cert, key))
+}
+
+fn load_certificate(path: &str) -> Result<Certificate> {
+  Ok(Certificate::from_pem(read_pem(path, "CA certificate")?))
+}
+
+fn read_pem(path: &str, kind: &str) -> Result<Vec<u8>> {
+  fs::read(path).map_err(|e| {
+    Box::new(AdeployError::Config(format!(
+      "Failed to read TLS {} '{}': {}",
+      kind, path, e
+    )))
+  })
+}
@@ -0,0 +1,112 @@
+//! ssh-agent signer tests against an in-process agent
+
+#![cfg(unix)]
+
+use std::{
+  io::{Read, Write},
+  os::unix::net::UnixListener,
+  path::PathBuf,
+  thread,
+};
+
+use adeploy::{
+  auth::Auth,
+  signer::{AgentSigner, Signer},
+};
+use ed25519_dalek::{Signer as _, SigningKey};
+
+mod common;
+
+fn push_string(out: &mut Vec<u8>, value: &[u8]) {
+  out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+  out.extend_from_slice(value);
+}
+
+fn read_string(data: &mut &[u8]) -> Vec<u8> {
+  let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+  let value = data[4..4 + len].to_vec();
+  *data = &data[4 + len..];
+  value
+}
+
+fn ed25519_blob(key: &SigningKey) -> Vec<u8> {
+  let mut blob = Vec::new();
+  push_string(&mut blob, b"ssh-ed25519");
+  push_string(&mut blob, key.verifying_key().as_bytes());
+  blob
+}
+
+/// Serve `connections` agent requests holding an RSA placeholder and one ed25519 key
+fn spawn_agent(socket: PathBuf, key: SigningKey, connections: usize) -> thread::JoinHandle<()> {
+  let listener = UnixListener::bind(&socket).unwrap();
+  thread::spawn(move || {
+    for stream in listener.incoming().take(connections) {
+      let mut stream = stream.unwrap();
+      let mut len = [0u8; 4];
+      stream.read_exact(&mut len).unwrap();
+      let mut message = vec![0u8; u32::from_be_bytes(len) as usize];
+      stream.read_exact(&mut message).unwrap();
+
+      let mut reply = Vec::new();
+      match message[0] {
+        11 => {
+          let mut rsa_blob = Vec::new();
+          push_string(&mut rsa_blob, b"ssh-rsa");
+          push_string(&mut rsa_blob, &[1, 0, 1]);
+
+          reply.push(12);
+          reply.extend_from_slice(&2u32.to_be_bytes());
+          push_string(&mut reply, &rsa_blob);
+          push_string(&mut reply, b"old-rsa");
+          push_string(&mut reply, &ed25519_blob(&key));
+          push_string(&mut reply, b"dev@laptop");
+        }
+        13 => {
+          let mut body = &message[1..];
+          assert_eq!(read_string(&mut body), ed25519_blob(&key));
+          let data = read_string(&mut body);
+
+          let mut signature = Vec::new();
+          push_string(&mut signature, b"ssh-ed25519");
+          push_string(&mut signature, &key.sign(&data).to_bytes());
+          reply.push(14);
+          push_string(&mut reply, &signature);
+        }
+        _ => reply.push(5),
+      }
+
+      let mut framed = Vec::new();
+      push_string(&mut framed, &reply);
+      stream.write_all(&framed).unwrap();
+    }
+  })
+}
+
+#[test]
+fn test_agent_signer_selects_identity_and_signs() {
+  let temp_dir = common::create_temp_dir();
+  let socket = temp_dir.path().join("agent.sock");
+  let key = SigningKey::from_bytes(&[7u8; 32]);
+  let public_key = Auth::encode_public_key(&key.verifying_key());
+  let fingerprint = Auth::fingerprint(&public_key).unwrap();
+  let agent = spawn_agent(socket.clone(), key, 4);
+
+  // Select by comment, then by fingerprint
+  let signer = AgentSigner::with_socket(socket.clone(), Some("dev@laptop")).unwrap();
+  assert_eq!(signer.public_key(), public_key);
+  assert_eq!(signer.identity().fingerprint, fingerprint);
+
+  let signature = signer.sign(b"envelope").unwrap();
+  assert!(Auth::verify_signature(&public_key, b"envelope", &signature).unwrap());
+
+  let signer = AgentSigner::with_socket(socket.clone(), Some(&fingerprint)).unwrap();
+  assert_eq!(signer.identity().comment, "dev@laptop");
+
+  // Unknown selectors list the identities the agent offers
+  let error = AgentSigner::with_socket(socket, Some("old-rsa"))
+    .err()
+    .unwrap();
+  assert!(error.to_string().contains(&fingerprint));
+
+  agent.join().unwrap();
+}
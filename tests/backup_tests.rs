@@ -0,0 +1,368 @@
+//! Backup archive and retention tests
+
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+use adeploy::{
+  backup::{self, BackupManifest, DeploymentRecord, RetentionPolicy},
+  config::ServerPackageConfig,
+  deploy_lock::PathLock,
+  deploy_log::DeployReporter,
+  server,
+};
+use chrono::{Duration, TimeZone, Utc};
+
+mod common;
+
+/// Create `backup_<timestamp>` snapshots holding `size` bytes each
+fn create_backups(dir: &Path, timestamps: &[&str], size: usize) {
+  for timestamp in timestamps {
+    let snapshot = dir.join(format!("backup_{}", timestamp));
+    fs::create_dir_all(snapshot.join("assets")).unwrap();
+    fs::write(snapshot.join("assets/app.js"), vec![b'x'; size]).unwrap();
+  }
+}
+
+fn remaining(dir: &Path) -> Vec<String> {
+  backup::list_backups(dir)
+    .unwrap()
+    .into_iter()
+    .map(|backup| backup.name)
+    .collect()
+}
+
+#[test]
+fn test_retention_limits_keep_newest_backups() {
+  let temp_dir = common::create_temp_dir();
+  let dir = temp_dir.path();
+  create_backups(
+    dir,
+    &[
+      "20250101_000000",
+      "20250201_000000",
+      "20250301_000000",
+      "20250401_000000",
+      "20250501_000000",
+    ],
+    1000,
+  );
+  fs::write(dir.join("notes.txt"), "not a backup").unwrap();
+  let now = Utc.with_ymd_and_hms(2025, 5, 10, 0, 0, 0).unwrap();
+
+  let listed = backup::list_backups(dir).unwrap();
+  assert_eq!(listed.len(), 5);
+  assert_eq!(listed[0].name, "backup_20250501_000000");
+  assert_eq!(listed[0].size, 1000);
+
+  let by_count = RetentionPolicy {
+    keep_count: Some(4),
+    ..Default::default()
+  };
+  let removed = backup::prune_backups(dir, &by_count, now).unwrap();
+  assert_eq!(removed.len(), 1);
+  assert_eq!(removed[0].name, "backup_20250101_000000");
+
+  let by_age = RetentionPolicy {
+    max_age: Some(Duration::days(60)),
+    ..Default::default()
+  };
+  backup::prune_backups(dir, &by_age, now).unwrap();
+  assert_eq!(
+    remaining(dir),
+    ["backup_20250501_000000", "backup_20250401_000000"]
+  );
+
+  // The newest backup survives even when it alone breaks every limit
+  let strict = RetentionPolicy {
+    keep_count: Some(0),
+    max_age: Some(Duration::seconds(1)),
+    max_total_size: Some(10),
+  };
+  backup::prune_backups(dir, &strict, now).unwrap();
+  assert_eq!(remaining(dir), ["backup_20250501_000000"]);
+  assert!(dir.join("notes.txt").exists());
+}
+
+#[test]
+fn test_retention_settings_accept_units() {
+  let config: ServerPackageConfig = toml::from_str(
+    r#"deploy_path = "/srv/app"
+backup_keep_count = 10
+backup_max_age = "30d"
+backup_max_total_size = "5G"
+"#,
+  )
+  .unwrap();
+  assert_eq!(config.backup_max_age, Some(30 * 24 * 60 * 60));
+  assert_eq!(config.backup_max_total_size, Some(5 * 1024 * 1024 * 1024));
+  let policy = RetentionPolicy::from_config(&config);
+  assert_eq!(policy.keep_count, Some(10));
+  assert_eq!(policy.max_age, Some(Duration::days(30)));
+
+  let config: ServerPackageConfig = toml::from_str(
+    "deploy_path = \"/srv/app\"\nbackup_max_age = 3600\nbackup_max_total_size = \"512MiB\"\n",
+  )
+  .unwrap();
+  assert_eq!(config.backup_max_age, Some(3600));
+  assert_eq!(config.backup_max_total_size, Some(512 * 1024 * 1024));
+  assert!(
+    !RetentionPolicy::from_config(&toml::from_str("deploy_path = \"/srv\"").unwrap()).is_bounded()
+  );
+
+  // Ages beyond what a Duration holds mean no age limit rather than a crash
+  let unbounded: ServerPackageConfig =
+    toml::from_str("deploy_path = \"/srv/app\"\nbackup_max_age = \"99999999999999w\"\n").unwrap();
+  assert_eq!(RetentionPolicy::from_config(&unbounded).max_age, None);
+
+  let invalid: Result<ServerPackageConfig, _> =
+    toml::from_str("deploy_path = \"/srv/app\"\nbackup_max_age = \"30 days\"\n");
+  assert!(invalid.is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_backup_archive_preserves_metadata_and_manifest() {
+  use std::{
+    os::unix::fs::{symlink, PermissionsExt},
+    time::{Duration as StdDuration, SystemTime},
+  };
+
+  let temp_dir = common::create_temp_dir();
+  let live = temp_dir.path().join("app");
+  fs::create_dir_all(live.join("bin")).unwrap();
+  fs::write(live.join("bin/server"), "#!/bin/sh\n").unwrap();
+  fs::set_permissions(live.join("bin/server"), fs::Permissions::from_mode(0o750)).unwrap();
+  let mtime = SystemTime::UNIX_EPOCH + StdDuration::from_secs(1_700_000_000);
+  fs::File::options()
+    .write(true)
+    .open(live.join("bin/server"))
+    .unwrap()
+    .set_modified(mtime)
+    .unwrap();
+  symlink("bin/server", live.join("start")).unwrap();
+  let record = DeploymentRecord {
+    deploy_id: "d-1".to_string(),
+    version: "1.4.2".to_string(),
+    deployed_at: Utc.with_ymd_and_hms(2025, 6, 1, 12, 0, 0).unwrap(),
+  };
+  record.save(&live).unwrap();
+  assert_eq!(DeploymentRecord::load(&live), Some(record.clone()));
+
+  let manifest = BackupManifest {
+    package: "app".to_string(),
+    created_at: Utc.with_ymd_and_hms(2025, 6, 2, 8, 30, 0).unwrap(),
+    source: live.clone(),
+    deployment: Some(record),
+  };
+  let archive = temp_dir
+    .path()
+    .join(backup::backup_name(manifest.created_at));
+  assert!(archive.to_string_lossy().ends_with(".tar.gz"));
+  backup::create_backup(&live, &archive, &manifest)
+    .unwrap()
+    .unwrap();
+  assert_eq!(backup::read_manifest(&archive).unwrap(), Some(manifest));
+
+  let listed = backup::list_backups(temp_dir.path()).unwrap();
+  assert_eq!(listed.len(), 1);
+  assert_eq!(
+    listed[0].created,
+    Utc.with_ymd_and_hms(2025, 6, 2, 8, 30, 0).unwrap()
+  );
+
+  let restored = temp_dir.path().join("restored");
+  fs::create_dir(&restored).unwrap();
+  backup::restore_backup(&archive, &restored).unwrap();
+  let server = fs::metadata(restored.join("bin/server")).unwrap();
+  assert_eq!(server.permissions().mode() & 0o777, 0o750);
+  assert_eq!(server.modified().unwrap(), mtime);
+  assert_eq!(
+    fs::read_link(restored.join("start")).unwrap(),
+    Path::new("bin/server")
+  );
+  // Bookkeeping files are described by the manifest rather than restored
+  assert!(!restored.join(backup::DEPLOYMENT_RECORD).exists());
+  assert!(!restored.join(backup::MANIFEST_ENTRY).exists());
+
+  // Nothing to back up yields no archive
+  let missing = temp_dir.path().join("missing");
+  let other = temp_dir.path().join("other.tar.gz");
+  let empty_manifest = BackupManifest {
+    source: missing.clone(),
+    deployment: None,
+    ..backup::read_manifest(&archive).unwrap().unwrap()
+  };
+  assert!(backup::create_backup(&missing, &other, &empty_manifest)
+    .unwrap()
+    .is_none());
+  assert!(!other.exists());
+}
+
+#[test]
+fn test_find_backup_by_name_or_latest() {
+  let temp_dir = common::create_temp_dir();
+  let dir = temp_dir.path();
+  assert!(backup::find_backup(dir, None)
+    .unwrap_err()
+    .to_string()
+    .contains("No backups found"));
+
+  let time = Utc.with_ymd_and_hms(2025, 3, 1, 9, 0, 0).unwrap();
+  create_backups(dir, &["20250201_000000"], 10);
+  let first = backup::new_backup_path(dir, time);
+  fs::write(&first, "archive").unwrap();
+  // A second backup within the same second gets a numbered name and counts as newer
+  let second = backup::new_backup_path(dir, time);
+  assert_ne!(first, second);
+  fs::write(&second, "archive").unwrap();
+
+  let latest = backup::find_backup(dir, None).unwrap();
+  assert_eq!(latest.path, second);
+  assert_eq!(latest.created, time);
+  let named = backup::find_backup(dir, Some("backup_20250301_090000")).unwrap();
+  assert_eq!(named.path, first);
+  let legacy = backup::find_backup(dir, Some("backup_20250201_000000")).unwrap();
+  assert!(legacy.path.is_dir());
+  assert!(backup::find_backup(dir, Some("backup_20990101_000000"))
+    .unwrap_err()
+    .to_string()
+    .contains("not found"));
+}
+
+/// A package whose only backup holds `index.html` = "v1" while "v2" is live. `settings` are
+/// appended to its configuration, which backs up into `<dir>/backups`.
+fn restorable_package(dir: &Path, settings: &str) -> (PathBuf, ServerPackageConfig) {
+  let live = dir.join("app");
+  let backups = dir.join("backups");
+  fs::create_dir_all(&live).unwrap();
+  fs::create_dir_all(&backups).unwrap();
+  fs::write(live.join("index.html"), "v1").unwrap();
+
+  let old = DeploymentRecord {
+    deploy_id: "d-old".to_string(),
+    version: "1.0.0".to_string(),
+    deployed_at: Utc.with_ymd_and_hms(2025, 6, 1, 12, 0, 0).unwrap(),
+  };
+  let manifest = BackupManifest {
+    package: "app".to_string(),
+    created_at: Utc.with_ymd_and_hms(2025, 6, 2, 8, 30, 0).unwrap(),
+    source: live.clone(),
+    deployment: Some(old),
+  };
+  backup::create_backup(
+    &live,
+    &backups.join(backup::backup_name(manifest.created_at)),
+    &manifest,
+  )
+  .unwrap();
+
+  // A newer deployment is live when the restore runs
+  fs::write(live.join("index.html"), "v2").unwrap();
+  DeploymentRecord {
+    deploy_id: "d-new".to_string(),
+    version: "2.0.0".to_string(),
+    deployed_at: Utc.with_ymd_and_hms(2025, 6, 3, 12, 0, 0).unwrap(),
+  }
+  .save(&live)
+  .unwrap();
+
+  let config = toml::from_str(&format!(
+    "deploy_path = \"{}\"\nbackup_path = \"{}\"\n{}",
+    common::toml_escape_path(&live),
+    common::toml_escape_path(&backups),
+    settings
+  ))
+  .unwrap();
+  (live, config)
+}
+
+#[tokio::test]
+async fn test_local_restore_takes_deploy_lock_and_records_deployment() {
+  let temp_dir = common::create_temp_dir();
+  let (live, config) = restorable_package(temp_dir.path(), "backup_enabled = false\n");
+
+  // A deployment running in the server process holds the deploy path
+  let held = PathLock::acquire(&live, "app", "d-rpc", std::time::Duration::ZERO)
+    .await
+    .unwrap()
+    .unwrap();
+  let mut reporter = DeployReporter::new();
+  let busy = server::restore_package(
+    &config,
+    "app",
+    None,
+    std::time::Duration::ZERO,
+    &mut reporter,
+  )
+  .await
+  .unwrap_err();
+  assert!(busy.to_string().contains("d-rpc"));
+  assert_eq!(fs::read_to_string(live.join("index.html")).unwrap(), "v2");
+  drop(held);
+
+  let deploy_id = server::restore_package(
+    &config,
+    "app",
+    None,
+    std::time::Duration::ZERO,
+    &mut reporter,
+  )
+  .await
+  .unwrap();
+  assert_eq!(fs::read_to_string(live.join("index.html")).unwrap(), "v1");
+  let record = DeploymentRecord::load(&live).unwrap();
+  assert_eq!(record.deploy_id, deploy_id);
+  assert_eq!(record.version, "1.0.0");
+}
+
+#[tokio::test]
+async fn test_failing_before_backup_hook_aborts_without_rollback() {
+  let temp_dir = common::create_temp_dir();
+  let (live, config) = restorable_package(
+    temp_dir.path(),
+    "backup_enabled = true\nrollback_on_failure = true\n[hooks]\nbefore_backup = [\"exit 3\"]\n",
+  );
+
+  let mut reporter = DeployReporter::new();
+  let error = server::restore_package(
+    &config,
+    "app",
+    None,
+    std::time::Duration::ZERO,
+    &mut reporter,
+  )
+  .await
+  .unwrap_err();
+  assert!(error.to_string().contains("exit code: 3"));
+
+  // The hook failed before the backup, so nothing changed and nothing was rolled back
+  let messages = reporter
+    .into_entries()
+    .into_iter()
+    .map(|entry| entry.message)
+    .collect::<Vec<_>>();
+  assert!(messages
+    .iter()
+    .any(|message| message.starts_with("before_backup hook failed")));
+  assert!(!messages
+    .iter()
+    .any(|message| message.contains("Restore failed") || message.contains("Rolling back")));
+  assert_eq!(fs::read_to_string(live.join("index.html")).unwrap(), "v2");
+  assert_eq!(
+    backup::list_backups(&temp_dir.path().join("backups"))
+      .unwrap()
+      .len(),
+    1
+  );
+
+  // There is nothing to roll back before a backup, so the policy is refused outright
+  let rejected: Result<ServerPackageConfig, _> = toml::from_str(
+    "deploy_path = \"/srv/app\"\n[hooks]\nbefore_backup = [{ command = \"true\", failure = \"rollback\" }]\n",
+  );
+  assert!(rejected
+    .unwrap_err()
+    .to_string()
+    .contains("cannot use failure = \"rollback\""));
+}
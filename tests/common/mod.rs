@@ -1,7 +1,11 @@
 //! Common test utilities and helpers
 
-use std::path::Path;
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
 
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
 use tempfile::TempDir;
 use tokio::net::TcpListener;
 
@@ -25,3 +29,50 @@ pub async fn find_available_port() -> u16 {
 pub fn toml_escape_path(path: &Path) -> String {
   path.to_string_lossy().replace('\\', "\\\\")
 }
+
+/// PEM files for a throwaway CA plus server and client certificates.
+#[allow(dead_code)]
+pub struct TlsFixture {
+  pub ca_cert: PathBuf,
+  pub server_cert: PathBuf,
+  pub server_key: PathBuf,
+  pub client_cert: PathBuf,
+  pub client_key: PathBuf,
+}
+
+/// Generate a CA that signs a `localhost` server certificate and a client certificate.
+#[allow(dead_code)]
+pub fn write_tls_fixture(dir: &Path) -> TlsFixture {
+  let ca_key = KeyPair::generate().expect("Failed to generate CA key");
+  let mut ca_params = CertificateParams::new(Vec::<String>::new()).expect("Invalid CA params");
+  ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+  let ca = CertifiedIssuer::self_signed(ca_params, ca_key).expect("Failed to self-sign CA");
+
+  let server_key = KeyPair::generate().expect("Failed to generate server key");
+  let server_cert = CertificateParams::new(vec!["localhost".to_string()])
+    .expect("Invalid server params")
+    .signed_by(&server_key, &ca)
+    .expect("Failed to sign server certificate");
+
+  let client_key = KeyPair::generate().expect("Failed to generate client key");
+  let client_cert = CertificateParams::new(vec!["adeploy-client".to_string()])
+    .expect("Invalid client params")
+    .signed_by(&client_key, &ca)
+    .expect("Failed to sign client certificate");
+
+  let fixture = TlsFixture {
+    ca_cert: dir.join("ca.pem"),
+    server_cert: dir.join("server.pem"),
+    server_key: dir.join("server.key"),
+    client_cert: dir.join("client.pem"),
+    client_key: dir.join("client.key"),
+  };
+
+  fs::write(&fixture.ca_cert, ca.pem()).expect("Failed to write CA certificate");
+  fs::write(&fixture.server_cert, server_cert.pem()).expect("Failed to write server certificate");
+  fs::write(&fixture.server_key, server_key.serialize_pem()).expect("Failed to write server key");
+  fs::write(&fixture.client_cert, client_cert.pem()).expect("Failed to write client certificate");
+  fs::write(&fixture.client_key, client_key.serialize_pem()).expect("Failed to write client key");
+
+  fixture
+}
@@ -5,6 +5,8 @@ use std::{
   path::{Path, PathBuf},
 };
 
+use adeploy::auth::Auth;
+
 use crate::common::toml_escape_path;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -21,6 +23,24 @@ pub enum ServerScenarioKind {
   MissingPackage,
   /// Client public key is not on the allow list.
   UnauthorizedKey,
+  /// Named key is only granted other packages.
+  PackageNotPermitted,
+  /// Package-level allowed_keys grants a key restricted to other packages.
+  PackageKeyOverride,
+  /// Named key is past its expiry date.
+  ExpiredKey,
+  /// Named key is listed in the revoked keys file.
+  RevokedKey,
+  /// Client key is only listed in an OpenSSH authorized_keys file.
+  AuthorizedKeysFile,
+  /// Package deploys into `releases/<deploy_id>` behind a `current` symlink.
+  ReleaseLayout,
+  /// After-deploy script fails and the package rolls back to its backup.
+  RollbackOnFailure,
+  /// Health check never passes and the package rolls back to its backup.
+  HealthCheckFailure,
+  /// Hook lists with per-hook failure policies; a `rollback` hook fails the upgrade.
+  HookPolicies,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -62,6 +82,51 @@ const SERVER_SCENARIOS: &[ServerScenario] = &[
     name: "server_unauthorized_key",
     description: "Client public key is not allowed",
   },
+  ServerScenario {
+    kind: ServerScenarioKind::PackageNotPermitted,
+    name: "server_package_not_permitted",
+    description: "Named key is restricted to other packages",
+  },
+  ServerScenario {
+    kind: ServerScenarioKind::PackageKeyOverride,
+    name: "server_package_key_override",
+    description: "Package allowed_keys overrides the key's package grants",
+  },
+  ServerScenario {
+    kind: ServerScenarioKind::ExpiredKey,
+    name: "server_expired_key",
+    description: "Named key expired before the request",
+  },
+  ServerScenario {
+    kind: ServerScenarioKind::RevokedKey,
+    name: "server_revoked_key",
+    description: "Named key appears in the revocation list",
+  },
+  ServerScenario {
+    kind: ServerScenarioKind::AuthorizedKeysFile,
+    name: "server_authorized_keys_file",
+    description: "Client key is granted through an OpenSSH authorized_keys file",
+  },
+  ServerScenario {
+    kind: ServerScenarioKind::ReleaseLayout,
+    name: "server_release_layout",
+    description: "Release directories with an atomically switched current symlink",
+  },
+  ServerScenario {
+    kind: ServerScenarioKind::RollbackOnFailure,
+    name: "server_rollback_on_failure",
+    description: "After hook fails and the pre-deploy backup is restored",
+  },
+  ServerScenario {
+    kind: ServerScenarioKind::HealthCheckFailure,
+    name: "server_health_check_failure",
+    description: "Health check fails after retries and the pre-deploy backup is restored",
+  },
+  ServerScenario {
+    kind: ServerScenarioKind::HookPolicies,
+    name: "server_hook_policies",
+    description: "A warning hook is tolerated, then a rollback hook restores the backup",
+  },
 ];
 
 /// All available server scenarios.
@@ -142,14 +207,14 @@ touch '{}'
 
   let post_script_content = if windows {
     match scenario {
-      PostDeployScriptFailure => {
+      PostDeployScriptFailure | RollbackOnFailure => {
         "@echo off\r\necho post hook failed 1>&2\r\nexit /B 1\r\n".to_string()
       }
       _ => format!("@echo off\r\ntype nul > \"{}\"\r\n", post_marker.display()),
     }
   } else {
     match scenario {
-      PostDeployScriptFailure => r"#!/bin/sh
+      PostDeployScriptFailure | RollbackOnFailure => r"#!/bin/sh
 echo 'post hook failed' >&2
 exit 1
 "
@@ -179,10 +244,92 @@ touch '{}'
     _ => package_name,
   };
 
-  let allowed_key_entry = if matches!(scenario, UnauthorizedKey) {
-    "invalid-test-key".to_string()
-  } else {
-    public_key.to_string()
+  let allowed_key_entry = match scenario {
+    UnauthorizedKey | AuthorizedKeysFile => "\"invalid-test-key\"".to_string(),
+    PackageNotPermitted | PackageKeyOverride => format!(
+      r#"{{ name = "ci-frontend", key = "{}", packages = ["frontend-*"] }}"#,
+      public_key
+    ),
+    ExpiredKey => format!(
+      r#"{{ name = "ci-legacy", key = "{}", expires_at = 2020-01-01T00:00:00Z }}"#,
+      public_key
+    ),
+    RevokedKey => format!(
+      r#"{{ name = "ci-leaked", key = "{}", not_before = "2020-01-01" }}"#,
+      public_key
+    ),
+    _ => format!("\"{}\"", public_key),
+  };
+
+  let package_allowed_keys = match scenario {
+    PackageKeyOverride => "allowed_keys = [\"ci-frontend\"]\n",
+    _ => "",
+  };
+
+  let key_file_line = match scenario {
+    RevokedKey => {
+      let revoked_path = server_dir.join("revoked_keys");
+      fs::write(
+        &revoked_path,
+        format!("# leaked on 2024-05-01\n{}\n", public_key),
+      )
+      .expect("Failed to write revoked keys file");
+      format!(
+        "revoked_keys_file = \"{}\"\n",
+        toml_escape_path(&revoked_path)
+      )
+    }
+    AuthorizedKeysFile => {
+      let key = Auth::parse_public_key(public_key).expect("Invalid client public key");
+      let authorized_keys_path = server_dir.join("authorized_keys");
+      fs::write(
+        &authorized_keys_path,
+        format!(
+          "# team keys\nssh-rsa AAAAB3NzaC1yc2E= someone@else\nrestrict {}\n",
+          Auth::format_openssh_public_key(&key, Some("deploy@ci"))
+        ),
+      )
+      .expect("Failed to write authorized keys file");
+      format!(
+        "authorized_keys_file = \"{}\"\n",
+        toml_escape_path(&authorized_keys_path)
+      )
+    }
+    _ => String::new(),
+  };
+
+  let layout_settings = match scenario {
+    ReleaseLayout => "layout = \"releases\"\nkeep_releases = 2\n".to_string(),
+    RollbackOnFailure => "rollback_on_failure = true\n".to_string(),
+    HealthCheckFailure => format!(
+      r#"rollback_on_failure = true
+
+[packages.{}.health_check]
+command = "exit 1"
+retries = 1
+interval_secs = 0
+"#,
+      package_name
+    ),
+    HookPolicies => format!(
+      r#"
+[packages.{package}.hooks]
+on_first_install = ['{first_install}']
+on_upgrade = ['{upgrade}']
+after_deploy = [
+  {{ command = "exit 1", failure = "warn" }},
+  {{ command = "exit 2", failure = "rollback" }},
+]
+on_rollback = ['{rollback}']
+on_failure = ['{failure}']
+"#,
+      package = package_name,
+      first_install = touch_command(&server_dir.join("first_install.marker")),
+      upgrade = touch_command(&server_dir.join("upgrade.marker")),
+      rollback = touch_command(&server_dir.join("rollback.marker")),
+      failure = touch_command(&server_dir.join("failure.marker")),
+    ),
+    _ => String::new(),
   };
 
   let backup_enabled = !matches!(scenario, BackupDisabled);
@@ -192,24 +339,29 @@ touch '{}'
 port = {port}
 max_file_size = 1048576
 allowed_keys = [
-  "{allowed_key}"
+  {allowed_key}
 ]
-
+nonce_cache_path = "{nonce_cache}"
+{key_file_line}
 [packages.{package}]
 deploy_path = "{deploy_path}"
 backup_enabled = {backup_enabled}
 backup_path = "{backup_path}"
 before_deploy_script = "{pre_script}"
 after_deploy_script = "{post_script}"
-"#,
+{package_allowed_keys}{layout_settings}"#,
     port = port,
     allowed_key = allowed_key_entry,
+    nonce_cache = toml_escape_path(&server_dir.join("nonce_cache.json")),
+    key_file_line = key_file_line,
     package = configured_package_name,
     deploy_path = toml_escape_path(&deploy_path),
     backup_enabled = backup_enabled,
     backup_path = toml_escape_path(&backup_path),
     pre_script = toml_escape_path(&pre_script_path),
     post_script = toml_escape_path(&post_script_path),
+    package_allowed_keys = package_allowed_keys,
+    layout_settings = layout_settings,
   );
 
   let config_path = server_dir.join("server_config.toml");
@@ -217,3 +369,12 @@ after_deploy_script = "{post_script}"
 
   config_path
 }
+
+/// Shell command creating an empty file, for use inside a TOML literal string
+fn touch_command(path: &Path) -> String {
+  if cfg!(target_os = "windows") {
+    format!("type nul > \"{}\"", path.display())
+  } else {
+    format!("touch \"{}\"", path.display())
+  }
+}
@@ -0,0 +1,124 @@
+//! Deployment lock tests
+
+use std::time::Duration;
+
+use adeploy::{
+  config::{DeployLockScope, ServerConfig},
+  deploy_lock::{DeployLocks, LockBusy, PathLock},
+};
+
+mod common;
+
+#[tokio::test]
+async fn test_busy_lock_rejects_with_in_flight_deploy_id() {
+  let locks = DeployLocks::new();
+  let first = locks
+    .acquire("web", "web", "deploy-1", Duration::ZERO)
+    .await
+    .unwrap();
+
+  let busy = locks
+    .acquire("web", "web", "deploy-2", Duration::ZERO)
+    .await
+    .err()
+    .unwrap();
+  assert_eq!(
+    busy,
+    LockBusy {
+      package_name: "web".to_string(),
+      deploy_id: "deploy-1".to_string(),
+    }
+  );
+
+  // Other packages are not held up, and a released lock can be taken again
+  let other = locks
+    .acquire("api", "api", "deploy-3", Duration::ZERO)
+    .await;
+  assert!(other.is_ok());
+  drop(first);
+  assert!(locks
+    .acquire("web", "web", "deploy-2", Duration::ZERO)
+    .await
+    .is_ok());
+}
+
+#[tokio::test]
+async fn test_queued_deployment_runs_once_lock_is_released() {
+  let locks = DeployLocks::new();
+  let first = locks
+    .acquire("", "web", "deploy-1", Duration::ZERO)
+    .await
+    .unwrap();
+
+  // The queue gives up when the holder outlasts the wait
+  let timed_out = locks
+    .acquire("", "api", "deploy-2", Duration::from_millis(50))
+    .await;
+  assert_eq!(timed_out.err().unwrap().deploy_id, "deploy-1");
+
+  let release = tokio::spawn(async move {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(first);
+  });
+  let queued = locks
+    .acquire("", "api", "deploy-2", Duration::from_secs(5))
+    .await;
+  assert!(queued.is_ok());
+  release.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_path_lock_excludes_other_holders_of_the_deploy_path() {
+  let temp_dir = common::create_temp_dir();
+  let deploy_path = temp_dir.path().join("app");
+
+  // The lock file sits beside deploy_path and does not create it
+  let first = PathLock::acquire(&deploy_path, "web", "deploy-1", Duration::ZERO)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(
+    PathLock::lock_file(&deploy_path),
+    temp_dir.path().join(".app.adeploy-lock")
+  );
+  assert!(PathLock::lock_file(&deploy_path).exists());
+  assert!(!deploy_path.exists());
+
+  // Each acquisition opens the file anew, just as another process would
+  let busy = PathLock::acquire(&deploy_path, "web", "deploy-2", Duration::from_millis(50))
+    .await
+    .unwrap()
+    .err()
+    .unwrap();
+  assert_eq!(
+    busy,
+    LockBusy {
+      package_name: "web".to_string(),
+      deploy_id: "deploy-1".to_string(),
+    }
+  );
+
+  let release = tokio::spawn(async move {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(first);
+  });
+  let queued = PathLock::acquire(&deploy_path, "web", "deploy-2", Duration::from_secs(5)).await;
+  assert!(queued.unwrap().is_ok());
+  release.await.unwrap();
+}
+
+#[test]
+fn test_deploy_lock_settings_default_to_package_scope() {
+  let base = "[server]\nport = 6060\nmax_file_size = 0\nallowed_keys = []\n[packages]\n";
+  let config: ServerConfig = toml::from_str(base).unwrap();
+  assert_eq!(config.server.deploy_lock, DeployLockScope::Package);
+  assert_eq!(config.server.deploy_lock_wait_secs, 0);
+
+  let config: ServerConfig = toml::from_str(&base.replace(
+    "allowed_keys = []\n",
+    "allowed_keys = []\ndeploy_lock = \"global\"\ndeploy_lock_wait_secs = 30\n",
+  ))
+  .unwrap();
+  assert_eq!(config.server.deploy_lock, DeployLockScope::Global);
+  assert_eq!(config.server.deploy_lock_wait_secs, 30);
+}
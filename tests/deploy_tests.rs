@@ -0,0 +1,708 @@
+//! Server-side extraction tests that drive the deploy manager directly
+
+use std::{
+  collections::HashMap,
+  fs,
+  io::{self, Write},
+  path::Path,
+  time::{Duration, Instant, SystemTime},
+};
+
+use adeploy::{
+  backup::DeploymentRecord,
+  config::ServerPackageConfig,
+  deploy::{DeployManager, SpooledArchive},
+  deploy_log::DeployReporter,
+  hooks::HookStage,
+};
+use flate2::{write::GzEncoder, Compression};
+use tempfile::NamedTempFile;
+
+mod common;
+
+fn package_config(deploy_path: &Path) -> ServerPackageConfig {
+  toml::from_str(&format!(
+    "deploy_path = \"{}\"\n",
+    common::toml_escape_path(deploy_path)
+  ))
+  .unwrap()
+}
+
+/// Gzipped tar archive holding `files` as (path, contents) pairs
+fn build_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+  let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+  for (path, contents) in files {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append_data(&mut header, path, *contents).unwrap();
+  }
+  tar.into_inner().unwrap().finish().unwrap()
+}
+
+fn spool(bytes: &[u8]) -> SpooledArchive {
+  let mut file = NamedTempFile::new().unwrap();
+  file.write_all(bytes).unwrap();
+  SpooledArchive {
+    size: bytes.len() as u64,
+    path: file.into_temp_path(),
+  }
+}
+
+/// Names of hidden staging or undo directories left beside or inside the deploy path
+fn leftovers(deploy_path: &Path) -> Vec<String> {
+  [deploy_path, deploy_path.parent().unwrap()]
+    .iter()
+    .flat_map(|dir| fs::read_dir(dir).unwrap())
+    .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+    .filter(|name| name.contains(".adeploy-"))
+    .collect()
+}
+
+#[tokio::test]
+async fn test_in_place_extraction_is_staged_and_committed() {
+  let temp_dir = common::create_temp_dir();
+  let deploy_path = temp_dir.path().join("app");
+  fs::create_dir_all(&deploy_path).unwrap();
+  fs::write(deploy_path.join("keep.txt"), "untouched").unwrap();
+  fs::write(deploy_path.join("app.txt"), "old").unwrap();
+  // A file where the archive now ships a directory is replaced
+  fs::write(deploy_path.join("lib"), "old file").unwrap();
+
+  let config = package_config(&deploy_path);
+  let manager = DeployManager::new();
+  let archive = spool(&build_archive(&[
+    ("app.txt", b"new"),
+    ("lib/module.txt", b"module"),
+  ]));
+  manager
+    .extract_files(&archive, &config, "app", &mut DeployReporter::default())
+    .await
+    .unwrap();
+
+  assert_eq!(
+    fs::read_to_string(deploy_path.join("app.txt")).unwrap(),
+    "new"
+  );
+  assert_eq!(
+    fs::read_to_string(deploy_path.join("lib/module.txt")).unwrap(),
+    "module"
+  );
+  assert_eq!(
+    fs::read_to_string(deploy_path.join("keep.txt")).unwrap(),
+    "untouched"
+  );
+  assert!(leftovers(&deploy_path).is_empty());
+
+  // An archive that breaks off mid-stream never touches the live directory
+  let large: Vec<u8> = (0..512 * 1024u32).map(|i| (i * 7919 % 251) as u8).collect();
+  let mut truncated = build_archive(&[("app.txt", b"newer"), ("large.bin", &large)]);
+  truncated.truncate(truncated.len() / 2);
+  let manager = DeployManager::new();
+  let result = manager
+    .extract_files(
+      &spool(&truncated),
+      &config,
+      "app",
+      &mut DeployReporter::default(),
+    )
+    .await;
+
+  assert!(result.is_err());
+  assert_eq!(
+    fs::read_to_string(deploy_path.join("app.txt")).unwrap(),
+    "new"
+  );
+  assert!(!deploy_path.join("large.bin").exists());
+  assert!(leftovers(&deploy_path).is_empty());
+}
+
+#[tokio::test]
+async fn test_rollback_restores_pre_deploy_backup() {
+  let temp_dir = common::create_temp_dir();
+  let deploy_path = temp_dir.path().join("app");
+  fs::create_dir_all(deploy_path.join("assets")).unwrap();
+  fs::write(deploy_path.join("app.txt"), "old").unwrap();
+  fs::write(deploy_path.join("assets/logo.svg"), "<svg/>").unwrap();
+
+  let config: ServerPackageConfig = toml::from_str(&format!(
+    "deploy_path = \"{}\"\nbackup_enabled = true\nbackup_path = \"{}\"\nrollback_on_failure = true\n",
+    common::toml_escape_path(&deploy_path),
+    common::toml_escape_path(&temp_dir.path().join("backups")),
+  ))
+  .unwrap();
+  let manager = DeployManager::new();
+  let archive = spool(&build_archive(&[
+    ("app.txt", b"new"),
+    ("assets/extra.css", b"body {}"),
+  ]));
+  let mut reporter = DeployReporter::default();
+  manager
+    .extract_files(&archive, &config, "app", &mut reporter)
+    .await
+    .unwrap();
+  assert!(deploy_path.join("assets/extra.css").exists());
+
+  manager
+    .rollback(&config, "app", &mut reporter)
+    .await
+    .unwrap();
+
+  assert_eq!(
+    fs::read_to_string(deploy_path.join("app.txt")).unwrap(),
+    "old"
+  );
+  assert!(deploy_path.join("assets/logo.svg").exists());
+  assert!(!deploy_path.join("assets/extra.css").exists());
+  assert!(leftovers(&deploy_path).is_empty());
+  assert!(reporter
+    .into_entries()
+    .iter()
+    .any(|entry| entry.message.starts_with("Restoring backup")));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_release_rollback_switches_back_to_previous_release() {
+  let temp_dir = common::create_temp_dir();
+  let deploy_path = temp_dir.path().join("app");
+  let config: ServerPackageConfig = toml::from_str(&format!(
+    "deploy_path = \"{}\"\nlayout = \"releases\"\nrollback_on_failure = true\n",
+    common::toml_escape_path(&deploy_path),
+  ))
+  .unwrap();
+  let current = deploy_path.join("current");
+
+  let first = DeployManager::new();
+  let mut reporter = DeployReporter::default();
+  first
+    .extract_files(
+      &spool(&build_archive(&[("app.txt", b"v1")])),
+      &config,
+      "app",
+      &mut reporter,
+    )
+    .await
+    .unwrap();
+  first
+    .activate_release(&config, &mut reporter)
+    .await
+    .unwrap();
+  first.prune_releases(&config, &mut reporter).await;
+
+  // The second release goes live, then fails its health check
+  let second = DeployManager::new();
+  second
+    .extract_files(
+      &spool(&build_archive(&[("app.txt", b"v2")])),
+      &config,
+      "app",
+      &mut reporter,
+    )
+    .await
+    .unwrap();
+  second
+    .activate_release(&config, &mut reporter)
+    .await
+    .unwrap();
+  assert_eq!(fs::read_to_string(current.join("app.txt")).unwrap(), "v2");
+
+  second
+    .rollback(&config, "app", &mut reporter)
+    .await
+    .unwrap();
+  assert_eq!(fs::read_to_string(current.join("app.txt")).unwrap(), "v1");
+  assert!(!deploy_path
+    .join("releases")
+    .join(second.release_name())
+    .exists());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_release_pruning_follows_deploy_order_not_mtime() {
+  let temp_dir = common::create_temp_dir();
+  let deploy_path = temp_dir.path().join("app");
+  let config: ServerPackageConfig = toml::from_str(&format!(
+    "deploy_path = \"{}\"\nlayout = \"releases\"\nkeep_releases = 2\n",
+    common::toml_escape_path(&deploy_path),
+  ))
+  .unwrap();
+  let releases = deploy_path.join("releases");
+  // Left behind by a version that named releases after the bare deploy_id
+  fs::create_dir_all(releases.join("legacy-release")).unwrap();
+
+  let mut reporter = DeployReporter::default();
+  let mut names = Vec::new();
+  for version in ["v1", "v2", "v3"] {
+    let manager = DeployManager::new();
+    manager
+      .extract_files(
+        &spool(&build_archive(&[("app.txt", version.as_bytes())])),
+        &config,
+        "app",
+        &mut reporter,
+      )
+      .await
+      .unwrap();
+    manager
+      .activate_release(&config, &mut reporter)
+      .await
+      .unwrap();
+    names.push(manager.release_name().to_string());
+
+    // Touching the first release must not make it look newer than the ones after it
+    fs::File::open(releases.join(&names[0]))
+      .unwrap()
+      .set_modified(SystemTime::now() + Duration::from_secs(3600))
+      .unwrap();
+    manager.prune_releases(&config, &mut reporter).await;
+  }
+
+  let mut remaining = fs::read_dir(&releases)
+    .unwrap()
+    .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+    .collect::<Vec<_>>();
+  remaining.sort();
+  assert_eq!(remaining, names[1..]);
+}
+
+#[tokio::test]
+async fn test_mirror_mode_removes_stale_files_but_keeps_preserved_paths() {
+  let temp_dir = common::create_temp_dir();
+  let deploy_path = temp_dir.path().join("app");
+  for (path, contents) in [
+    ("bundle.old.js", "stale"),
+    ("plugins/removed/plugin.js", "stale"),
+    ("config/app.toml", "old"),
+    ("config/app.local", "site settings"),
+    ("data/app.db", "records"),
+    ("logs/app.log", "history"),
+  ] {
+    let path = deploy_path.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+  }
+
+  let config: ServerPackageConfig = toml::from_str(&format!(
+    "deploy_path = \"{}\"\nsync_mode = \"mirror\"\npreserve = [\"config/*.local\", \"data/\", \"logs/\"]\n",
+    common::toml_escape_path(&deploy_path),
+  ))
+  .unwrap();
+  let archive = spool(&build_archive(&[
+    ("bundle.new.js", b"fresh"),
+    ("config/app.toml", b"new"),
+    ("data/seed.db", b"seed"),
+  ]));
+  DeployManager::new()
+    .extract_files(&archive, &config, "app", &mut DeployReporter::default())
+    .await
+    .unwrap();
+
+  assert!(deploy_path.join("bundle.new.js").exists());
+  assert!(!deploy_path.join("bundle.old.js").exists());
+  assert!(!deploy_path.join("plugins").exists());
+  assert_eq!(
+    fs::read_to_string(deploy_path.join("config/app.toml")).unwrap(),
+    "new"
+  );
+  assert_eq!(
+    fs::read_to_string(deploy_path.join("config/app.local")).unwrap(),
+    "site settings"
+  );
+  // Preserved directories are neither pruned nor written into
+  assert!(deploy_path.join("data/app.db").exists());
+  assert!(!deploy_path.join("data/seed.db").exists());
+  assert!(deploy_path.join("logs/app.log").exists());
+  assert!(leftovers(&deploy_path).is_empty());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_hook_scripts_see_deployment_environment() {
+  let temp_dir = common::create_temp_dir();
+  let deploy_path = temp_dir.path().join("app");
+  fs::create_dir_all(deploy_path.join("scripts")).unwrap();
+  DeploymentRecord {
+    deploy_id: "d-1".to_string(),
+    version: "1.0.0".to_string(),
+    deployed_at: chrono::Utc::now(),
+  }
+  .save(&deploy_path)
+  .unwrap();
+
+  let config: ServerPackageConfig = toml::from_str(&format!(
+    r#"deploy_path = "{}"
+before_deploy_script = "env > hook.env; pwd >> hook.env"
+working_directory = "scripts"
+
+[env]
+APP_ENV = "production"
+ADEPLOY_PACKAGE = "spoofed"
+"#,
+    common::toml_escape_path(&deploy_path),
+  ))
+  .unwrap();
+  let metadata = HashMap::from([
+    ("git-sha".to_string(), "abc123".to_string()),
+    ("branch".to_string(), "main".to_string()),
+  ]);
+  let manager = DeployManager::new()
+    .with_version("1.1.0")
+    .with_package("app")
+    .with_client("ci", metadata);
+  manager
+    .run_hooks(
+      HookStage::BeforeDeploy,
+      &config,
+      &mut DeployReporter::default(),
+    )
+    .await
+    .unwrap();
+
+  let output = fs::read_to_string(deploy_path.join("scripts/hook.env")).unwrap();
+  let lines = output.lines().collect::<Vec<_>>();
+  for expected in [
+    format!("ADEPLOY_DEPLOY_ID={}", manager.deploy_id),
+    "ADEPLOY_PACKAGE=app".to_string(),
+    "ADEPLOY_VERSION=1.1.0".to_string(),
+    "ADEPLOY_PREVIOUS_VERSION=1.0.0".to_string(),
+    format!("ADEPLOY_DEPLOY_PATH={}", deploy_path.display()),
+    format!("ADEPLOY_RELEASE_PATH={}", deploy_path.display()),
+    // No backup has been taken before the files are extracted
+    "ADEPLOY_BACKUP_PATH=".to_string(),
+    "ADEPLOY_KEY_NAME=ci".to_string(),
+    "ADEPLOY_META_GIT_SHA=abc123".to_string(),
+    "ADEPLOY_META_BRANCH=main".to_string(),
+    "APP_ENV=production".to_string(),
+  ] {
+    assert!(lines.contains(&expected.as_str()), "missing {}", expected);
+  }
+  assert!(!lines.contains(&"ADEPLOY_PACKAGE=spoofed"));
+  assert_eq!(
+    Path::new(lines.last().unwrap()).canonicalize().unwrap(),
+    deploy_path.join("scripts").canonicalize().unwrap()
+  );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_release_hooks_run_in_the_new_release() {
+  let temp_dir = common::create_temp_dir();
+  let deploy_path = temp_dir.path().join("app");
+  let output = temp_dir.path().join("hook.out");
+  let config: ServerPackageConfig = toml::from_str(&format!(
+    r#"deploy_path = "{}"
+layout = "releases"
+working_directory = "."
+after_deploy_script = "echo \"$ADEPLOY_RELEASE_PATH\" > '{}'; pwd >> '{}'"
+"#,
+    common::toml_escape_path(&deploy_path),
+    common::toml_escape_path(&output),
+    common::toml_escape_path(&output),
+  ))
+  .unwrap();
+
+  let mut reporter = DeployReporter::default();
+  let first = DeployManager::new();
+  first
+    .extract_files(
+      &spool(&build_archive(&[("app.txt", b"v1")])),
+      &config,
+      "app",
+      &mut reporter,
+    )
+    .await
+    .unwrap();
+  first
+    .activate_release(&config, &mut reporter)
+    .await
+    .unwrap();
+
+  // The hook runs before `current` is switched, so it must be pointed at the new release
+  let second = DeployManager::new();
+  second
+    .extract_files(
+      &spool(&build_archive(&[("app.txt", b"v2")])),
+      &config,
+      "app",
+      &mut reporter,
+    )
+    .await
+    .unwrap();
+  second
+    .run_hooks(HookStage::AfterDeploy, &config, &mut reporter)
+    .await
+    .unwrap();
+
+  let release = deploy_path.join("releases").join(second.release_name());
+  let output = fs::read_to_string(&output).unwrap();
+  let lines = output.lines().collect::<Vec<_>>();
+  assert_eq!(lines[0], release.display().to_string());
+  assert_eq!(
+    Path::new(lines[1]).canonicalize().unwrap(),
+    release.canonicalize().unwrap()
+  );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_hook_script_timeout_kills_process_group() {
+  let temp_dir = common::create_temp_dir();
+  let deploy_path = temp_dir.path().join("app");
+  fs::create_dir_all(&deploy_path).unwrap();
+  let config: ServerPackageConfig = toml::from_str(&format!(
+    r#"deploy_path = "{}"
+working_directory = "."
+before_deploy_script = "(sleep 2; touch late) & echo started; wait"
+before_deploy_timeout = "1s"
+script_kill_grace = 1
+
+[hooks]
+after_deploy = [{{ command = "trap '' TERM; sleep 5", timeout = 1, failure = "abort" }}]
+"#,
+    common::toml_escape_path(&deploy_path),
+  ))
+  .unwrap();
+  let manager = DeployManager::new();
+
+  let started = Instant::now();
+  let mut reporter = DeployReporter::default();
+  let failure = manager
+    .run_hooks(HookStage::BeforeDeploy, &config, &mut reporter)
+    .await
+    .unwrap_err();
+  assert!(started.elapsed() < Duration::from_secs(2));
+  assert!(failure.error.to_string().contains("timed out after 1s"));
+  let messages = reporter
+    .into_entries()
+    .into_iter()
+    .map(|entry| entry.message)
+    .collect::<Vec<_>>();
+  assert_eq!(messages[1], "started");
+  assert!(messages[2].contains("timed out after 1s; terminating it"));
+
+  // A script ignoring SIGTERM is killed once the grace period is over
+  let started = Instant::now();
+  let mut reporter = DeployReporter::default();
+  manager
+    .run_hooks(HookStage::AfterDeploy, &config, &mut reporter)
+    .await
+    .unwrap_err();
+  assert!(started.elapsed() < Duration::from_secs(4));
+  assert!(reporter
+    .into_entries()
+    .iter()
+    .any(|entry| entry.message.contains("did not exit within 1s of SIGTERM")));
+
+  // The background child was terminated along with the first script
+  assert!(!deploy_path.join("late").exists());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_timed_out_script_children_get_the_grace_period() {
+  let temp_dir = common::create_temp_dir();
+  let deploy_path = temp_dir.path().join("app");
+  fs::create_dir_all(&deploy_path).unwrap();
+  // The shell exits on SIGTERM at once, while its background child needs a moment to clean up
+  let config: ServerPackageConfig = toml::from_str(&format!(
+    r#"deploy_path = "{}"
+working_directory = "."
+before_deploy_script = "(trap 'sleep 0.5; touch cleaned; exit 0' TERM; while :; do sleep 0.1; done) & wait"
+before_deploy_timeout = "1s"
+script_kill_grace = 3
+"#,
+    common::toml_escape_path(&deploy_path),
+  ))
+  .unwrap();
+
+  let mut reporter = DeployReporter::default();
+  let failure = DeployManager::new()
+    .run_hooks(HookStage::BeforeDeploy, &config, &mut reporter)
+    .await
+    .unwrap_err();
+  assert!(failure.error.to_string().contains("timed out after 1s"));
+  assert!(deploy_path.join("cleaned").exists());
+  assert!(!reporter
+    .into_entries()
+    .iter()
+    .any(|entry| entry.message.contains("did not exit within")));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_hook_runs_with_limits_and_allowed_environment() {
+  let temp_dir = common::create_temp_dir();
+  let deploy_path = temp_dir.path().join("app");
+  fs::create_dir_all(&deploy_path).unwrap();
+  let user = "nobody";
+  let running_as_root = unsafe { libc::geteuid() } == 0;
+  // Hooks run from `/`, which the unprivileged account can always enter
+  let config: ServerPackageConfig = toml::from_str(&format!(
+    r#"deploy_path = "{}"
+working_directory = "/"
+before_deploy_script = "echo limits $(ulimit -t) $(ulimit -v) $(ulimit -n); id -u; env"
+env_allowlist = ["PATH", "LC_*"]
+{}
+[hook_limits]
+cpu_time = "1m"
+address_space = "2G"
+open_files = 64
+"#,
+    common::toml_escape_path(&deploy_path),
+    if running_as_root {
+      format!("run_as_user = \"{}\"\n", user)
+    } else {
+      String::new()
+    },
+  ))
+  .unwrap();
+
+  let mut reporter = DeployReporter::default();
+  DeployManager::new()
+    .run_hooks(HookStage::BeforeDeploy, &config, &mut reporter)
+    .await
+    .unwrap();
+  let messages = reporter
+    .into_entries()
+    .into_iter()
+    .map(|entry| entry.message)
+    .collect::<Vec<_>>();
+
+  assert_eq!(messages[1], "limits 60 2097152 64");
+  let expected_uid = if running_as_root {
+    let output = std::process::Command::new("id")
+      .args(["-u", user])
+      .output()
+      .unwrap();
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+  } else {
+    unsafe { libc::geteuid() }.to_string()
+  };
+  assert_eq!(messages[2], expected_uid);
+
+  // Only allowed, shell-maintained and adeploy's own variables reach the script
+  for line in &messages[3..] {
+    let name = line.split('=').next().unwrap();
+    assert!(
+      ["PATH", "PWD", "SHLVL", "_", "HOME", "USER", "LOGNAME"].contains(&name)
+        || name.starts_with("LC_")
+        || name.starts_with("ADEPLOY_"),
+      "unexpected variable {}",
+      name
+    );
+  }
+  if running_as_root {
+    assert!(messages.iter().any(|line| line == "USER=nobody"));
+  }
+}
+
+/// Gzipped tar archive of (path, mode) entries; paths ending in `/` are directories
+#[cfg(unix)]
+fn build_archive_with_modes(entries: &[(&str, u32)]) -> Vec<u8> {
+  let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+  for (path, mode) in entries {
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(*mode);
+    if path.ends_with('/') {
+      header.set_entry_type(tar::EntryType::Directory);
+      header.set_size(0);
+      header.set_cksum();
+      tar.append_data(&mut header, path, io::empty()).unwrap();
+    } else {
+      header.set_size(4);
+      header.set_cksum();
+      tar.append_data(&mut header, path, &b"data"[..]).unwrap();
+    }
+  }
+  tar.into_inner().unwrap().finish().unwrap()
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_extraction_applies_ownership_and_modes() {
+  use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+  let temp_dir = common::create_temp_dir();
+  let archive = build_archive_with_modes(&[
+    ("bin/", 0o700),
+    ("bin/run", 0o4755),
+    ("docs/", 0o700),
+    ("docs/guide.txt", 0o600),
+    ("README", 0o640),
+  ]);
+  let extract = |name: &'static str, settings: String| {
+    let deploy_path = temp_dir.path().join(name);
+    let config: ServerPackageConfig = toml::from_str(&format!(
+      "deploy_path = \"{}\"\n{}",
+      common::toml_escape_path(&deploy_path),
+      settings
+    ))
+    .unwrap();
+    let archive = spool(&archive);
+    async move {
+      DeployManager::new()
+        .extract_files(&archive, &config, "app", &mut DeployReporter::default())
+        .await
+        .unwrap();
+      deploy_path
+    }
+  };
+  let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+  // Archive modes are kept, apart from setuid and setgid
+  let plain = extract("plain", String::new()).await;
+  assert_eq!(mode(&plain.join("bin/run")), 0o755);
+  assert_eq!(mode(&plain.join("README")), 0o640);
+  let kept = extract("kept", "keep_setuid = true\n".to_string()).await;
+  assert_eq!(mode(&kept.join("bin/run")), 0o4755);
+
+  let mapped = extract(
+    "mapped",
+    r#"file_mode = "0644"
+dir_mode = 0o750
+
+[modes]
+"bin/*" = "0755"
+"docs" = "700"
+"#
+    .to_string(),
+  )
+  .await;
+  assert_eq!(mode(&mapped.join("bin")), 0o750);
+  assert_eq!(mode(&mapped.join("bin/run")), 0o755);
+  assert_eq!(mode(&mapped.join("docs")), 0o700);
+  assert_eq!(mode(&mapped.join("docs/guide.txt")), 0o644);
+  assert_eq!(mode(&mapped.join("README")), 0o644);
+
+  // Changing ownership needs root
+  if unsafe { libc::geteuid() } == 0 {
+    let id = |flag: &str| {
+      let output = std::process::Command::new("id")
+        .args([flag, "nobody"])
+        .output()
+        .unwrap();
+      String::from_utf8(output.stdout)
+        .unwrap()
+        .trim()
+        .parse::<u32>()
+        .unwrap()
+    };
+    let owned = extract(
+      "owned",
+      "owner = \"nobody\"\nkeep_setuid = true\n".to_string(),
+    )
+    .await;
+    let run = fs::metadata(owned.join("bin/run")).unwrap();
+    assert_eq!((run.uid(), run.gid()), (id("-u"), id("-g")));
+    // The mode survives the ownership change, which would otherwise clear setuid
+    assert_eq!(run.permissions().mode() & 0o7777, 0o4755);
+    assert_eq!(fs::metadata(owned.join("docs")).unwrap().uid(), id("-u"));
+  }
+
+  let invalid: Result<ServerPackageConfig, _> =
+    toml::from_str("deploy_path = \"/srv/app\"\nfile_mode = \"rwxr-xr-x\"\n");
+  assert!(invalid.is_err());
+}
@@ -0,0 +1,115 @@
+//! Post-deploy health check tests against local probes
+
+use adeploy::{
+  config::{HealthCheckConfig, ServerPackageConfig},
+  deploy_log::DeployReporter,
+  health,
+};
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::TcpListener,
+};
+
+fn health_check(probe: &str) -> HealthCheckConfig {
+  toml::from_str(&format!(
+    "{}\ntimeout_secs = 2\nretries = 2\ninterval_secs = 0\n",
+    probe
+  ))
+  .unwrap()
+}
+
+fn package(settings: &str) -> ServerPackageConfig {
+  toml::from_str(&format!("deploy_path = \"/srv/app\"\n{}", settings)).unwrap()
+}
+
+#[tokio::test]
+async fn test_http_health_check_retries_until_expected_status() {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let address = listener.local_addr().unwrap();
+  let server = tokio::spawn(async move {
+    for status in ["503 Service Unavailable", "200 OK"] {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut request = [0u8; 1024];
+      let read = stream.read(&mut request).await.unwrap();
+      assert!(request[..read].starts_with(b"GET /healthz HTTP/1.0\r\n"));
+      let response = format!("HTTP/1.0 {}\r\nContent-Length: 0\r\n\r\n", status);
+      stream.write_all(response.as_bytes()).await.unwrap();
+    }
+  });
+
+  let check = health_check(&format!("http = \"http://{}/healthz\"", address));
+  assert_eq!(check.expected_status, 200);
+  let mut reporter = DeployReporter::default();
+  health::run_health_check(&check, &package(""), &mut reporter)
+    .await
+    .unwrap();
+  server.await.unwrap();
+
+  let messages = reporter
+    .into_entries()
+    .into_iter()
+    .map(|entry| entry.message)
+    .collect::<Vec<_>>();
+  assert!(messages[0].contains("attempt 1/3 failed"));
+  assert!(messages[0].contains("returned status 503"));
+  assert!(messages[1].contains("passed on attempt 2/3"));
+}
+
+#[tokio::test]
+async fn test_tcp_and_command_health_checks() {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let address = listener.local_addr().unwrap();
+  let check = health_check(&format!("tcp = \"{}\"", address));
+  health::run_health_check(&check, &package(""), &mut DeployReporter::default())
+    .await
+    .unwrap();
+
+  // Nothing listens once the socket is closed
+  drop(listener);
+  let error = health::run_health_check(&check, &package(""), &mut DeployReporter::default())
+    .await
+    .unwrap_err();
+  assert!(error.to_string().contains("failed after 3 attempts"));
+
+  let check = health_check("command = \"exit 0\"");
+  health::run_health_check(&check, &package(""), &mut DeployReporter::default())
+    .await
+    .unwrap();
+  let check = health_check("command = \"exit 3\"");
+  let error = health::run_health_check(&check, &package(""), &mut DeployReporter::default())
+    .await
+    .unwrap_err();
+  assert!(error.to_string().contains("exited with code 3"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_command_health_check_runs_under_hook_restrictions() {
+  std::env::set_var("ADEPLOY_PROBE_SECRET", "leaked");
+  let check = health_check("command = 'test -z \"$ADEPLOY_PROBE_SECRET\"'");
+
+  // Without an allowlist the probe inherits the server environment
+  assert!(
+    health::run_health_check(&check, &package(""), &mut DeployReporter::default())
+      .await
+      .is_err()
+  );
+  health::run_health_check(
+    &check,
+    &package("env_allowlist = [\"PATH\"]\n"),
+    &mut DeployReporter::default(),
+  )
+  .await
+  .unwrap();
+
+  // Limits apply as well: a probe may not open more descriptors than allowed
+  let check = health_check("command = 'test \"$(ulimit -n)\" = 16'");
+  health::run_health_check(
+    &check,
+    &package("[hook_limits]\nopen_files = 16\n"),
+    &mut DeployReporter::default(),
+  )
+  .await
+  .unwrap();
+  std::env::remove_var("ADEPLOY_PROBE_SECRET");
+}
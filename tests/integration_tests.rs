@@ -4,10 +4,15 @@ use std::{
   fs,
   path::{Path, PathBuf},
   sync::Arc,
-  time::Duration,
+  time::{Duration, Instant},
 };
 
 use adeploy::{
+  adeploy::{
+    deploy_chunk::Payload, deploy_event::Event, deploy_service_client::DeployServiceClient,
+    DeployChunk, DeployHeader, DeployStage,
+  },
+  auth::{Auth, SignedEnvelope, AUTH_REJECTION_METADATA},
   client,
   config::{
     ClientConfig, ConfigProvider, ConfigProviderImpl, ConfigType, KeyPairPaths, ServerConfig,
@@ -15,9 +20,14 @@ use adeploy::{
   error::Result as AdeployResult,
   server,
 };
+use base64::{engine::general_purpose, Engine};
 use log2::*;
+use sha2::{Digest, Sha256};
 use tempfile::TempDir;
-use tokio::time::{sleep, timeout};
+use tokio::{
+  task::JoinHandle,
+  time::{sleep, timeout},
+};
 
 #[derive(Clone)]
 struct ConfigProviderMock {
@@ -125,6 +135,430 @@ async fn test_deployment_scenario_matrix() {
   }
 }
 
+#[tokio::test]
+async fn test_mutual_tls_deployment() {
+  let test_setup = setup_test().await;
+  let port = test_setup.port;
+  let package_name = "test-app";
+  generate_test_keys(&test_setup.public_key_path, &test_setup.private_key_path);
+  let public_key = fs::read_to_string(&test_setup.public_key_path)
+    .unwrap()
+    .trim()
+    .to_string();
+
+  let tls = common::write_tls_fixture(&test_setup.server_dir);
+
+  let server_config_path = server_scenarios::write_server_config(
+    ServerScenarioKind::StandardSuccess,
+    &test_setup.server_dir,
+    port,
+    &public_key,
+    package_name,
+  );
+  append_to_file(
+    &server_config_path,
+    &format!(
+      r#"
+[server.tls]
+cert = "{cert}"
+key = "{key}"
+client_ca = "{ca}"
+"#,
+      cert = common::toml_escape_path(&tls.server_cert),
+      key = common::toml_escape_path(&tls.server_key),
+      ca = common::toml_escape_path(&tls.ca_cert),
+    ),
+  );
+
+  let client_config_path = client_scenarios::write_client_config(
+    ClientScenarioKind::HappyPath,
+    &test_setup.client_dir,
+    port,
+  );
+  let base_client_config = fs::read_to_string(&client_config_path).unwrap();
+  let server_tls_block = format!(
+    r#"
+[remotes."127.0.0.1".tls]
+ca = "{ca}"
+server_name = "localhost"
+"#,
+    ca = common::toml_escape_path(&tls.ca_cert),
+  );
+  let client_identity_block = format!(
+    r#"client_cert = "{cert}"
+client_key = "{key}"
+"#,
+    cert = common::toml_escape_path(&tls.client_cert),
+    key = common::toml_escape_path(&tls.client_key),
+  );
+  fs::write(
+    &client_config_path,
+    format!(
+      "{}{}{}",
+      base_client_config, server_tls_block, client_identity_block
+    ),
+  )
+  .unwrap();
+
+  let provider = build_config_provider(
+    ClientScenarioKind::HappyPath,
+    &test_setup,
+    client_config_path.clone(),
+    server_config_path,
+  );
+
+  let server_provider = provider.clone();
+  let server_handle = tokio::spawn(async move {
+    let _ = server::start_server(server_provider).await;
+  });
+  sleep(Duration::from_millis(200)).await;
+
+  let deploy_result = timeout(
+    DEPLOY_TIMEOUT,
+    client::deploy(
+      "127.0.0.1",
+      Some(vec![package_name.to_string()]),
+      provider.as_ref(),
+    ),
+  )
+  .await
+  .expect("Deployment timed out");
+  assert!(
+    deploy_result.is_ok(),
+    "mTLS deployment failed: {:?}",
+    deploy_result.err()
+  );
+  verify_deployed_files(&test_setup.server_dir.join("deploy"));
+
+  // Without a client certificate the handshake must be rejected
+  fs::write(
+    &client_config_path,
+    format!("{}{}", base_client_config, server_tls_block),
+  )
+  .unwrap();
+  let deploy_result = timeout(
+    DEPLOY_TIMEOUT,
+    client::deploy(
+      "127.0.0.1",
+      Some(vec![package_name.to_string()]),
+      provider.as_ref(),
+    ),
+  )
+  .await
+  .expect("Deployment timed out");
+  assert!(
+    deploy_result.is_err(),
+    "Deployment without a client certificate should fail"
+  );
+
+  server_handle.abort();
+  let _ = server_handle.await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_release_layout_switches_and_prunes_releases() {
+  let test_setup = setup_test().await;
+  let package_name = "test-app";
+  generate_test_keys(&test_setup.public_key_path, &test_setup.private_key_path);
+  let public_key = fs::read_to_string(&test_setup.public_key_path)
+    .unwrap()
+    .trim()
+    .to_string();
+
+  let server_config_path = server_scenarios::write_server_config(
+    ServerScenarioKind::ReleaseLayout,
+    &test_setup.server_dir,
+    test_setup.port,
+    &public_key,
+    package_name,
+  );
+  let client_config_path = client_scenarios::write_client_config(
+    ClientScenarioKind::HappyPath,
+    &test_setup.client_dir,
+    test_setup.port,
+  );
+  let provider = build_config_provider(
+    ClientScenarioKind::HappyPath,
+    &test_setup,
+    client_config_path,
+    server_config_path,
+  );
+
+  let server_provider = provider.clone();
+  let server_handle = tokio::spawn(async move {
+    let _ = server::start_server(server_provider).await;
+  });
+  sleep(Duration::from_millis(200)).await;
+
+  let deploy = || async {
+    timeout(
+      DEPLOY_TIMEOUT,
+      client::deploy(
+        "127.0.0.1",
+        Some(vec![package_name.to_string()]),
+        provider.as_ref(),
+      ),
+    )
+    .await
+    .expect("Deployment timed out")
+  };
+
+  let deploy_path = test_setup.server_dir.join("deploy");
+  let releases_path = deploy_path.join("releases");
+  let current_path = deploy_path.join("current");
+  let mut live_releases = Vec::new();
+  for _ in 0..3 {
+    deploy().await.expect("Release deployment failed");
+    live_releases.push(fs::read_link(&current_path).unwrap());
+  }
+
+  // keep_releases = 2 keeps the live release and the one before it
+  verify_deployed_files(&current_path);
+  assert_eq!(fs::read_dir(&releases_path).unwrap().count(), 2);
+  assert!(deploy_path.join(&live_releases[1]).exists());
+  assert!(!deploy_path.join(&live_releases[0]).exists());
+
+  // A failing after-deploy hook leaves the previous release live
+  let post_script = test_setup.server_dir.join("scripts").join("post_deploy.sh");
+  fs::write(&post_script, "#!/bin/sh\nexit 1\n").unwrap();
+  assert!(deploy().await.is_err());
+  assert_eq!(fs::read_link(&current_path).unwrap(), live_releases[2]);
+  assert_eq!(fs::read_dir(&releases_path).unwrap().count(), 2);
+
+  server_handle.abort();
+  let _ = server_handle.await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_rollback_restores_latest_backup() {
+  let test_setup = setup_test().await;
+  let package_name = "test-app";
+  generate_test_keys(&test_setup.public_key_path, &test_setup.private_key_path);
+  let public_key = fs::read_to_string(&test_setup.public_key_path)
+    .unwrap()
+    .trim()
+    .to_string();
+
+  let server_config_path = server_scenarios::write_server_config(
+    ServerScenarioKind::StandardSuccess,
+    &test_setup.server_dir,
+    test_setup.port,
+    &public_key,
+    package_name,
+  );
+  let client_config_path = client_scenarios::write_client_config(
+    ClientScenarioKind::HappyPath,
+    &test_setup.client_dir,
+    test_setup.port,
+  );
+  let provider = build_config_provider(
+    ClientScenarioKind::HappyPath,
+    &test_setup,
+    client_config_path,
+    server_config_path,
+  );
+
+  let server_provider = provider.clone();
+  let server_handle = tokio::spawn(async move {
+    let _ = server::start_server(server_provider).await;
+  });
+  sleep(Duration::from_millis(200)).await;
+
+  timeout(
+    DEPLOY_TIMEOUT,
+    client::deploy(
+      "127.0.0.1",
+      Some(vec![package_name.to_string()]),
+      provider.as_ref(),
+    ),
+  )
+  .await
+  .expect("Deployment timed out")
+  .expect("Deployment failed");
+  let deploy_path = test_setup.server_dir.join("deploy");
+  verify_deployed_files(&deploy_path);
+
+  let unknown = timeout(
+    DEPLOY_TIMEOUT,
+    client::restore(
+      "127.0.0.1",
+      package_name,
+      Some("backup_19700101_000000"),
+      provider.as_ref(),
+    ),
+  )
+  .await
+  .expect("Restore timed out");
+  assert!(unknown.unwrap_err().to_string().contains("not found"));
+
+  // The latest backup holds the seed file from before the deployment
+  fs::remove_file(deploy_path.join("post_deploy_executed.marker")).unwrap();
+  timeout(
+    DEPLOY_TIMEOUT,
+    client::restore("127.0.0.1", package_name, None, provider.as_ref()),
+  )
+  .await
+  .expect("Restore timed out")
+  .expect("Restore failed");
+
+  assert!(!deploy_path.join("test1.txt").exists());
+  assert_eq!(
+    fs::read_to_string(deploy_path.join("backup.txt")).unwrap(),
+    "backup content"
+  );
+  assert_marker_state(&deploy_path, "post_deploy_executed.marker", true);
+  // The deployed files were backed up before being replaced
+  let backups = fs::read_dir(test_setup.server_dir.join("backup"))
+    .unwrap()
+    .count();
+  assert_eq!(backups, 2);
+
+  server_handle.abort();
+  let _ = server_handle.await;
+}
+
+#[tokio::test]
+async fn test_chunked_upload_requires_signed_envelope() {
+  let test_setup = setup_test().await;
+  let server_handle = start_upload_server(&test_setup).await;
+  let archive = build_test_archive();
+
+  let header = upload_header(&test_setup, &archive, "");
+  let mut client = DeployServiceClient::connect(format!("http://127.0.0.1:{}", test_setup.port))
+    .await
+    .unwrap();
+  let status = client
+    .deploy_progress(upload_chunks(header, &archive))
+    .await
+    .expect_err("Legacy signatures must be rejected on chunked uploads");
+
+  assert_eq!(status.code(), tonic::Code::Unauthenticated);
+  assert_eq!(
+    status.metadata().get(AUTH_REJECTION_METADATA).unwrap(),
+    "legacy-signature"
+  );
+  assert!(!test_setup
+    .server_dir
+    .join("deploy")
+    .join("test1.txt")
+    .exists());
+
+  server_handle.abort();
+  let _ = server_handle.await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_deploy_stream_checks_declared_size_and_deploys() {
+  let test_setup = setup_test().await;
+  let server_handle = start_upload_server(&test_setup).await;
+  let archive = build_test_archive();
+  let deploy_path = test_setup.server_dir.join("deploy");
+  let mut client = DeployServiceClient::connect(format!("http://127.0.0.1:{}", test_setup.port))
+    .await
+    .unwrap();
+
+  // file_size is outside the signed envelope, so only the spool check catches a mismatch
+  let mut header = upload_header(&test_setup, &archive, "stream-size-mismatch");
+  header.file_size += 1;
+  let response = client
+    .deploy_stream(upload_chunks(header, &archive))
+    .await
+    .unwrap()
+    .into_inner();
+  assert!(!response.success);
+  assert!(response.message.contains("Size verification failed"));
+  assert!(!deploy_path.join("test1.txt").exists());
+
+  let header = upload_header(&test_setup, &archive, "stream-deploy");
+  let response = client
+    .deploy_stream(upload_chunks(header, &archive))
+    .await
+    .unwrap()
+    .into_inner();
+  assert!(
+    response.success,
+    "DeployStream failed: {}",
+    response.message
+  );
+  assert!(!response.deploy_id.is_empty());
+  verify_deployed_files(&deploy_path);
+
+  server_handle.abort();
+  let _ = server_handle.await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_deploy_progress_streams_hook_output_while_it_runs() {
+  let test_setup = setup_test().await;
+  let server_handle = start_upload_server(&test_setup).await;
+  let archive = build_test_archive();
+  let finished_marker = test_setup.server_dir.join("hook_finished.marker");
+  fs::write(
+    test_setup.server_dir.join("scripts").join("post_deploy.sh"),
+    format!(
+      "#!/bin/sh\necho 'hook started'\nsleep 2\necho 'hook finishing' >&2\ntouch '{}'\n",
+      finished_marker.display()
+    ),
+  )
+  .unwrap();
+
+  let mut client = DeployServiceClient::connect(format!("http://127.0.0.1:{}", test_setup.port))
+    .await
+    .unwrap();
+  let header = upload_header(&test_setup, &archive, "progress-stream");
+  let mut events = client
+    .deploy_progress(upload_chunks(header, &archive))
+    .await
+    .unwrap()
+    .into_inner();
+
+  let mut after_script_stage = None;
+  let mut started = None;
+  let mut finishing = None;
+  let mut result = None;
+  while let Some(event) = events.message().await.unwrap() {
+    let received = Instant::now();
+    match event.event.unwrap() {
+      Event::Stage(stage) if stage == DeployStage::AfterScript as i32 => {
+        after_script_stage = Some(received);
+      }
+      Event::Log(log) if log.message == "hook started" => {
+        // The hook is still sleeping when its first line arrives
+        assert!(!finished_marker.exists());
+        started = Some(received);
+      }
+      Event::Log(log) if log.message == "STDERR: hook finishing" => finishing = Some(received),
+      Event::Result(response) => {
+        assert!(response.success, "Deployment failed: {}", response.message);
+        result = Some(received);
+      }
+      _ => {}
+    }
+  }
+
+  let after_script_stage = after_script_stage.expect("No after-script stage event");
+  let started = started.expect("First hook line was not streamed");
+  let finishing = finishing.expect("Second hook line was not streamed");
+  let result = result.expect("No result event");
+  assert!(after_script_stage <= started);
+  assert!(finishing.duration_since(started) >= Duration::from_secs(1));
+  assert!(result.duration_since(started) >= Duration::from_secs(1));
+  assert!(finished_marker.exists());
+
+  server_handle.abort();
+  let _ = server_handle.await;
+}
+
+fn append_to_file(path: &Path, content: &str) {
+  let mut existing = fs::read_to_string(path).unwrap();
+  existing.push_str(content);
+  fs::write(path, existing).unwrap();
+}
+
 fn build_matrix() -> Vec<ScenarioCase> {
   let mut cases = Vec::new();
   for client in client_scenarios::all() {
@@ -161,12 +595,36 @@ fn resolve_expected_outcome(
     (HappyPath, PreDeployScriptFailure) => Some(CombinedOutcome::ServerError(
       "execution failed with exit code: 1",
     )),
+    (HappyPath, RollbackOnFailure) => Some(CombinedOutcome::ServerError(
+      "execution failed with exit code: 1",
+    )),
+    (HappyPath, HealthCheckFailure) => {
+      Some(CombinedOutcome::ServerError("failed after 2 attempts"))
+    }
+    (HappyPath, HookPolicies) => Some(CombinedOutcome::ServerError(
+      "execution failed with exit code: 2",
+    )),
     (HappyPath, MissingPackage) => Some(CombinedOutcome::ServerError(
       "Package 'test-app' not configured",
     )),
     (HappyPath, UnauthorizedKey) => Some(CombinedOutcome::ServerError(
       "Client public key not allowed",
     )),
+    (HappyPath, PackageNotPermitted) => Some(CombinedOutcome::ServerError(
+      "Key 'ci-frontend' is not permitted to deploy package 'test-app'",
+    )),
+    (HappyPath, PackageKeyOverride) => Some(CombinedOutcome::Success(SuccessExpectation::new(
+      true, true, true,
+    ))),
+    (HappyPath, ExpiredKey) => Some(CombinedOutcome::ServerError("Client key expired at")),
+    (HappyPath, RevokedKey) => Some(CombinedOutcome::ServerError("Client key has been revoked")),
+    (HappyPath, AuthorizedKeysFile) => Some(CombinedOutcome::Success(SuccessExpectation::new(
+      true, true, true,
+    ))),
+    // The first release has no live deployment to snapshot
+    (HappyPath, ReleaseLayout) => Some(CombinedOutcome::Success(SuccessExpectation::new(
+      true, true, false,
+    ))),
     (MissingRemoteConfig, StandardSuccess) => Some(CombinedOutcome::ClientError(
       "No server configuration found for host",
     )),
@@ -249,7 +707,11 @@ async fn run_case(case: &ScenarioCase) -> Result<(), String> {
 
   let result = match (&case.expected, deploy_result) {
     (CombinedOutcome::Success(expectation), Ok(())) => {
-      verify_deployed_files(&deploy_path);
+      let live_path = match case.server_kind {
+        ServerScenarioKind::ReleaseLayout => deploy_path.join("current"),
+        _ => deploy_path.clone(),
+      };
+      verify_deployed_files(&live_path);
       assert_marker_state(
         &deploy_path,
         "pre_deploy_executed.marker",
@@ -282,6 +744,26 @@ async fn run_case(case: &ScenarioCase) -> Result<(), String> {
     }
     (_, Ok(())) => Err("Expected deployment to fail but it succeeded".to_string()),
   };
+  let result = result.and_then(|()| match case.server_kind {
+    ServerScenarioKind::RollbackOnFailure
+    | ServerScenarioKind::HealthCheckFailure
+    | ServerScenarioKind::HookPolicies
+      if deploy_path.join("test1.txt").exists() =>
+    {
+      Err("Rolled back deployment left its files in place".to_string())
+    }
+    ServerScenarioKind::HookPolicies => {
+      let ran = |marker: &str| test_setup.server_dir.join(marker).exists();
+      if !ran("upgrade.marker") || ran("first_install.marker") {
+        Err("Expected only the upgrade hooks to run".to_string())
+      } else if !ran("rollback.marker") || !ran("failure.marker") {
+        Err("Rollback and failure hooks did not run".to_string())
+      } else {
+        Ok(())
+      }
+    }
+    _ => Ok(()),
+  });
 
   if let Some(handle) = server_handle {
     handle.abort();
@@ -425,3 +907,111 @@ fn assert_backup_state(backup_path: &Path, expect_backup: bool) {
     );
   }
 }
+
+/// Start a server for the standard scenario that raw upload tests talk to directly
+async fn start_upload_server(test_setup: &TestSetup) -> JoinHandle<()> {
+  generate_test_keys(&test_setup.public_key_path, &test_setup.private_key_path);
+  let public_key = fs::read_to_string(&test_setup.public_key_path)
+    .unwrap()
+    .trim()
+    .to_string();
+
+  let server_config_path = server_scenarios::write_server_config(
+    ServerScenarioKind::StandardSuccess,
+    &test_setup.server_dir,
+    test_setup.port,
+    &public_key,
+    "test-app",
+  );
+  let client_config_path = client_scenarios::write_client_config(
+    ClientScenarioKind::HappyPath,
+    &test_setup.client_dir,
+    test_setup.port,
+  );
+  let provider = build_config_provider(
+    ClientScenarioKind::HappyPath,
+    test_setup,
+    client_config_path,
+    server_config_path,
+  );
+
+  let handle = tokio::spawn(async move {
+    let _ = server::start_server(provider).await;
+  });
+  sleep(Duration::from_millis(200)).await;
+  handle
+}
+
+/// A tar.gz archive holding the files `verify_deployed_files` expects
+fn build_test_archive() -> Vec<u8> {
+  let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+  let mut builder = tar::Builder::new(encoder);
+  for (name, content) in [
+    ("test1.txt", "test1 content"),
+    ("test2.txt", "test2 content"),
+  ] {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+      .append_data(&mut header, name, content.as_bytes())
+      .unwrap();
+  }
+  builder.into_inner().unwrap().finish().unwrap()
+}
+
+/// Upload header for `test-app` signed with the test key; an empty nonce signs only the hash
+fn upload_header(test_setup: &TestSetup, archive: &[u8], nonce: &str) -> DeployHeader {
+  let signing_key = Auth::load_key_pair(&test_setup.private_key_path.to_string_lossy()).unwrap();
+  let public_key = Auth::encode_public_key(&signing_key.verifying_key());
+  let auth = Auth::with_key_pair(signing_key);
+
+  let file_hash = format!("{:x}", Sha256::digest(archive));
+  let metadata = Default::default();
+  let timestamp = chrono::Utc::now().timestamp();
+  let signed = if nonce.is_empty() {
+    file_hash.as_bytes().to_vec()
+  } else {
+    SignedEnvelope {
+      package_name: "test-app",
+      version: "1.0.0",
+      file_hash: &file_hash,
+      metadata: &metadata,
+      timestamp,
+      nonce,
+    }
+    .canonical_bytes()
+  };
+
+  DeployHeader {
+    package_name: "test-app".to_string(),
+    version: "1.0.0".to_string(),
+    file_hash,
+    file_size: archive.len() as u64,
+    signature: general_purpose::STANDARD.encode(auth.sign_data(&signed).unwrap()),
+    public_key,
+    metadata,
+    timestamp,
+    nonce: nonce.to_string(),
+  }
+}
+
+/// The header followed by the archive split into two data chunks
+fn upload_chunks(
+  header: DeployHeader,
+  archive: &[u8],
+) -> tokio_stream::Iter<std::vec::IntoIter<DeployChunk>> {
+  let (first, second) = archive.split_at(archive.len() / 2);
+  tokio_stream::iter(vec![
+    DeployChunk {
+      payload: Some(Payload::Header(header)),
+    },
+    DeployChunk {
+      payload: Some(Payload::Data(first.to_vec())),
+    },
+    DeployChunk {
+      payload: Some(Payload::Data(second.to_vec())),
+    },
+  ])
+}
@@ -0,0 +1,102 @@
+//! Key lifecycle and allowlist editing tests
+
+use std::fs;
+
+use adeploy::{
+  auth::Auth,
+  config::{ConfigProvider, ConfigProviderImpl, KeyPairPaths},
+  keys,
+};
+
+mod common;
+
+#[test]
+fn test_generate_show_and_rotate_key() {
+  let temp_dir = common::create_temp_dir();
+  let key_dir = temp_dir.path().join(".key");
+  let paths = KeyPairPaths::new(key_dir.join("id_ed25519"), key_dir.join("id_ed25519.pub"));
+
+  keys::generate_key(&paths, None, false).unwrap();
+  let line = keys::public_key_line(&paths).unwrap();
+  assert!(line.starts_with("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5"));
+  let fingerprint = keys::key_fingerprint(&paths).unwrap();
+  assert_eq!(Auth::fingerprint(&line).unwrap(), fingerprint);
+
+  // An existing key pair is never silently replaced
+  assert!(keys::generate_key(&paths, None, false).is_err());
+
+  let rotated = keys::rotate_key(&paths, None).unwrap();
+  assert_eq!(rotated.previous_fingerprint, fingerprint);
+  assert_ne!(keys::key_fingerprint(&paths).unwrap(), fingerprint);
+  assert_eq!(
+    keys::key_fingerprint(&rotated.retired).unwrap(),
+    fingerprint
+  );
+}
+
+#[test]
+fn test_failed_rotation_keeps_current_key() {
+  let temp_dir = common::create_temp_dir();
+  let key_dir = temp_dir.path().join(".key");
+  let paths = KeyPairPaths::new(key_dir.join("id_ed25519"), key_dir.join("id_ed25519.pub"));
+  keys::generate_key(&paths, None, false).unwrap();
+  let fingerprint = keys::key_fingerprint(&paths).unwrap();
+
+  // A directory where the replacement would be written makes generation fail
+  fs::create_dir(key_dir.join("id_ed25519.new")).unwrap();
+  assert!(keys::rotate_key(&paths, None).is_err());
+
+  assert_eq!(keys::key_fingerprint(&paths).unwrap(), fingerprint);
+  let mut names = fs::read_dir(&key_dir)
+    .unwrap()
+    .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+    .collect::<Vec<_>>();
+  names.sort();
+  assert_eq!(names, ["id_ed25519", "id_ed25519.new", "id_ed25519.pub"]);
+}
+
+#[test]
+fn test_authorize_key_edits_allowed_keys() {
+  let temp_dir = common::create_temp_dir();
+  let config_path = temp_dir.path().join("server_config.toml");
+  fs::write(
+    &config_path,
+    r#"[server]
+port = 6060
+max_file_size = 1048576
+# Deploy keys
+allowed_keys = [
+  "existing-key", # legacy entry
+]
+
+[packages.web]
+deploy_path = "/srv/web"
+backup_enabled = false
+"#,
+  )
+  .unwrap();
+
+  let key = Auth::parse_public_key(
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILjSZah/b5ZeAG4K+MFS1RmO0MN5CPOwoxyx4nAnHkjG deploy@ci",
+  )
+  .unwrap();
+  let line = Auth::format_openssh_public_key(&key, Some("deploy@ci"));
+  keys::authorize_key(&config_path, &line, "ci", &["web".to_string()]).unwrap();
+
+  let content = fs::read_to_string(&config_path).unwrap();
+  assert!(content.contains("# Deploy keys"));
+  assert!(content.contains("\"existing-key\", # legacy entry"));
+
+  let config = ConfigProviderImpl.load_server_config(&config_path).unwrap();
+  let entry = config.server.find_key(&line).unwrap();
+  assert_eq!(entry.name(), Some("ci"));
+  assert!(entry.grants_package("web"));
+  assert!(!entry.grants_package("api"));
+
+  // Duplicate keys and names are rejected without touching the file
+  assert!(keys::authorize_key(&config_path, &line, "ci-again", &[]).is_err());
+  let other =
+    Auth::encode_public_key(&ed25519_dalek::SigningKey::from_bytes(&[9u8; 32]).verifying_key());
+  assert!(keys::authorize_key(&config_path, &other, "ci", &[]).is_err());
+  assert_eq!(fs::read_to_string(&config_path).unwrap(), content);
+}
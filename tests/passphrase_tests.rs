@@ -0,0 +1,53 @@
+//! Passphrase source tests
+
+#![cfg(unix)]
+
+use std::{fs::File, io::Write, os::unix::io::FromRawFd, sync::Mutex};
+
+use adeploy::passphrase;
+
+/// Tests here change process-wide environment variables
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+#[test]
+fn test_passphrase_fd_is_read_once_and_left_open() {
+  let _env = ENV_LOCK.lock().unwrap();
+  let mut fds = [0; 2];
+  // SAFETY: fds has room for the two descriptors pipe writes
+  assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+  let [read_fd, write_fd] = fds;
+
+  // SAFETY: write_fd was just created by pipe and is owned here
+  let mut writer = unsafe { File::from_raw_fd(write_fd) };
+  writer.write_all(b"correct horse\nleftover").unwrap();
+
+  std::env::set_var(passphrase::PASSPHRASE_FD_ENV, read_fd.to_string());
+  let first = passphrase::from_environment().unwrap();
+  // The second lookup is served from the cache rather than the drained pipe
+  let second = passphrase::from_environment().unwrap();
+  std::env::remove_var(passphrase::PASSPHRASE_FD_ENV);
+
+  assert_eq!(first.as_deref(), Some("correct horse"));
+  assert_eq!(second.as_deref(), Some("correct horse"));
+
+  // The inherited descriptor is still open and nothing past the first line was consumed
+  // SAFETY: F_GETFD only inspects the descriptor
+  assert_ne!(unsafe { libc::fcntl(read_fd, libc::F_GETFD) }, -1);
+  drop(writer);
+  // SAFETY: read_fd is still open and owned by this test
+  let mut reader = unsafe { File::from_raw_fd(read_fd) };
+  let mut rest = String::new();
+  std::io::Read::read_to_string(&mut reader, &mut rest).unwrap();
+  assert_eq!(rest, "leftover");
+}
+
+#[test]
+fn test_new_key_rejects_empty_passphrase_from_environment() {
+  let _env = ENV_LOCK.lock().unwrap();
+  std::env::set_var(passphrase::PASSPHRASE_ENV, "");
+  let result = passphrase::obtain_new();
+  std::env::remove_var(passphrase::PASSPHRASE_ENV);
+
+  let error = result.unwrap_err();
+  assert!(error.to_string().contains("must not be empty"));
+}
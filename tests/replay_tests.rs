@@ -0,0 +1,54 @@
+//! Replay protection tests
+
+use adeploy::replay::{ReplayGuard, ReplayRejection};
+
+mod common;
+
+const WINDOW: u64 = 300;
+const NOW: i64 = 1_700_000_000;
+
+#[test]
+fn test_replay_guard_rejects_reused_nonce_across_restarts() {
+  let temp_dir = common::create_temp_dir();
+  let cache_path = temp_dir.path().join("nonce_cache.json");
+
+  let mut guard = ReplayGuard::load(cache_path.clone());
+  assert_eq!(guard.check("nonce-1", NOW, NOW, WINDOW), Ok(()));
+  let first = guard.snapshot();
+  assert_eq!(
+    guard.check("nonce-1", NOW, NOW + 1, WINDOW),
+    Err(ReplayRejection::NonceReused)
+  );
+  assert_eq!(guard.check("nonce-0", NOW, NOW + 1, WINDOW), Ok(()));
+  guard.snapshot().persist().unwrap();
+  // A snapshot taken earlier never overwrites a newer one
+  first.persist().unwrap();
+
+  // A restarted server must still remember the nonce
+  let mut restarted = ReplayGuard::load(cache_path);
+  assert_eq!(
+    restarted.check("nonce-1", NOW, NOW + 2, WINDOW),
+    Err(ReplayRejection::NonceReused)
+  );
+  assert_eq!(
+    restarted.check("nonce-0", NOW, NOW + 2, WINDOW),
+    Err(ReplayRejection::NonceReused)
+  );
+  assert_eq!(restarted.check("nonce-2", NOW, NOW + 2, WINDOW), Ok(()));
+}
+
+#[test]
+fn test_replay_guard_enforces_clock_skew_window() {
+  let temp_dir = common::create_temp_dir();
+  let mut guard = ReplayGuard::load(temp_dir.path().join("nonce_cache.json"));
+
+  assert_eq!(
+    guard.check("stale", NOW - 301, NOW, WINDOW),
+    Err(ReplayRejection::OutsideWindow { skew_secs: 301 })
+  );
+  assert_eq!(
+    guard.check("future", NOW + 301, NOW, WINDOW),
+    Err(ReplayRejection::OutsideWindow { skew_secs: -301 })
+  );
+  assert_eq!(guard.check("edge", NOW - 300, NOW, WINDOW), Ok(()));
+}